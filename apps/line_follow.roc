@@ -38,6 +38,9 @@ main = \{state, lightLeft, lightRight} ->
         delayMS: 200,
         state: next,
         display: IO.displayNum (ll + lr),
+        headlights: TurnSignals,
+        resetPose: False,
+        scan: False,
         speedLeft: Num.toI8 speedLeft,
         speedRight: Num.toI8 speedRight,
     }
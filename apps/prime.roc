@@ -16,6 +16,9 @@ main = \{state} ->
         delayMS: 50,
         state: next,
         display: IO.displayNum data,
+        headlights: Off,
+        resetPose: False,
+        scan: False,
         speedLeft: 0,
         speedRight: 0,
     }
@@ -0,0 +1,449 @@
+//! Accelerometer settings, data conversion and FIFO handling for the LSM303AGR.
+
+// CTRL_REG1_A bits.
+const LPEN: u8 = 0b0000_1000;
+const XYZ_EN: u8 = 0b0000_0111;
+// CTRL_REG4_A bits.
+/// Block data update, so a read never mixes the low and high bytes of two samples.
+const BDU: u8 = 0b1000_0000;
+const HR: u8 = 0b0000_1000;
+// CTRL_REG5_A bits.
+const FIFO_EN: u8 = 0b0100_0000;
+// FIFO_SRC_REG_A bits.
+const WTM: u8 = 0b1000_0000;
+const OVRN_FIFO: u8 = 0b0100_0000;
+const FSS_MASK: u8 = 0b0001_1111;
+const EMPTY: u8 = 0b0010_0000;
+
+/// Samples the FIFO holds.
+pub const FIFO_DEPTH: usize = 32;
+
+/// Output data rate. The values are the ODR bits of CTRL_REG1_A.
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccelOdr {
+    PowerDown = 0,
+    Hz1 = 1,
+    Hz10 = 2,
+    Hz25 = 3,
+    Hz50 = 4,
+    Hz100 = 5,
+    Hz200 = 6,
+    Hz400 = 7,
+}
+
+/// Measurement range. The values are the FS bits of CTRL_REG4_A.
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FullScale {
+    G2 = 0,
+    G4 = 1,
+    G8 = 2,
+    G16 = 3,
+}
+
+impl FullScale {
+    /// Step size of the interrupt engines' thresholds, from the datasheet.
+    pub fn threshold_mg_per_step(self) -> i32 {
+        match self {
+            FullScale::G2 => 16,
+            FullScale::G4 => 32,
+            FullScale::G8 => 62,
+            FullScale::G16 => 186,
+        }
+    }
+}
+
+/// Trades precision for current draw.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// 8 bits.
+    LowPower,
+    /// 10 bits.
+    Normal,
+    /// 12 bits.
+    HighResolution,
+}
+
+impl AccelOdr {
+    /// Time between samples, None when powered down.
+    pub fn period_us(self) -> Option<u32> {
+        let hz = match self {
+            AccelOdr::PowerDown => return None,
+            AccelOdr::Hz1 => 1,
+            AccelOdr::Hz10 => 10,
+            AccelOdr::Hz25 => 25,
+            AccelOdr::Hz50 => 50,
+            AccelOdr::Hz100 => 100,
+            AccelOdr::Hz200 => 200,
+            AccelOdr::Hz400 => 400,
+        };
+        Some(1_000_000 / hz)
+    }
+}
+
+impl Resolution {
+    fn bits(self) -> u32 {
+        match self {
+            Resolution::LowPower => 8,
+            Resolution::Normal => 10,
+            Resolution::HighResolution => 12,
+        }
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccelConfig {
+    pub odr: AccelOdr,
+    pub scale: FullScale,
+    pub resolution: Resolution,
+}
+
+impl AccelConfig {
+    /// Plenty for tilt and bumps. +-2g is enough since the robot never leaves the ground.
+    pub const DEFAULT: AccelConfig = AccelConfig {
+        odr: AccelOdr::Hz100,
+        scale: FullScale::G2,
+        resolution: Resolution::HighResolution,
+    };
+
+    /// For running from batteries, still quick enough to notice being picked up.
+    pub const LOW_POWER: AccelConfig = AccelConfig {
+        odr: AccelOdr::Hz10,
+        scale: FullScale::G2,
+        resolution: Resolution::LowPower,
+    };
+
+    pub fn ctrl_reg1(&self) -> u8 {
+        let lpen = match self.resolution {
+            Resolution::LowPower => LPEN,
+            _ => 0,
+        };
+        ((self.odr as u8) << 4) | lpen | XYZ_EN
+    }
+
+    pub fn ctrl_reg4(&self) -> u8 {
+        let hr = match self.resolution {
+            Resolution::HighResolution => HR,
+            _ => 0,
+        };
+        BDU | ((self.scale as u8) << 4) | hr
+    }
+
+    /// Size of one step of the output in micro-g, from the datasheet.
+    pub fn micro_g_per_digit(&self) -> i32 {
+        let by_scale = match self.resolution {
+            Resolution::HighResolution => [980, 1_950, 3_900, 11_720],
+            Resolution::Normal => [3_900, 7_820, 15_630, 46_900],
+            Resolution::LowPower => [15_630, 31_260, 62_520, 187_580],
+        };
+        by_scale[self.scale as usize]
+    }
+}
+
+impl Default for AccelConfig {
+    fn default() -> AccelConfig {
+        AccelConfig::DEFAULT
+    }
+}
+
+/// How the FIFO fills. The values are the FM bits of FIFO_CTRL_REG_A.
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoMode {
+    /// Off, only the latest sample is kept.
+    Bypass = 0,
+    /// Fills up and then stops until it is restarted.
+    Fifo = 1,
+    /// Keeps the latest samples, dropping the oldest once full.
+    Stream = 2,
+    /// Streams until the free fall engine on INT1 fires, then fills up with what follows
+    /// and stops, keeping the samples around the event.
+    StreamToFifo = 3,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FifoConfig {
+    pub mode: FifoMode,
+    /// Samples waiting that set off the watermark interrupt, up to 31.
+    pub watermark: u8,
+}
+
+impl FifoConfig {
+    pub const BYPASS: FifoConfig = FifoConfig {
+        mode: FifoMode::Bypass,
+        watermark: 0,
+    };
+
+    /// Half full, which leaves room for the reads to run late.
+    pub const STREAM: FifoConfig = FifoConfig {
+        mode: FifoMode::Stream,
+        watermark: 16,
+    };
+
+    pub fn enabled(&self) -> bool {
+        self.mode != FifoMode::Bypass
+    }
+
+    /// Bits to add to CTRL_REG5_A.
+    pub fn ctrl_reg5(&self) -> u8 {
+        if self.enabled() {
+            FIFO_EN
+        } else {
+            0
+        }
+    }
+
+    pub fn fifo_ctrl_reg(&self) -> u8 {
+        ((self.mode as u8) << 6) | self.watermark.min(FSS_MASK)
+    }
+
+    /// Whether it stops once full, and has to go through bypass to start again.
+    pub fn stops_when_full(&self) -> bool {
+        matches!(self.mode, FifoMode::Fifo | FifoMode::StreamToFifo)
+    }
+
+    /// Time between watermark interrupts at `odr`, None when powered down.
+    pub fn period_us(&self, odr: AccelOdr) -> Option<u32> {
+        let samples = if self.enabled() {
+            self.watermark.max(1)
+        } else {
+            1
+        };
+        odr.period_us().map(|period| period * samples as u32)
+    }
+}
+
+impl Default for FifoConfig {
+    fn default() -> FifoConfig {
+        FifoConfig::BYPASS
+    }
+}
+
+/// FIFO_SRC_REG_A.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FifoStatus {
+    /// Samples waiting to be read.
+    pub samples: usize,
+    pub watermark: bool,
+    /// Full, so samples have been dropped in stream mode or it has stopped otherwise.
+    pub overrun: bool,
+}
+
+impl FifoStatus {
+    pub fn from_src_reg(value: u8) -> FifoStatus {
+        // The count only has room for 31, so a full FIFO reads as 31 and not empty.
+        let samples = if value & EMPTY != 0 {
+            0
+        } else if value & OVRN_FIFO != 0 {
+            FIFO_DEPTH
+        } else {
+            (value & FSS_MASK) as usize
+        };
+        FifoStatus {
+            samples,
+            watermark: value & WTM != 0,
+            overrun: value & OVRN_FIFO != 0,
+        }
+    }
+}
+
+/// Acceleration in milli-g. Gravity reads as about 1000 pointing up.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AccelData {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl AccelData {
+    /// Converts the raw little endian x, y, z output registers, read with `config`, to milli-g.
+    pub fn from_raw(data: &[u8; 6], config: &AccelConfig) -> AccelData {
+        // Samples are left justified, so the unused low bits are dropped.
+        let shift = 16 - config.resolution.bits();
+        let axis = |i: usize| {
+            let raw = (u16::from(data[i]) | (u16::from(data[i + 1]) << 8)) as i16;
+            i32::from(raw >> shift) * config.micro_g_per_digit() / 1000
+        };
+        AccelData {
+            x: axis(0),
+            y: axis(2),
+            z: axis(4),
+        }
+    }
+
+    /// Length of the acceleration in milli-g, about 1000 at rest.
+    pub fn magnitude(&self) -> f32 {
+        let (x, y, z) = (self.x as f32, self.y as f32, self.z as f32);
+        libm::sqrtf(x * x + y * y + z * z)
+    }
+}
+
+/// Summary of the samples since it was last reset, for picking out bumps and rough ground.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AccelStats {
+    pub count: u32,
+    peak_mg: i32,
+    sum: [i64; 3],
+    sum_squares: i64,
+}
+
+impl AccelStats {
+    pub fn add(&mut self, data: &AccelData) {
+        self.count += 1;
+        self.peak_mg = self.peak_mg.max(libm::roundf(data.magnitude()) as i32);
+        for (sum, axis) in self.sum.iter_mut().zip([data.x, data.y, data.z]) {
+            *sum += i64::from(axis);
+            self.sum_squares += i64::from(axis) * i64::from(axis);
+        }
+    }
+
+    /// Largest acceleration, including gravity, so about 1000 when nothing happened.
+    pub fn peak_mg(&self) -> i32 {
+        self.peak_mg
+    }
+
+    /// How much the acceleration moved about its average, so gravity and a steady tilt
+    /// do not count. 0 when nothing happened.
+    pub fn rms_mg(&self) -> f32 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let n = self.count as f32;
+        let mean_squares = self.sum_squares as f32 / n;
+        let mean = self.sum.map(|s| s as f32 / n);
+        let squared_mean = mean[0] * mean[0] + mean[1] * mean[1] + mean[2] * mean[2];
+        libm::sqrtf((mean_squares - squared_mean).max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(x: i16, y: i16, z: i16) -> [u8; 6] {
+        let [x0, x1] = x.to_le_bytes();
+        let [y0, y1] = y.to_le_bytes();
+        let [z0, z1] = z.to_le_bytes();
+        [x0, x1, y0, y1, z0, z1]
+    }
+
+    #[test]
+    fn default_registers() {
+        let config = AccelConfig::default();
+        // 100Hz with every axis on, block data update and high resolution at +-2g.
+        assert_eq!(config.ctrl_reg1(), 0x57);
+        assert_eq!(config.ctrl_reg4(), 0x88);
+    }
+
+    #[test]
+    fn low_power_registers() {
+        let config = AccelConfig {
+            odr: AccelOdr::Hz10,
+            scale: FullScale::G8,
+            resolution: Resolution::LowPower,
+        };
+        assert_eq!(config.ctrl_reg1(), 0x2F);
+        assert_eq!(config.ctrl_reg4(), 0xA0);
+        assert_eq!(AccelConfig::LOW_POWER.ctrl_reg4(), 0x80);
+    }
+
+    #[test]
+    fn sample_periods() {
+        assert_eq!(AccelOdr::PowerDown.period_us(), None);
+        assert_eq!(AccelOdr::Hz1.period_us(), Some(1_000_000));
+        assert_eq!(AccelOdr::Hz25.period_us(), Some(40_000));
+        assert_eq!(AccelOdr::Hz400.period_us(), Some(2_500));
+    }
+
+    #[test]
+    fn one_g_at_each_resolution() {
+        // 1g is 1024 counts at 12 bits and +-2g, left justified by 4.
+        let high = AccelConfig::default();
+        let data = AccelData::from_raw(&raw(0, -1024 << 4, 1024 << 4), &high);
+        assert_eq!(
+            data,
+            AccelData {
+                x: 0,
+                y: -1003,
+                z: 1003
+            }
+        );
+
+        let normal = AccelConfig {
+            resolution: Resolution::Normal,
+            ..high
+        };
+        assert_eq!(AccelData::from_raw(&raw(256 << 6, 0, 0), &normal).x, 998);
+
+        let low = AccelConfig {
+            resolution: Resolution::LowPower,
+            scale: FullScale::G16,
+            ..high
+        };
+        assert_eq!(AccelData::from_raw(&raw(5 << 8, 0, 0), &low).x, 937);
+    }
+
+    #[test]
+    fn fifo_registers() {
+        assert_eq!(FifoConfig::BYPASS.ctrl_reg5(), 0);
+        assert_eq!(FifoConfig::BYPASS.fifo_ctrl_reg(), 0);
+        assert_eq!(FifoConfig::STREAM.ctrl_reg5(), 0x40);
+        assert_eq!(FifoConfig::STREAM.fifo_ctrl_reg(), 0x90);
+        let trigger = FifoConfig {
+            mode: FifoMode::StreamToFifo,
+            watermark: 40,
+        };
+        assert_eq!(trigger.fifo_ctrl_reg(), 0xDF);
+        assert!(trigger.stops_when_full());
+        assert_eq!(FifoConfig::STREAM.period_us(AccelOdr::Hz400), Some(40_000));
+        assert_eq!(FifoConfig::BYPASS.period_us(AccelOdr::Hz100), Some(10_000));
+    }
+
+    #[test]
+    fn fifo_status() {
+        let status = FifoStatus::from_src_reg(0b1001_0010);
+        assert_eq!(status.samples, 18);
+        assert!(status.watermark);
+        assert!(!status.overrun);
+        assert_eq!(FifoStatus::from_src_reg(0b0010_0000).samples, 0);
+        let full = FifoStatus::from_src_reg(0b1101_1111);
+        assert_eq!(full.samples, FIFO_DEPTH);
+        assert!(full.overrun);
+    }
+
+    #[test]
+    fn stats() {
+        let mut stats = AccelStats::default();
+        assert_eq!(stats.rms_mg(), 0.0);
+        // Sitting still on a slope.
+        for _ in 0..10 {
+            stats.add(&AccelData {
+                x: 0,
+                y: 800,
+                z: 600,
+            });
+        }
+        assert_eq!(stats.peak_mg(), 1000);
+        assert!(stats.rms_mg() < 1.0);
+
+        // Rough ground shakes it up and down by 300mg.
+        let mut stats = AccelStats::default();
+        for i in 0..10 {
+            let y = if i % 2 == 0 { 1300 } else { 700 };
+            stats.add(&AccelData { x: 0, y, z: 0 });
+        }
+        assert_eq!(stats.count, 10);
+        assert_eq!(stats.peak_mg(), 1300);
+        assert!((stats.rms_mg() - 300.0).abs() < 1.0);
+    }
+}
@@ -0,0 +1,375 @@
+//! Descriptions of the robot bases the platform can drive.
+//! A profile says how the motors are wired to the PCA9685 and which edge connector pins
+//! the sensors use, so `RobotBase` does not hard code a single chassis.
+
+use crate::headlight;
+use crate::mag::Mounting;
+use crate::motor::{Direction, Motor, MotorChannels};
+use crate::servo::ServoCalibration;
+
+/// How a motor's H-bridge is connected to the PCA9685.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotorWiring {
+    /// Direction inputs held fully on or off, with the speed from the duty cycle of a third channel.
+    ThreeChannel(MotorChannels),
+    /// Bridge inputs driven directly. The duty cycle goes on the input for the direction
+    /// and the other input is held off.
+    TwoChannel { forward: u8, reverse: u8 },
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotorConfig {
+    pub wiring: MotorWiring,
+    /// Swaps forward and reverse, for motors that are mounted or wired the other way round.
+    pub inverted: bool,
+}
+
+impl MotorConfig {
+    pub const fn new(wiring: MotorWiring) -> MotorConfig {
+        MotorConfig {
+            wiring,
+            inverted: false,
+        }
+    }
+
+    /// Returns the `(channel, on, off)` counts needed to drive this motor.
+    pub fn pwm(&self, dir: Direction, speed: u16) -> PwmBatch<3> {
+        let dir = match (dir, self.inverted) {
+            (dir, false) => dir,
+            (Direction::Forward, true) => Direction::Reverse,
+            (Direction::Reverse, true) => Direction::Forward,
+        };
+        let mut batch = PwmBatch::new();
+        match self.wiring {
+            MotorWiring::ThreeChannel(channels) => {
+                for update in channels.pwm(dir, speed) {
+                    batch.push(update);
+                }
+            }
+            MotorWiring::TwoChannel { forward, reverse } => {
+                let (forward_speed, reverse_speed) = match dir {
+                    Direction::Forward => (speed, 0),
+                    Direction::Reverse => (0, speed),
+                };
+                batch.push((forward, 0, forward_speed));
+                batch.push((reverse, 0, reverse_speed));
+            }
+        }
+        batch
+    }
+}
+
+/// Up to `N` `(channel, on, off)` updates, to hand to `Pca9685::set_pwms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PwmBatch<const N: usize> {
+    updates: [(u8, u16, u16); N],
+    len: usize,
+}
+
+impl<const N: usize> PwmBatch<N> {
+    pub fn new() -> PwmBatch<N> {
+        PwmBatch {
+            updates: [(0, 0, 0); N],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, update: (u8, u16, u16)) {
+        self.updates[self.len] = update;
+        self.len += 1;
+    }
+
+    pub fn as_slice(&self) -> &[(u8, u16, u16)] {
+        &self.updates[..self.len]
+    }
+}
+
+impl<const N: usize> Default for PwmBatch<N> {
+    fn default() -> PwmBatch<N> {
+        PwmBatch::new()
+    }
+}
+
+/// Sensor pins, as micro:bit edge connector numbers.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardPins {
+    pub sonar_trig: u8,
+    pub sonar_echo: u8,
+    pub servo: u8,
+    pub line_left: u8,
+    pub line_right: u8,
+    pub ir_receiver: u8,
+}
+
+/// Edge connector pins the platform keeps for itself: the display columns on 3, 4, 6, 7
+/// and 10, buttons A and B on 5 and 11, and the I2C bus on 19 and 20.
+pub const RESERVED_EDGE_PINS: [u8; 9] = [3, 4, 5, 6, 7, 10, 11, 19, 20];
+
+impl BoardPins {
+    pub const fn as_array(&self) -> [u8; 6] {
+        [
+            self.sonar_trig,
+            self.sonar_echo,
+            self.servo,
+            self.line_left,
+            self.line_right,
+            self.ir_receiver,
+        ]
+    }
+
+    /// Whether every pin is broken out, used only once, and not one of `RESERVED_EDGE_PINS`.
+    /// Const so a bad profile fails to build.
+    pub const fn are_usable(&self) -> bool {
+        let pins = self.as_array();
+        let mut i = 0;
+        while i < pins.len() {
+            if edge_connector_gpio(pins[i]).is_none() {
+                return false;
+            }
+            let mut j = 0;
+            while j < RESERVED_EDGE_PINS.len() {
+                if pins[i] == RESERVED_EDGE_PINS[j] {
+                    return false;
+                }
+                j += 1;
+            }
+            j = i + 1;
+            while j < pins.len() {
+                if pins[i] == pins[j] {
+                    return false;
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+        true
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardProfile {
+    pub name: &'static str,
+    pub pca9685_addr: u8,
+    /// In `Motor::ALL` order. None where the chassis has no motor.
+    pub motors: [Option<MotorConfig>; 4],
+    /// Left and right headlight channels, if the base has headlights.
+    pub headlights: Option<[u8; 2]>,
+    pub servo: ServoCalibration,
+    /// How the micro:bit sits on the base, for the tilt compensated compass.
+    pub mounting: Mounting,
+    pub pins: BoardPins,
+}
+
+/// The KeyeStudio Microbit 4WD Mecanum Robot Kit.
+pub const KEYESTUDIO_MECANUM: BoardProfile = BoardProfile {
+    name: "KeyeStudio 4WD Mecanum",
+    pca9685_addr: 0x47,
+    motors: [
+        Some(MotorConfig::new(MotorWiring::ThreeChannel(
+            Motor::FrontLeft.channels(),
+        ))),
+        Some(MotorConfig::new(MotorWiring::ThreeChannel(
+            Motor::BackLeft.channels(),
+        ))),
+        Some(MotorConfig::new(MotorWiring::ThreeChannel(
+            Motor::FrontRight.channels(),
+        ))),
+        Some(MotorConfig::new(MotorWiring::ThreeChannel(
+            Motor::BackRight.channels(),
+        ))),
+    ],
+    headlights: Some([headlight::LEFT_CHANNEL, headlight::RIGHT_CHANNEL]),
+    servo: ServoCalibration::DEFAULT,
+    mounting: Mounting::Upright,
+    pins: BoardPins {
+        sonar_trig: 15,
+        sonar_echo: 16,
+        servo: 14,
+        line_left: 1,
+        line_right: 2,
+        ir_receiver: 8,
+    },
+};
+
+/// A two wheel chassis with a PCA9685 at its default address driving a dual H-bridge,
+/// left motor on channels 0 and 1, right motor on 2 and 3.
+/// The wheels are driven as the front motors, the back motors are left out.
+pub const TWO_WHEEL: BoardProfile = BoardProfile {
+    name: "Two wheel",
+    pca9685_addr: 0x40,
+    motors: [
+        Some(MotorConfig::new(MotorWiring::TwoChannel {
+            forward: 0,
+            reverse: 1,
+        })),
+        None,
+        Some(MotorConfig::new(MotorWiring::TwoChannel {
+            forward: 2,
+            reverse: 3,
+        })),
+        None,
+    ],
+    headlights: None,
+    servo: ServoCalibration::DEFAULT,
+    mounting: Mounting::Upright,
+    pins: KEYESTUDIO_MECANUM.pins,
+};
+
+impl BoardProfile {
+    pub fn motor(&self, motor: Motor) -> Option<MotorConfig> {
+        self.motors[motor as usize]
+    }
+
+    /// Returns the counts to drive `motor`, empty if the chassis does not have it.
+    pub fn motor_pwm(&self, motor: Motor, dir: Direction, speed: u16) -> PwmBatch<3> {
+        self.motor(motor)
+            .map(|config| config.pwm(dir, speed))
+            .unwrap_or_default()
+    }
+
+    /// Returns the counts for every motor at once, so they can be sent as one batch.
+    /// `commands` is in `Motor::ALL` order.
+    pub fn motors_pwm(&self, commands: &[(Direction, u16); 4]) -> PwmBatch<12> {
+        let mut batch = PwmBatch::new();
+        for (motor, (dir, speed)) in Motor::ALL.iter().zip(commands) {
+            for update in self.motor_pwm(*motor, *dir, *speed).as_slice() {
+                batch.push(*update);
+            }
+        }
+        batch
+    }
+}
+
+impl Default for BoardProfile {
+    fn default() -> BoardProfile {
+        KEYESTUDIO_MECANUM
+    }
+}
+
+/// nRF52833 pin number, port * 32 + pin, of a micro:bit v2 edge connector pin.
+/// Returns None for pins that are not broken out.
+pub const fn edge_connector_gpio(pin: u8) -> Option<u8> {
+    let (port, pin) = match pin {
+        0 => (0, 2),
+        1 => (0, 3),
+        2 => (0, 4),
+        3 => (0, 31),
+        4 => (0, 28),
+        5 => (0, 14),
+        6 => (1, 5),
+        7 => (0, 11),
+        8 => (0, 10),
+        9 => (0, 9),
+        10 => (0, 30),
+        11 => (0, 23),
+        12 => (0, 12),
+        13 => (0, 17),
+        14 => (0, 1),
+        15 => (0, 13),
+        16 => (1, 2),
+        19 => (0, 26),
+        20 => (1, 0),
+        _ => return None,
+    };
+    Some(port * 32 + pin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::motor::all_motors_pwm;
+
+    #[test]
+    fn keyestudio_matches_motor_mapping() {
+        let commands = [
+            (Direction::Forward, 100),
+            (Direction::Reverse, 200),
+            (Direction::Forward, 300),
+            (Direction::Reverse, 400),
+        ];
+        assert_eq!(
+            KEYESTUDIO_MECANUM.motors_pwm(&commands).as_slice(),
+            all_motors_pwm(&commands)
+        );
+    }
+
+    #[test]
+    fn two_channel_wiring() {
+        let config = MotorConfig::new(MotorWiring::TwoChannel {
+            forward: 0,
+            reverse: 1,
+        });
+        assert_eq!(
+            config.pwm(Direction::Forward, 1000).as_slice(),
+            [(0, 0, 1000), (1, 0, 0)]
+        );
+        assert_eq!(
+            config.pwm(Direction::Reverse, 1000).as_slice(),
+            [(0, 0, 0), (1, 0, 1000)]
+        );
+    }
+
+    #[test]
+    fn inverted_motor_swaps_direction() {
+        let mut config = KEYESTUDIO_MECANUM.motor(Motor::FrontLeft).unwrap();
+        config.inverted = true;
+        assert_eq!(
+            config.pwm(Direction::Forward, 1000).as_slice(),
+            Motor::FrontLeft.channels().pwm(Direction::Reverse, 1000)
+        );
+    }
+
+    #[test]
+    fn missing_motors_are_skipped() {
+        let batch = TWO_WHEEL.motors_pwm(&[(Direction::Forward, 500); 4]);
+        assert_eq!(
+            batch.as_slice(),
+            [(0, 0, 500), (1, 0, 0), (2, 0, 500), (3, 0, 0)]
+        );
+        assert!(TWO_WHEEL
+            .motor_pwm(Motor::BackLeft, Direction::Forward, 500)
+            .as_slice()
+            .is_empty());
+    }
+
+    #[test]
+    fn edge_connector_pins() {
+        let pins = KEYESTUDIO_MECANUM.pins;
+        // The pins the robot base has always used.
+        assert_eq!(edge_connector_gpio(pins.line_left), Some(3));
+        assert_eq!(edge_connector_gpio(pins.line_right), Some(4));
+        assert_eq!(edge_connector_gpio(pins.sonar_trig), Some(13));
+        assert_eq!(edge_connector_gpio(pins.sonar_echo), Some(32 + 2));
+        assert_eq!(edge_connector_gpio(pins.servo), Some(1));
+        assert_eq!(edge_connector_gpio(17), None);
+    }
+
+    #[test]
+    fn profile_pins_are_usable() {
+        assert!(KEYESTUDIO_MECANUM.pins.are_usable());
+        assert!(TWO_WHEEL.pins.are_usable());
+        let pins = KEYESTUDIO_MECANUM.pins;
+        // Button A.
+        assert!(!BoardPins { servo: 5, ..pins }.are_usable());
+        // I2C clock.
+        assert!(!BoardPins {
+            ir_receiver: 19,
+            ..pins
+        }
+        .are_usable());
+        assert!(!BoardPins {
+            line_right: 1,
+            ..pins
+        }
+        .are_usable());
+        assert!(!BoardPins {
+            sonar_echo: 17,
+            ..pins
+        }
+        .are_usable());
+    }
+}
@@ -0,0 +1,417 @@
+//! Keeping I2C devices usable when the bus misbehaves.
+//! Transactions are retried a few times before failing. A device that keeps failing gets
+//! the bus clocked free and is set up again, and one that still fails is marked unavailable
+//! and only tried again now and then, so the rest of the robot keeps running without it.
+
+use crate::roc::DeviceStatus;
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal_async::i2c::{ErrorType, I2c, Operation, SevenBitAddress};
+
+/// Tries for each transaction. A NACK from a loose connector rarely lasts more than one.
+pub const ATTEMPTS: u8 = 3;
+/// Failed operations in a row, after all their attempts, before a device is given up on.
+pub const DEGRADE_AFTER: u8 = 3;
+/// Wait before the first retry of an unavailable device. It doubles after each failed retry.
+pub const MIN_BACKOFF_MS: u32 = 100;
+pub const MAX_BACKOFF_MS: u32 = 5_000;
+/// How often a working device is checked for having been reset by a brown out.
+pub const RESET_CHECK_MS: u64 = 1_000;
+/// A slave can be at most 8 data bits and an ack into a byte when it loses track.
+pub const RECOVERY_CLOCKS: u8 = 9;
+/// Half a clock period at 100kHz.
+const RECOVERY_HALF_PERIOD_US: u32 = 5;
+
+/// I2C bus that retries each transaction up to `ATTEMPTS` times.
+pub struct Retry<I> {
+    i2c: I,
+    /// Transactions that needed more than one attempt, for logging.
+    pub retried: u32,
+}
+
+impl<I> Retry<I> {
+    pub fn new(i2c: I) -> Retry<I> {
+        Retry { i2c, retried: 0 }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut I {
+        &mut self.i2c
+    }
+
+    pub fn release(self) -> I {
+        self.i2c
+    }
+}
+
+impl<I: ErrorType> ErrorType for Retry<I> {
+    type Error = I::Error;
+}
+
+impl<I: I2c> I2c<SevenBitAddress> for Retry<I> {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let mut attempt = 1;
+        loop {
+            match self.i2c.transaction(address, operations).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= ATTEMPTS => return Err(e),
+                Err(_) => {
+                    if attempt == 1 {
+                        self.retried = self.retried.saturating_add(1);
+                    }
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// What to do after an operation failed every attempt.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Clock the bus free and set the device up again, then carry on.
+    Reinit,
+    /// Leave the device alone until `DeviceHealth::should_try` says otherwise.
+    Degraded,
+}
+
+/// Tracks whether one device on the bus is working.
+#[derive(Debug, Clone)]
+pub struct DeviceHealth {
+    status: DeviceStatus,
+    // Failed operations in a row.
+    failures: u8,
+    backoff_ms: u32,
+    retry_at_ms: u64,
+    next_check_ms: u64,
+}
+
+impl DeviceHealth {
+    pub const fn new() -> DeviceHealth {
+        DeviceHealth {
+            status: DeviceStatus::Available,
+            failures: 0,
+            backoff_ms: MIN_BACKOFF_MS,
+            retry_at_ms: 0,
+            next_check_ms: RESET_CHECK_MS,
+        }
+    }
+
+    pub fn status(&self) -> DeviceStatus {
+        self.status
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.status == DeviceStatus::Available
+    }
+
+    /// Whether to talk to the device at `now_ms`. An unavailable device is only tried
+    /// once its backoff has passed, and has to be set up again before it is used.
+    pub fn should_try(&self, now_ms: u64) -> bool {
+        self.is_available() || now_ms >= self.retry_at_ms
+    }
+
+    /// Records a working operation. Returns true if the device was unavailable until now.
+    pub fn succeeded(&mut self) -> bool {
+        let recovered = !self.is_available();
+        self.status = DeviceStatus::Available;
+        self.failures = 0;
+        self.backoff_ms = MIN_BACKOFF_MS;
+        recovered
+    }
+
+    /// Records an operation that failed every attempt at `now_ms`.
+    pub fn failed(&mut self, now_ms: u64) -> Recovery {
+        self.failures = self.failures.saturating_add(1);
+        if self.is_available() && self.failures < DEGRADE_AFTER {
+            return Recovery::Reinit;
+        }
+        if self.is_available() {
+            self.status = DeviceStatus::Unavailable;
+        } else {
+            self.backoff_ms = (self.backoff_ms * 2).min(MAX_BACKOFF_MS);
+        }
+        self.retry_at_ms = now_ms + self.backoff_ms as u64;
+        Recovery::Degraded
+    }
+
+    /// Whether a working device is due a check that it has not been reset by a brown out.
+    /// Returns true at most once every `RESET_CHECK_MS`.
+    pub fn reset_check_due(&mut self, now_ms: u64) -> bool {
+        if !self.is_available() || now_ms < self.next_check_ms {
+            return false;
+        }
+        self.next_check_ms = now_ms + RESET_CHECK_MS;
+        true
+    }
+}
+
+impl Default for DeviceHealth {
+    fn default() -> DeviceHealth {
+        DeviceHealth::new()
+    }
+}
+
+/// Frees a bus stuck with SDA low, from a slave that was cut off in the middle of a byte,
+/// by clocking SCL until the slave lets go and then sending a stop.
+/// Both pins have to be open drain, and the I2C peripheral has to have let go of them.
+/// Returns whether SDA was released.
+pub fn recover_bus<P: InputPin + OutputPin>(
+    scl: &mut P,
+    sda: &mut P,
+    delay: &mut impl DelayNs,
+) -> Result<bool, P::Error> {
+    scl.set_high()?;
+    sda.set_high()?;
+    delay.delay_us(RECOVERY_HALF_PERIOD_US);
+    for _ in 0..RECOVERY_CLOCKS {
+        if sda.is_high()? {
+            break;
+        }
+        scl.set_low()?;
+        delay.delay_us(RECOVERY_HALF_PERIOD_US);
+        scl.set_high()?;
+        delay.delay_us(RECOVERY_HALF_PERIOD_US);
+    }
+    let released = sda.is_high()?;
+    // A stop is SDA rising while SCL is high.
+    scl.set_low()?;
+    delay.delay_us(RECOVERY_HALF_PERIOD_US);
+    sda.set_low()?;
+    delay.delay_us(RECOVERY_HALF_PERIOD_US);
+    scl.set_high()?;
+    delay.delay_us(RECOVERY_HALF_PERIOD_US);
+    sda.set_high()?;
+    delay.delay_us(RECOVERY_HALF_PERIOD_US);
+    Ok(released)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pca9685::mock::{MockError, MockPca9685, NoDelay as NoAsyncDelay};
+    use crate::pca9685::Pca9685;
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+    use futures::executor::block_on;
+
+    const ADDR: u8 = 0x47;
+
+    /// Fails the next `failures` transactions before passing them on.
+    struct Flaky {
+        i2c: MockPca9685,
+        failures: u8,
+    }
+
+    impl ErrorType for Flaky {
+        type Error = MockError;
+    }
+
+    impl I2c<SevenBitAddress> for Flaky {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(MockError);
+            }
+            self.i2c.transaction(address, operations).await
+        }
+    }
+
+    /// An initialized driver whose next `failures` transactions fail.
+    fn flaky(failures: u8) -> Pca9685<Retry<Flaky>> {
+        let i2c = Flaky {
+            i2c: MockPca9685::new(ADDR),
+            failures: 0,
+        };
+        let mut pwm = Pca9685::new(Retry::new(i2c), ADDR);
+        block_on(pwm.init(&mut NoAsyncDelay)).unwrap();
+        pwm.bus_mut().inner_mut().failures = failures;
+        pwm
+    }
+
+    #[test]
+    fn retries_until_success() {
+        let mut pwm = flaky(ATTEMPTS - 1);
+        let before = pwm.bus().i2c.i2c.transactions;
+        block_on(pwm.set_pwm(3, 0, 100)).unwrap();
+        assert_eq!(pwm.bus().retried, 1);
+        let mock = pwm.release().release().i2c;
+        assert_eq!(mock.channel(3), (0, 100));
+        assert_eq!(mock.transactions, before + 1);
+    }
+
+    #[test]
+    fn gives_up_after_attempts() {
+        let mut pwm = flaky(ATTEMPTS);
+        assert!(block_on(pwm.set_pwm(3, 0, 100)).is_err());
+        // The next operation goes through.
+        block_on(pwm.set_pwm(3, 0, 100)).unwrap();
+    }
+
+    #[test]
+    fn reinit_then_degrade() {
+        let mut health = DeviceHealth::new();
+        for _ in 1..DEGRADE_AFTER {
+            assert_eq!(health.failed(0), Recovery::Reinit);
+            assert_eq!(health.status(), DeviceStatus::Available);
+        }
+        assert_eq!(health.failed(1_000), Recovery::Degraded);
+        assert_eq!(health.status(), DeviceStatus::Unavailable);
+        assert!(!health.should_try(1_000 + MIN_BACKOFF_MS as u64 - 1));
+        assert!(health.should_try(1_000 + MIN_BACKOFF_MS as u64));
+        assert!(health.succeeded());
+        assert!(!health.succeeded());
+        assert_eq!(health.status(), DeviceStatus::Available);
+        // A success resets the count.
+        assert_eq!(health.failed(2_000), Recovery::Reinit);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut health = DeviceHealth::new();
+        for _ in 0..DEGRADE_AFTER {
+            health.failed(0);
+        }
+        let mut now = 0;
+        let mut waits = [0; 8];
+        for wait in waits.iter_mut() {
+            now += 10_000;
+            assert_eq!(health.failed(now), Recovery::Degraded);
+            *wait = health.retry_at_ms - now;
+        }
+        assert_eq!(waits, [200, 400, 800, 1_600, 3_200, 5_000, 5_000, 5_000]);
+    }
+
+    #[test]
+    fn reset_checks_are_spaced_out() {
+        let mut health = DeviceHealth::new();
+        assert!(!health.reset_check_due(0));
+        assert!(health.reset_check_due(RESET_CHECK_MS));
+        assert!(!health.reset_check_due(RESET_CHECK_MS + 1));
+        assert!(health.reset_check_due(2 * RESET_CHECK_MS + 5));
+        for _ in 0..DEGRADE_AFTER {
+            health.failed(0);
+        }
+        assert!(!health.reset_check_due(10 * RESET_CHECK_MS));
+    }
+
+    /// Open drain SCL and SDA lines with a slave holding SDA low for some clocks.
+    struct Lines {
+        scl: bool,
+        sda_released: bool,
+        slave_clocks: u8,
+        clocks: u8,
+        stopped: bool,
+    }
+
+    struct Line<'a> {
+        lines: &'a RefCell<Lines>,
+        is_scl: bool,
+    }
+
+    impl embedded_hal::digital::ErrorType for Line<'_> {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Line<'_> {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            let mut lines = self.lines.borrow_mut();
+            if self.is_scl {
+                if lines.scl {
+                    lines.clocks += 1;
+                }
+                lines.scl = false;
+            } else {
+                lines.sda_released = false;
+            }
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            let mut lines = self.lines.borrow_mut();
+            if self.is_scl {
+                lines.scl = true;
+            } else {
+                if lines.scl && !lines.sda_released {
+                    lines.stopped = true;
+                }
+                lines.sda_released = true;
+            }
+            Ok(())
+        }
+    }
+
+    impl InputPin for Line<'_> {
+        fn is_high(&mut self) -> Result<bool, Infallible> {
+            let lines = self.lines.borrow();
+            Ok(if self.is_scl {
+                lines.scl
+            } else {
+                lines.sda_released && lines.clocks >= lines.slave_clocks
+            })
+        }
+
+        fn is_low(&mut self) -> Result<bool, Infallible> {
+            self.is_high().map(|high| !high)
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    fn recover(slave_clocks: u8) -> (bool, Lines) {
+        let lines = RefCell::new(Lines {
+            scl: true,
+            sda_released: true,
+            slave_clocks,
+            clocks: 0,
+            stopped: false,
+        });
+        let mut scl = Line {
+            lines: &lines,
+            is_scl: true,
+        };
+        let mut sda = Line {
+            lines: &lines,
+            is_scl: false,
+        };
+        let released = recover_bus(&mut scl, &mut sda, &mut NoDelay).unwrap();
+        (released, lines.into_inner())
+    }
+
+    #[test]
+    fn clocks_stuck_slave_free() {
+        let (released, lines) = recover(4);
+        assert!(released);
+        // Four to free it and one for the stop.
+        assert_eq!(lines.clocks, 5);
+        assert!(lines.stopped);
+        assert!(lines.scl && lines.sda_released);
+    }
+
+    #[test]
+    fn free_bus_just_gets_a_stop() {
+        let (released, lines) = recover(0);
+        assert!(released);
+        assert_eq!(lines.clocks, 1);
+        assert!(lines.stopped);
+    }
+
+    #[test]
+    fn gives_up_after_nine_clocks() {
+        let (released, lines) = recover(20);
+        assert!(!released);
+        assert_eq!(lines.clocks, RECOVERY_CLOCKS + 1);
+    }
+}
@@ -0,0 +1,104 @@
+#[repr(C)]
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Row {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+}
+
+#[repr(C)]
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct DisplayData {
+    pub a: Row,
+    pub b: Row,
+    pub c: Row,
+    pub d: Row,
+    pub e: Row,
+}
+
+impl DisplayData {
+    pub fn from_bytes(bytes: [[u8; 5]; 5]) -> DisplayData {
+        let row = |[a, b, c, d, e]: [u8; 5]| Row { a, b, c, d, e };
+        let [a, b, c, d, e] = bytes;
+        DisplayData {
+            a: row(a),
+            b: row(b),
+            c: row(c),
+            d: row(d),
+            e: row(e),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [[u8; 5]; 5] {
+        [
+            [self.a.a, self.a.b, self.a.c, self.a.d, self.a.e],
+            [self.b.a, self.b.b, self.b.c, self.b.d, self.b.e],
+            [self.c.a, self.c.b, self.c.c, self.c.d, self.c.e],
+            [self.d.a, self.d.b, self.d.c, self.d.d, self.d.e],
+            [self.e.a, self.e.b, self.e.c, self.e.d, self.e.e],
+        ]
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for DisplayData {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{:?}", self.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(a: u8, b: u8, c: u8, d: u8, e: u8) -> Row {
+        Row { a, b, c, d, e }
+    }
+
+    #[test]
+    fn empty_display_is_blank() {
+        assert_eq!(DisplayData::default().to_bytes(), [[0; 5]; 5]);
+    }
+
+    #[test]
+    fn to_bytes_is_row_major() {
+        let data = DisplayData {
+            a: row(1, 2, 3, 4, 5),
+            b: row(6, 7, 8, 9, 10),
+            c: row(11, 12, 13, 14, 15),
+            d: row(16, 17, 18, 19, 20),
+            e: row(21, 22, 23, 24, 25),
+        };
+        assert_eq!(
+            data.to_bytes(),
+            [
+                [1, 2, 3, 4, 5],
+                [6, 7, 8, 9, 10],
+                [11, 12, 13, 14, 15],
+                [16, 17, 18, 19, 20],
+                [21, 22, 23, 24, 25],
+            ]
+        );
+    }
+
+    #[test]
+    fn from_bytes_round_trips() {
+        let bytes = [
+            [1, 0, 0, 0, 2],
+            [0; 5],
+            [0, 0, 3, 0, 0],
+            [0; 5],
+            [4, 0, 0, 0, 5],
+        ];
+        assert_eq!(DisplayData::from_bytes(bytes).to_bytes(), bytes);
+    }
+
+    #[test]
+    fn layout_matches_roc_display() {
+        // Roc passes `Display Row Row Row Row Row` as 25 packed bytes.
+        assert_eq!(core::mem::size_of::<DisplayData>(), 25);
+        assert_eq!(core::mem::align_of::<DisplayData>(), 1);
+    }
+}
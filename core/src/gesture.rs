@@ -0,0 +1,455 @@
+//! Gestures from the accelerometer, like MakeCode's `on shake` and `on tilt left`, seen from
+//! the robot's point of view. Orientation and free fall come from the LSM303AGR's 6D and
+//! free fall engines, which watch every sample however slowly they are read. Shakes and
+//! being picked up are picked out of the samples here.
+//! Being picked up, dropped or tipped over also cuts the motors, so the wheels do not spin
+//! in someone's hands.
+
+use crate::accel::{AccelConfig, AccelData};
+use crate::mag::{cross, dot, Mounting};
+use crate::roc::Gesture;
+
+// INT1_CFG_A and INT2_CFG_A bits.
+const AOI: u8 = 0b1000_0000;
+const SIX_D: u8 = 0b0100_0000;
+const LOW_EVENTS: u8 = 0b0001_0101;
+const ALL_EVENTS: u8 = 0b0011_1111;
+// CTRL_REG5_A bits.
+const LIR_INT1: u8 = 0b0000_1000;
+// INT1_SRC_A and INT2_SRC_A bits.
+const IA: u8 = 0b0100_0000;
+
+/// Every axis below this for `FREE_FALL_MS` is falling.
+pub const FREE_FALL_MG: i32 = 350;
+pub const FREE_FALL_MS: u32 = 30;
+/// An axis past this for `ORIENTATION_MS`, about 40 degrees of tilt, sets the orientation.
+pub const ORIENTATION_MG: i32 = 650;
+pub const ORIENTATION_MS: u32 = 100;
+/// A jolt is the acceleration being this far from 1g.
+pub const SHAKE_MG: f32 = 800.0;
+/// Jolts within `SHAKE_WINDOW_MS` that make a shake.
+pub const SHAKE_JOLTS: u32 = 4;
+pub const SHAKE_WINDOW_MS: u64 = 1000;
+/// Smoothed upwards acceleration past this is being lifted. Bumps are too short to reach it.
+pub const LIFT_MG: f32 = 1150.0;
+/// Time constant of the smoothed upwards acceleration. The same as taking a tenth of each
+/// sample at 100Hz, and kept the same at any data rate.
+const LIFT_SMOOTHING_MS: f32 = 95.0;
+/// Within this of 1g counts as still.
+pub const STILL_MG: f32 = 150.0;
+/// How long the robot has to sit still on its wheels before the motors run again.
+pub const SETTLE_MS: u64 = 2000;
+
+/// Register values that set up the free fall engine on IA1 and the 6D engine on IA2.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EngineRegisters {
+    pub ctrl_reg5: u8,
+    pub int1_cfg: u8,
+    pub int1_ths: u8,
+    pub int1_duration: u8,
+    pub int2_cfg: u8,
+    pub int2_ths: u8,
+    pub int2_duration: u8,
+}
+
+impl EngineRegisters {
+    /// Thresholds and durations are counted in steps that depend on the range and data rate.
+    pub fn new(config: &AccelConfig) -> EngineRegisters {
+        let ths = |mg: i32| (mg / config.scale.threshold_mg_per_step()).clamp(1, 127) as u8;
+        let period_us = config.odr.period_us().unwrap_or(1_000_000);
+        let duration = |ms: u32| (ms * 1000 / period_us).min(127) as u8;
+        EngineRegisters {
+            // Free fall is latched, so a short drop between reads is not missed.
+            ctrl_reg5: LIR_INT1,
+            int1_cfg: AOI | LOW_EVENTS,
+            int1_ths: ths(FREE_FALL_MG),
+            int1_duration: duration(FREE_FALL_MS),
+            int2_cfg: AOI | SIX_D | ALL_EVENTS,
+            int2_ths: ths(ORIENTATION_MG),
+            int2_duration: duration(ORIENTATION_MS),
+        }
+    }
+}
+
+/// INT1_SRC_A and INT2_SRC_A, read after each sample.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EngineSources {
+    pub int1_src: u8,
+    pub int2_src: u8,
+}
+
+impl EngineSources {
+    pub fn free_fall(&self) -> bool {
+        self.int1_src & IA != 0
+    }
+
+    /// The chip axis pointing up in the 6D engine's position, None between positions.
+    pub fn position(&self) -> Option<[f32; 3]> {
+        if self.int2_src & IA == 0 {
+            return None;
+        }
+        // Low and high bits for x, then y, then z.
+        let mut up = [0.0; 3];
+        for (axis, up) in up.iter_mut().enumerate() {
+            let bits = (self.int2_src >> (axis * 2)) & 0b11;
+            *up = match bits {
+                0b01 => -1.0,
+                0b10 => 1.0,
+                _ => 0.0,
+            };
+        }
+        Some(up)
+    }
+}
+
+/// Which way up the robot is, with `up` in the chip's axes.
+fn orientation(up: [f32; 3], mounting: Mounting) -> Option<Gesture> {
+    let forward = mounting.forward();
+    let robot_up = mounting.up();
+    let left = cross(robot_up, forward);
+    let (forward, left, up) = (dot(up, forward), dot(up, left), dot(up, robot_up));
+    // A tilted side points up less, so forward tilts make the forward axis point down.
+    match () {
+        _ if up > 0.5 => Some(Gesture::FaceUp),
+        _ if up < -0.5 => Some(Gesture::FaceDown),
+        _ if forward < -0.5 => Some(Gesture::TiltForward),
+        _ if forward > 0.5 => Some(Gesture::TiltBackward),
+        _ if left < -0.5 => Some(Gesture::TiltLeft),
+        _ if left > 0.5 => Some(Gesture::TiltRight),
+        _ => None,
+    }
+}
+
+pub struct GestureDetector {
+    mounting: Mounting,
+    orientation: Gesture,
+    falling: bool,
+    // Smoothed acceleration along the robot's up axis.
+    up_mg: f32,
+    // Time of the last sample, None before the first.
+    last_sample_ms: Option<u64>,
+    jolting: bool,
+    jolts: u32,
+    first_jolt_ms: u64,
+    // Set when the robot is picked up, dropped or tipped over, until it has settled.
+    handled: bool,
+    last_moved_ms: u64,
+}
+
+impl GestureDetector {
+    pub fn new(mounting: Mounting) -> GestureDetector {
+        GestureDetector {
+            mounting,
+            orientation: Gesture::FaceUp,
+            falling: false,
+            up_mg: 1000.0,
+            last_sample_ms: None,
+            jolting: false,
+            jolts: 0,
+            first_jolt_ms: 0,
+            handled: false,
+            last_moved_ms: 0,
+        }
+    }
+
+    /// The last position the 6D engine settled on, `FaceUp` to start with.
+    pub fn orientation(&self) -> Gesture {
+        self.orientation
+    }
+
+    /// False from being picked up, dropped or tipped over until the robot has sat still on
+    /// its wheels for `SETTLE_MS`. Held very still in the air also counts as set down.
+    pub fn motors_allowed(&self) -> bool {
+        !self.handled
+    }
+
+    /// Takes in the next sample in milli-g, the time it was taken, and what the engines saw
+    /// since the last one. Samples read in a burst go in one at a time, each at its own time.
+    /// Returns the gesture that just happened, if any.
+    pub fn update(
+        &mut self,
+        accel: &AccelData,
+        sources: EngineSources,
+        now_ms: u64,
+    ) -> Option<Gesture> {
+        let deviation = libm::fabsf(accel.magnitude() - 1000.0);
+        if deviation > STILL_MG {
+            self.last_moved_ms = now_ms;
+        }
+        let sample = [accel.x as f32, accel.y as f32, accel.z as f32];
+        let dt_ms = match self.last_sample_ms {
+            Some(last) => now_ms.saturating_sub(last) as f32,
+            None => 0.0,
+        };
+        self.last_sample_ms = Some(now_ms);
+        let smoothing = 1.0 - libm::expf(-dt_ms / LIFT_SMOOTHING_MS);
+        self.up_mg += (dot(sample, self.mounting.up()) - self.up_mg) * smoothing;
+
+        // Least important first, so a more important gesture in the same sample wins.
+        let mut gesture = None;
+        let position = sources.position();
+        if let Some(orientation) = position.and_then(|up| orientation(up, self.mounting)) {
+            if orientation != self.orientation {
+                self.orientation = orientation;
+                gesture = Some(orientation);
+            }
+        }
+        if self.shaken(deviation, now_ms) {
+            gesture = Some(Gesture::Shake);
+        }
+        let upright = self.orientation == Gesture::FaceUp;
+        if upright && !self.handled && self.up_mg > LIFT_MG {
+            self.handled = true;
+            gesture = Some(Gesture::Lifted);
+        }
+        let falling = sources.free_fall();
+        if falling && !self.falling {
+            self.handled = true;
+            gesture = Some(Gesture::FreeFall);
+        }
+        self.falling = falling;
+
+        if !upright {
+            self.handled = true;
+            self.last_moved_ms = now_ms;
+        }
+        if self.handled && now_ms.saturating_sub(self.last_moved_ms) >= SETTLE_MS {
+            self.handled = false;
+        }
+        gesture
+    }
+
+    /// Counts jolts, and says whether there have been enough close together for a shake.
+    fn shaken(&mut self, deviation: f32, now_ms: u64) -> bool {
+        // Half the threshold to end a jolt, so noise on the edge is not counted twice.
+        let jolting = if self.jolting {
+            deviation > SHAKE_MG / 2.0
+        } else {
+            deviation > SHAKE_MG
+        };
+        let started = jolting && !self.jolting;
+        self.jolting = jolting;
+        if !started {
+            return false;
+        }
+        if self.jolts == 0 || now_ms - self.first_jolt_ms > SHAKE_WINDOW_MS {
+            self.jolts = 0;
+            self.first_jolt_ms = now_ms;
+        }
+        self.jolts += 1;
+        if self.jolts < SHAKE_JOLTS {
+            return false;
+        }
+        self.jolts = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accel::{AccelOdr, FullScale, Resolution};
+
+    // 6D positions in INT2_SRC_A.
+    const XL: u8 = 0b00_0001;
+    const XH: u8 = 0b00_0010;
+    const YL: u8 = 0b00_0100;
+    const YH: u8 = 0b00_1000;
+    const ZL: u8 = 0b01_0000;
+    const ZH: u8 = 0b10_0000;
+
+    const LEVEL: AccelData = AccelData {
+        x: 0,
+        y: 1000,
+        z: 0,
+    };
+
+    fn position(bits: u8) -> EngineSources {
+        EngineSources {
+            int1_src: 0,
+            int2_src: IA | bits,
+        }
+    }
+
+    /// Feeds `accel` at 100Hz from `start_ms` for `ms`, returning the gestures seen.
+    fn run(
+        detector: &mut GestureDetector,
+        accel: AccelData,
+        sources: EngineSources,
+        start_ms: u64,
+        ms: u64,
+    ) -> Vec<Gesture> {
+        run_every(detector, accel, sources, start_ms, ms, 10)
+    }
+
+    fn run_every(
+        detector: &mut GestureDetector,
+        accel: AccelData,
+        sources: EngineSources,
+        start_ms: u64,
+        ms: u64,
+        period_ms: usize,
+    ) -> Vec<Gesture> {
+        (start_ms..start_ms + ms)
+            .step_by(period_ms)
+            .filter_map(|now| detector.update(&accel, sources, now))
+            .collect()
+    }
+
+    #[test]
+    fn engine_registers() {
+        let registers = EngineRegisters::new(&AccelConfig::default());
+        assert_eq!(
+            registers,
+            EngineRegisters {
+                ctrl_reg5: 0x08,
+                int1_cfg: 0x95,
+                int1_ths: 21,
+                int1_duration: 3,
+                int2_cfg: 0xFF,
+                int2_ths: 40,
+                int2_duration: 10,
+            }
+        );
+        let slow = EngineRegisters::new(&AccelConfig {
+            odr: AccelOdr::Hz10,
+            scale: FullScale::G16,
+            resolution: Resolution::LowPower,
+        });
+        assert_eq!(slow.int1_ths, 1);
+        assert_eq!(slow.int1_duration, 0);
+        assert_eq!(slow.int2_duration, 1);
+    }
+
+    #[test]
+    fn orientation_from_robot_view() {
+        let upright = |bits| orientation(position(bits).position().unwrap(), Mounting::Upright);
+        assert_eq!(upright(YH), Some(Gesture::FaceUp));
+        assert_eq!(upright(YL), Some(Gesture::FaceDown));
+        assert_eq!(upright(ZL), Some(Gesture::TiltForward));
+        assert_eq!(upright(ZH), Some(Gesture::TiltBackward));
+        assert_eq!(upright(XL), Some(Gesture::TiltLeft));
+        assert_eq!(upright(XH), Some(Gesture::TiltRight));
+
+        let flat = |bits| orientation(position(bits).position().unwrap(), Mounting::Flat);
+        assert_eq!(flat(ZH), Some(Gesture::FaceUp));
+        assert_eq!(flat(YL), Some(Gesture::TiltForward));
+        assert_eq!(flat(XH), Some(Gesture::TiltLeft));
+        assert_eq!(EngineSources::default().position(), None);
+    }
+
+    #[test]
+    fn flipping_cuts_the_motors_until_settled() {
+        let mut detector = GestureDetector::new(Mounting::Upright);
+        assert!(run(&mut detector, LEVEL, position(YH), 0, 500).is_empty());
+        assert!(detector.motors_allowed());
+
+        let upside_down = AccelData { y: -1000, ..LEVEL };
+        let seen = run(&mut detector, upside_down, position(YL), 500, 3000);
+        assert_eq!(seen, [Gesture::FaceDown]);
+        assert!(!detector.motors_allowed());
+
+        let seen = run(&mut detector, LEVEL, position(YH), 3500, SETTLE_MS - 10);
+        assert_eq!(seen, [Gesture::FaceUp]);
+        assert!(!detector.motors_allowed());
+        run(&mut detector, LEVEL, position(YH), 3500 + SETTLE_MS, 20);
+        assert!(detector.motors_allowed());
+    }
+
+    #[test]
+    fn free_fall_is_seen_once() {
+        let mut detector = GestureDetector::new(Mounting::Upright);
+        let falling = EngineSources {
+            int1_src: IA | 0b01_0101,
+            int2_src: 0,
+        };
+        let seen = run(&mut detector, AccelData::default(), falling, 0, 200);
+        assert_eq!(seen, [Gesture::FreeFall]);
+        assert!(!detector.motors_allowed());
+    }
+
+    #[test]
+    fn shake() {
+        let mut detector = GestureDetector::new(Mounting::Upright);
+        let jolt = AccelData { x: 1900, ..LEVEL };
+        let mut seen = Vec::new();
+        for i in 0..4 {
+            seen.extend(run(&mut detector, jolt, position(YH), i * 200, 50));
+            seen.extend(run(&mut detector, LEVEL, position(YH), i * 200 + 50, 150));
+        }
+        assert_eq!(seen, [Gesture::Shake]);
+        // Jolts too far apart are bumps.
+        for i in 0..4 {
+            seen.extend(run(&mut detector, jolt, position(YH), 2000 + i * 600, 50));
+            seen.extend(run(&mut detector, LEVEL, position(YH), 2050 + i * 600, 550));
+        }
+        assert_eq!(seen, [Gesture::Shake]);
+        assert!(detector.motors_allowed());
+    }
+
+    #[test]
+    fn lifted() {
+        let mut detector = GestureDetector::new(Mounting::Upright);
+        // A bump from driving over something is too short.
+        let up = AccelData { y: 1600, ..LEVEL };
+        assert!(run(&mut detector, up, position(YH), 0, 20).is_empty());
+        assert!(run(&mut detector, LEVEL, position(YH), 20, 500).is_empty());
+        assert!(detector.motors_allowed());
+
+        let lift = AccelData { y: 1300, ..LEVEL };
+        let seen = run(&mut detector, lift, position(YH), 1000, 300);
+        assert_eq!(seen, [Gesture::Lifted]);
+        assert!(!detector.motors_allowed());
+        // Wobbling in someone's hands keeps the motors off.
+        let wobble = AccelData { x: 600, ..LEVEL };
+        for i in 0..5 {
+            run(&mut detector, wobble, position(YH), 1300 + i * 1000, 10);
+            run(&mut detector, LEVEL, position(YH), 1310 + i * 1000, 990);
+        }
+        assert!(!detector.motors_allowed());
+        run(&mut detector, LEVEL, position(YH), 6300, SETTLE_MS);
+        assert!(detector.motors_allowed());
+    }
+
+    #[test]
+    fn lifting_takes_as_long_at_any_data_rate() {
+        let lift = AccelData { y: 1300, ..LEVEL };
+        let lifted_after = |period_ms| {
+            let mut detector = GestureDetector::new(Mounting::Upright);
+            run_every(&mut detector, LEVEL, position(YH), 0, 100, period_ms);
+            (100..1000)
+                .step_by(period_ms)
+                .find(|now| detector.update(&lift, position(YH), *now) == Some(Gesture::Lifted))
+        };
+        let at_100hz = lifted_after(10).unwrap();
+        let at_500hz = lifted_after(2).unwrap();
+        assert!(
+            at_100hz.abs_diff(at_500hz) <= 10,
+            "{} {}",
+            at_100hz,
+            at_500hz
+        );
+    }
+
+    #[test]
+    fn shake_from_a_burst_of_fast_samples() {
+        // Read from the FIFO at 400Hz, so every jolt only lasts a few samples.
+        let mut detector = GestureDetector::new(Mounting::Upright);
+        let jolt = AccelData { x: 1900, ..LEVEL };
+        let mut seen = Vec::new();
+        for i in 0..4 {
+            seen.extend(run_every(&mut detector, jolt, position(YH), i * 150, 10, 2));
+            seen.extend(run_every(
+                &mut detector,
+                LEVEL,
+                position(YH),
+                i * 150 + 10,
+                140,
+                2,
+            ));
+        }
+        assert_eq!(seen, [Gesture::Shake]);
+    }
+}
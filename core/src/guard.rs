@@ -0,0 +1,172 @@
+//! Sonar based collision guard that keeps the robot from driving forward into walls.
+//! Only the forward part of a wheel command is limited, so reversing and turning still work.
+
+use crate::roc::GuardStatus;
+use crate::sonar::SonarReading;
+
+pub const DEFAULT_STOP_DISTANCE_MM: u32 = 100;
+pub const DEFAULT_SLOW_DISTANCE_MM: u32 = 300;
+/// Forward speed is limited as if something were this close while the sonar cannot be
+/// trusted, halfway through the default slow zone.
+pub const UNTRUSTED_DISTANCE_MM: u32 = 200;
+
+pub struct CollisionGuard {
+    stop_distance_mm: u32,
+    slow_distance_mm: u32,
+    // Latest trusted obstacle, None when nothing was in range.
+    obstacle_mm: Option<u32>,
+    trusted: bool,
+}
+
+impl CollisionGuard {
+    /// Forward motion is blocked within `stop_distance_mm` and scaled down linearly
+    /// until `slow_distance_mm`. Until the first trusted reading it counts as untrusted.
+    pub fn new(stop_distance_mm: u32, slow_distance_mm: u32) -> CollisionGuard {
+        CollisionGuard {
+            stop_distance_mm,
+            slow_distance_mm: slow_distance_mm.max(stop_distance_mm),
+            obstacle_mm: None,
+            trusted: false,
+        }
+    }
+
+    /// Takes in the latest filtered sonar reading. While the pings disagree the last
+    /// trusted obstacle is kept, and nothing further than `UNTRUSTED_DISTANCE_MM` is assumed.
+    pub fn update(&mut self, reading: &SonarReading) {
+        self.trusted = reading.valid;
+        if reading.valid {
+            self.obstacle_mm = reading.distance_mm;
+        }
+    }
+
+    /// How far away the guard takes the nearest obstacle to be, None for nothing in range.
+    pub fn distance_mm(&self) -> Option<u32> {
+        if self.trusted {
+            return self.obstacle_mm;
+        }
+        let untrusted = UNTRUSTED_DISTANCE_MM;
+        Some(self.obstacle_mm.map_or(untrusted, |d| d.min(untrusted)))
+    }
+
+    /// How much of the forward speed is allowed at `distance_mm`, from 0 to 1.
+    /// `None` means the sonar saw nothing in range.
+    pub fn forward_scale(&self, distance_mm: Option<u32>) -> f32 {
+        match distance_mm {
+            None => 1.0,
+            Some(d) if d <= self.stop_distance_mm => 0.0,
+            Some(d) if d >= self.slow_distance_mm => 1.0,
+            Some(d) => {
+                (d - self.stop_distance_mm) as f32
+                    / (self.slow_distance_mm - self.stop_distance_mm) as f32
+            }
+        }
+    }
+
+    /// Limits signed wheel speeds, in `Motor::ALL` order, for the obstacle at `distance_mm`,
+    /// and says how.
+    pub fn apply(&self, speeds: [i16; 4]) -> ([i16; 4], GuardStatus) {
+        // The forward component is what every wheel has in common.
+        // Turning and strafing are differences between wheels, so they are left alone.
+        let forward = speeds.iter().map(|s| *s as i32).sum::<i32>() / 4;
+        let scale = self.forward_scale(self.distance_mm());
+        if forward <= 0 || scale >= 1.0 {
+            return (speeds, GuardStatus::Clear);
+        }
+        let removed = libm::roundf(forward as f32 * (1.0 - scale)) as i32;
+        let limited = speeds.map(|s| (s as i32 - removed) as i16);
+        let status = if scale <= 0.0 {
+            GuardStatus::Blocked
+        } else {
+            GuardStatus::Slowed
+        };
+        (limited, status)
+    }
+}
+
+impl Default for CollisionGuard {
+    fn default() -> CollisionGuard {
+        CollisionGuard::new(DEFAULT_STOP_DISTANCE_MM, DEFAULT_SLOW_DISTANCE_MM)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(distance_mm: Option<u32>, valid: bool) -> SonarReading {
+        SonarReading {
+            distance_mm,
+            valid,
+            age_us: Some(0),
+        }
+    }
+
+    fn guard_at(distance_mm: Option<u32>) -> CollisionGuard {
+        let mut guard = CollisionGuard::default();
+        guard.update(&reading(distance_mm, true));
+        guard
+    }
+
+    #[test]
+    fn clear_when_far_or_out_of_range() {
+        let speeds = [2000; 4];
+        assert_eq!(
+            guard_at(Some(1000)).apply(speeds),
+            (speeds, GuardStatus::Clear)
+        );
+        assert_eq!(guard_at(None).apply(speeds), (speeds, GuardStatus::Clear));
+    }
+
+    #[test]
+    fn blocks_forward_when_close() {
+        assert_eq!(
+            guard_at(Some(50)).apply([2000; 4]),
+            ([0; 4], GuardStatus::Blocked)
+        );
+    }
+
+    #[test]
+    fn scales_forward_in_slow_zone() {
+        let mut guard = CollisionGuard::new(100, 300);
+        guard.update(&reading(Some(200), true));
+        assert_eq!(guard.apply([2000; 4]), ([1000; 4], GuardStatus::Slowed));
+    }
+
+    #[test]
+    fn allows_reverse_and_rotation() {
+        let guard = guard_at(Some(20));
+        let reverse = [-2000; 4];
+        assert_eq!(guard.apply(reverse), (reverse, GuardStatus::Clear));
+        let spin = [2000, 2000, -2000, -2000];
+        assert_eq!(guard.apply(spin), (spin, GuardStatus::Clear));
+    }
+
+    #[test]
+    fn keeps_turn_while_blocking_forward() {
+        // Forward 1000 while turning right.
+        let (speeds, status) = guard_at(Some(20)).apply([2000, 2000, 0, 0]);
+        assert_eq!(speeds, [1000, 1000, -1000, -1000]);
+        assert_eq!(status, GuardStatus::Blocked);
+    }
+
+    #[test]
+    fn slows_until_readings_are_trusted() {
+        let guard = CollisionGuard::default();
+        assert_eq!(guard.distance_mm(), Some(UNTRUSTED_DISTANCE_MM));
+        assert_eq!(guard.apply([2000; 4]), ([1000; 4], GuardStatus::Slowed));
+    }
+
+    #[test]
+    fn untrusted_readings_keep_the_last_obstacle() {
+        let mut guard = guard_at(Some(50));
+        guard.update(&reading(Some(2000), false));
+        assert_eq!(guard.distance_mm(), Some(50));
+        assert_eq!(guard.apply([2000; 4]).1, GuardStatus::Blocked);
+
+        let mut guard = guard_at(None);
+        guard.update(&reading(None, false));
+        assert_eq!(guard.distance_mm(), Some(UNTRUSTED_DISTANCE_MM));
+        guard.update(&reading(None, true));
+        assert_eq!(guard.distance_mm(), None);
+    }
+}
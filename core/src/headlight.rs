@@ -0,0 +1,222 @@
+//! Brightness and blink patterns for the two headlight leds on the robot base.
+//! Patterns are worked out from the time, so the host can refresh them whenever
+//! it is already talking to the PCA9685.
+
+use crate::pca9685::MAX_COUNT;
+use crate::roc::HeadlightMode;
+
+/// Headlight channels on the KeyeStudio base.
+pub const LEFT_CHANNEL: u8 = 12;
+pub const RIGHT_CHANNEL: u8 = 13;
+pub const MAX_BRIGHTNESS: u16 = MAX_COUNT;
+/// About the rate of a car indicator.
+pub const TURN_SIGNAL_PERIOD_MS: u32 = 700;
+/// Periods of the patterns Roc can pick with `HeadlightMode`.
+pub const BLINK_PERIOD_MS: u32 = 1000;
+pub const BREATHE_PERIOD_MS: u32 = 3000;
+/// How much faster one side's wheels have to go before it counts as turning.
+pub const TURN_THRESHOLD: i16 = 400;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    Solid,
+    /// On for the first half of each period.
+    Blink {
+        period_ms: u32,
+    },
+    /// Fades smoothly up and back down once per period.
+    Breathe {
+        period_ms: u32,
+    },
+    /// Blinks while the robot turns towards this light, solid otherwise.
+    TurnSignal,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Headlight {
+    /// Peak brightness, from 0 to `MAX_BRIGHTNESS`.
+    pub brightness: u16,
+    pub pattern: Pattern,
+}
+
+impl Headlight {
+    pub const OFF: Headlight = Headlight {
+        brightness: 0,
+        pattern: Pattern::Solid,
+    };
+    pub const ON: Headlight = Headlight {
+        brightness: MAX_BRIGHTNESS,
+        pattern: Pattern::Solid,
+    };
+
+    /// Brightness at `t_ms`, where `turning` says whether the robot turns towards this light.
+    pub fn duty(&self, t_ms: u64, turning: bool) -> u16 {
+        let brightness = self.brightness.min(MAX_BRIGHTNESS);
+        let blink = |period_ms: u32| {
+            let period_ms = period_ms.max(1) as u64;
+            if t_ms % period_ms < period_ms / 2 {
+                brightness
+            } else {
+                0
+            }
+        };
+        match self.pattern {
+            Pattern::Solid => brightness,
+            Pattern::Blink { period_ms } => blink(period_ms),
+            Pattern::TurnSignal if turning => blink(TURN_SIGNAL_PERIOD_MS),
+            Pattern::TurnSignal => brightness,
+            Pattern::Breathe { period_ms } => {
+                let period_ms = period_ms.max(1) as u64;
+                let phase = (t_ms % period_ms) as f32 / period_ms as f32;
+                let level = (1.0 - libm::cosf(2.0 * core::f32::consts::PI * phase)) / 2.0;
+                // Squared, since leds look much brighter than their duty cycle when dim.
+                libm::roundf(brightness as f32 * level * level) as u16
+            }
+        }
+    }
+}
+
+/// Which way the robot is turning from its signed wheel speeds in `Motor::ALL` order.
+/// Returns `(left, right)`.
+pub fn turning(speeds: [i16; 4]) -> (bool, bool) {
+    let [front_left, back_left, front_right, back_right] = speeds.map(i32::from);
+    let diff = (front_right + back_right - front_left - back_left) / 2;
+    let threshold = TURN_THRESHOLD as i32;
+    (diff > threshold, diff < -threshold)
+}
+
+pub struct Headlights {
+    pub left: Headlight,
+    pub right: Headlight,
+}
+
+impl Headlights {
+    /// Returns the `(channel, on, off)` counts for both lights at `t_ms`,
+    /// with the lights on the left and right `channels`.
+    pub fn pwm(&self, channels: [u8; 2], t_ms: u64, speeds: [i16; 4]) -> [(u8, u16, u16); 2] {
+        let (left_turn, right_turn) = turning(speeds);
+        let [left, right] = channels;
+        [
+            (left, 0, self.left.duty(t_ms, left_turn)),
+            (right, 0, self.right.duty(t_ms, right_turn)),
+        ]
+    }
+}
+
+impl From<HeadlightMode> for Headlights {
+    /// Both lights at full brightness, in the same pattern.
+    fn from(mode: HeadlightMode) -> Headlights {
+        let pattern = match mode {
+            HeadlightMode::Off => return Headlights::default(),
+            HeadlightMode::On => Pattern::Solid,
+            HeadlightMode::Blink => Pattern::Blink {
+                period_ms: BLINK_PERIOD_MS,
+            },
+            HeadlightMode::Breathe => Pattern::Breathe {
+                period_ms: BREATHE_PERIOD_MS,
+            },
+            HeadlightMode::TurnSignals => Pattern::TurnSignal,
+        };
+        let light = Headlight {
+            brightness: MAX_BRIGHTNESS,
+            pattern,
+        };
+        Headlights {
+            left: light,
+            right: light,
+        }
+    }
+}
+
+impl Default for Headlights {
+    fn default() -> Headlights {
+        Headlights {
+            left: Headlight::OFF,
+            right: Headlight::OFF,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solid_brightness_is_clamped() {
+        let light = Headlight {
+            brightness: 5000,
+            pattern: Pattern::Solid,
+        };
+        assert_eq!(light.duty(123, false), MAX_BRIGHTNESS);
+        assert_eq!(Headlight::OFF.duty(0, true), 0);
+    }
+
+    #[test]
+    fn blink() {
+        let light = Headlight {
+            brightness: 1000,
+            pattern: Pattern::Blink { period_ms: 500 },
+        };
+        assert_eq!(light.duty(0, false), 1000);
+        assert_eq!(light.duty(249, false), 1000);
+        assert_eq!(light.duty(250, false), 0);
+        assert_eq!(light.duty(1_000, false), 1000);
+    }
+
+    #[test]
+    fn breathe() {
+        let light = Headlight {
+            brightness: 2000,
+            pattern: Pattern::Breathe { period_ms: 1000 },
+        };
+        assert_eq!(light.duty(0, false), 0);
+        assert_eq!(light.duty(500, false), 2000);
+        assert_eq!(light.duty(250, false), 500);
+        assert_eq!(light.duty(250, false), light.duty(750, false));
+    }
+
+    #[test]
+    fn turn_signal_follows_wheels() {
+        assert_eq!(turning([0; 4]), (false, false));
+        // Right wheels faster turns left.
+        assert_eq!(turning([1000, 1000, 2000, 2000]), (true, false));
+        assert_eq!(turning([1000, 1000, -1000, -1000]), (false, true));
+        // Small differences are just steering noise.
+        assert_eq!(turning([1000, 1000, 1200, 1200]), (false, false));
+
+        let lights = Headlights {
+            left: Headlight {
+                brightness: 100,
+                pattern: Pattern::TurnSignal,
+            },
+            right: Headlight {
+                brightness: 100,
+                pattern: Pattern::TurnSignal,
+            },
+        };
+        let channels = [LEFT_CHANNEL, RIGHT_CHANNEL];
+        let spin_left = [-2000, -2000, 2000, 2000];
+        assert_eq!(
+            lights.pwm(channels, 0, spin_left),
+            [(LEFT_CHANNEL, 0, 100), (RIGHT_CHANNEL, 0, 100)]
+        );
+        let off_half = (TURN_SIGNAL_PERIOD_MS / 2) as u64;
+        assert_eq!(
+            lights.pwm(channels, off_half, spin_left),
+            [(LEFT_CHANNEL, 0, 0), (RIGHT_CHANNEL, 0, 100)]
+        );
+    }
+    #[test]
+    fn roc_modes() {
+        let off = Headlights::from(HeadlightMode::Off);
+        assert_eq!((off.left, off.right), (Headlight::OFF, Headlight::OFF));
+        let on = Headlights::from(HeadlightMode::On);
+        assert_eq!((on.left, on.right), (Headlight::ON, Headlight::ON));
+        let signals = Headlights::from(HeadlightMode::TurnSignals);
+        assert_eq!(signals.left.pattern, Pattern::TurnSignal);
+        assert_eq!(signals.right, signals.left);
+        assert_eq!(signals.left.brightness, MAX_BRIGHTNESS);
+    }
+}
@@ -0,0 +1,433 @@
+//! NEC and RC5 decoding for the IR receiver on the robot base.
+//! The firmware reports each edge of the receiver output and both protocols are decoded
+//! from the time between them, so either kind of remote works.
+
+use crate::roc::IrEvent;
+
+/// Pulses within a quarter of the expected length are accepted.
+const TOLERANCE_DIVISOR: u32 = 4;
+
+const NEC_LEADER_MARK_US: u32 = 9_000;
+const NEC_LEADER_SPACE_US: u32 = 4_500;
+const NEC_REPEAT_SPACE_US: u32 = 2_250;
+const NEC_BIT_MARK_US: u32 = 560;
+const NEC_ZERO_SPACE_US: u32 = 560;
+const NEC_ONE_SPACE_US: u32 = 1_690;
+const NEC_BITS: u8 = 32;
+
+const RC5_HALF_BIT_US: u32 = 889;
+const RC5_BITS: u8 = 14;
+
+/// Remotes resend about every 110ms while a key is held.
+pub const REPEAT_TIMEOUT_US: u64 = 150_000;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrProtocol {
+    Nec,
+    Rc5,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrCode {
+    pub protocol: IrProtocol,
+    /// 8 or 16 bits for NEC depending on the remote, 5 bits for RC5.
+    pub address: u16,
+    pub command: u8,
+    /// The key is being held down, rather than newly pressed.
+    pub repeat: bool,
+}
+
+fn near(duration_us: u32, expected_us: u32) -> bool {
+    duration_us.abs_diff(expected_us) <= expected_us / TOLERANCE_DIVISOR
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Nec {
+    Idle,
+    LeaderSpace,
+    BitMark { bits: u32, count: u8 },
+    BitSpace { bits: u32, count: u8 },
+    RepeatMark,
+}
+
+impl Nec {
+    fn next(self, mark: bool, duration_us: u32) -> (Nec, Option<NecFrame>) {
+        use Nec::*;
+        // A leader always starts a new frame, whatever came before.
+        if mark && near(duration_us, NEC_LEADER_MARK_US) {
+            return (LeaderSpace, None);
+        }
+        match (self, mark) {
+            (LeaderSpace, false) if near(duration_us, NEC_LEADER_SPACE_US) => {
+                (BitMark { bits: 0, count: 0 }, None)
+            }
+            (LeaderSpace, false) if near(duration_us, NEC_REPEAT_SPACE_US) => (RepeatMark, None),
+            (BitMark { bits, count }, true) if near(duration_us, NEC_BIT_MARK_US) => {
+                if count == NEC_BITS {
+                    // The stop mark.
+                    (Idle, Some(NecFrame::Code(bits)))
+                } else {
+                    (BitSpace { bits, count }, None)
+                }
+            }
+            (BitSpace { bits, count }, false) => {
+                let bit = if near(duration_us, NEC_ZERO_SPACE_US) {
+                    0
+                } else if near(duration_us, NEC_ONE_SPACE_US) {
+                    1
+                } else {
+                    return (Idle, None);
+                };
+                // Bits are sent least significant first.
+                let bits = bits | bit << count;
+                (
+                    BitMark {
+                        bits,
+                        count: count + 1,
+                    },
+                    None,
+                )
+            }
+            (RepeatMark, true) if near(duration_us, NEC_BIT_MARK_US) => {
+                (Idle, Some(NecFrame::Repeat))
+            }
+            _ => (Idle, None),
+        }
+    }
+}
+
+enum NecFrame {
+    Code(u32),
+    Repeat,
+}
+
+fn nec_code(bits: u32) -> Option<IrCode> {
+    let [address_low, address_high, command, inverse_command] = bits.to_le_bytes();
+    if command != !inverse_command {
+        return None;
+    }
+    // Extended NEC uses the inverted address byte for 8 more address bits.
+    let address = if address_high == !address_low {
+        address_low as u16
+    } else {
+        u16::from_le_bytes([address_low, address_high])
+    };
+    Some(IrCode {
+        protocol: IrProtocol::Nec,
+        address,
+        command,
+        repeat: false,
+    })
+}
+
+/// RC5 is Manchester coded, so it is collected as half bits and decoded once complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rc5 {
+    // Level of each half bit so far, earliest in the highest bit.
+    halves: u32,
+    count: u8,
+}
+
+impl Rc5 {
+    const IDLE: Rc5 = Rc5 {
+        halves: 0,
+        count: 0,
+    };
+
+    fn push(&mut self, mark: bool, count: u8) {
+        for _ in 0..count {
+            self.halves = self.halves << 1 | mark as u32;
+            self.count += 1;
+        }
+    }
+
+    /// Returns the code and its toggle bit once a frame is complete.
+    fn next(mut self, mark: bool, duration_us: u32) -> (Rc5, Option<(IrCode, bool)>) {
+        let halves = if near(duration_us, RC5_HALF_BIT_US) {
+            1
+        } else if near(duration_us, 2 * RC5_HALF_BIT_US) {
+            2
+        } else {
+            return (Rc5::IDLE, None);
+        };
+        if self.count == 0 {
+            if !mark {
+                // Idle between frames.
+                return (Rc5::IDLE, None);
+            }
+            // The first start bit is a one, its space half is lost in the idle before it.
+            self.push(false, 1);
+        }
+        self.push(mark, halves);
+        if self.count == 2 * RC5_BITS - 1 && mark {
+            // A final zero ends on a space that runs into the idle after the frame.
+            self.push(false, 1);
+        }
+        if self.count < 2 * RC5_BITS {
+            return (self, None);
+        }
+        if self.count > 2 * RC5_BITS {
+            return (Rc5::IDLE, None);
+        }
+        (Rc5::IDLE, self.decode())
+    }
+
+    fn decode(&self) -> Option<(IrCode, bool)> {
+        let mut bits = 0u16;
+        for i in (0..RC5_BITS).rev() {
+            bits = bits << 1
+                | match (self.halves >> (2 * i)) & 0b11 {
+                    // Space then mark is a one.
+                    0b01 => 1,
+                    0b10 => 0,
+                    _ => return None,
+                };
+        }
+        // Start bit, field bit, toggle, 5 address bits and 6 command bits.
+        if bits >> 13 != 1 {
+            return None;
+        }
+        let field = (bits >> 12) & 1;
+        let toggle = (bits >> 11) & 1 == 1;
+        let address = (bits >> 6) & 0x1F;
+        // The field bit is the inverted 7th command bit, so plain RC5 commands have it set.
+        let command = (bits & 0x3F) as u8 | ((field as u8 ^ 1) << 6);
+        let code = IrCode {
+            protocol: IrProtocol::Rc5,
+            address,
+            command,
+            repeat: false,
+        };
+        Some((code, toggle))
+    }
+}
+
+pub struct IrDecoder {
+    last_edge_us: Option<u64>,
+    nec: Nec,
+    rc5: Rc5,
+    // Last code and when it, or a repeat of it, was received.
+    last: Option<(IrCode, u64)>,
+    // RC5 flips this bit on each new press, so the same code and toggle is a held key.
+    rc5_toggle: bool,
+    event: IrEvent,
+}
+
+impl IrDecoder {
+    pub const fn new() -> IrDecoder {
+        IrDecoder {
+            last_edge_us: None,
+            nec: Nec::Idle,
+            rc5: Rc5::IDLE,
+            last: None,
+            rc5_toggle: false,
+            event: IrEvent::Idle,
+        }
+    }
+
+    /// Records a change of the receiver output at `at_us`. `mark` is true when it starts
+    /// seeing the carrier, which is when the receiver pin goes low.
+    /// Returns any code completed by the edge.
+    pub fn edge(&mut self, at_us: u64, mark: bool) -> Option<IrCode> {
+        let last_edge_us = self.last_edge_us.replace(at_us)?;
+        let duration_us = (at_us - last_edge_us).min(u32::MAX as u64) as u32;
+        // The edge ends a pulse of the opposite level.
+        let was_mark = !mark;
+
+        let (nec, frame) = self.nec.next(was_mark, duration_us);
+        self.nec = nec;
+        let (rc5, rc5_code) = self.rc5.next(was_mark, duration_us);
+        self.rc5 = rc5;
+
+        let recent = self
+            .last
+            .filter(|(_, seen_us)| at_us - seen_us <= REPEAT_TIMEOUT_US)
+            .map(|(code, _)| IrCode {
+                repeat: true,
+                ..code
+            });
+        let code = match (frame, rc5_code) {
+            (Some(NecFrame::Code(bits)), _) => nec_code(bits),
+            (Some(NecFrame::Repeat), _) => recent.filter(|code| code.protocol == IrProtocol::Nec),
+            (None, Some((code, toggle))) => {
+                let held = toggle == self.rc5_toggle
+                    && recent
+                        == Some(IrCode {
+                            repeat: true,
+                            ..code
+                        });
+                self.rc5_toggle = toggle;
+                Some(IrCode {
+                    repeat: held,
+                    ..code
+                })
+            }
+            (None, None) => None,
+        }?;
+        self.last = Some((code, at_us));
+        self.event = match (self.event, code.repeat) {
+            (IrEvent::Pressed, _) | (_, false) => IrEvent::Pressed,
+            (_, true) => IrEvent::Repeat,
+        };
+        Some(code)
+    }
+
+    /// The last code received, including repeats.
+    pub fn last(&self) -> Option<IrCode> {
+        self.last.map(|(code, _)| code)
+    }
+
+    /// What happened since the last call. A new press wins over repeats.
+    pub fn take_event(&mut self) -> IrEvent {
+        core::mem::take(&mut self.event)
+    }
+}
+
+impl Default for IrDecoder {
+    fn default() -> IrDecoder {
+        IrDecoder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds alternating mark and space lengths, starting with a mark at `start_us`.
+    fn send(decoder: &mut IrDecoder, start_us: u64, pulses: &[u32]) -> Option<IrCode> {
+        let mut at_us = start_us;
+        let mut code = decoder.edge(at_us, true);
+        for (i, pulse) in pulses.iter().enumerate() {
+            at_us += *pulse as u64;
+            // Even pulses are marks, so they end by going back to a space.
+            code = decoder.edge(at_us, i % 2 == 1).or(code);
+        }
+        code
+    }
+
+    fn nec_frame(bytes: [u8; 4]) -> [u32; 67] {
+        let mut pulses = [NEC_BIT_MARK_US; 67];
+        pulses[0] = NEC_LEADER_MARK_US;
+        pulses[1] = NEC_LEADER_SPACE_US;
+        let bits = u32::from_le_bytes(bytes);
+        for i in 0..32 {
+            pulses[3 + 2 * i] = if bits >> i & 1 == 1 {
+                NEC_ONE_SPACE_US
+            } else {
+                NEC_ZERO_SPACE_US
+            };
+        }
+        pulses
+    }
+
+    const NEC_REPEAT: [u32; 3] = [NEC_LEADER_MARK_US, NEC_REPEAT_SPACE_US, NEC_BIT_MARK_US];
+
+    /// Pulses for an RC5 frame, merging neighbouring half bits of the same level.
+    fn rc5_frame(toggle: bool, address: u8, command: u8) -> ([u32; 28], usize) {
+        let bits =
+            1 << 13 | 1 << 12 | (toggle as u16) << 11 | (address as u16) << 6 | command as u16;
+        let mut pulses = [0; 28];
+        let mut len = 0;
+        let mut level = true;
+        // Skip the space half of the first start bit, it is part of the idle.
+        for half in 1..28 {
+            let bit = bits >> (13 - half / 2) & 1;
+            let mark = (bit == 1) == (half % 2 == 1);
+            if mark != level {
+                len += 1;
+                level = mark;
+            }
+            pulses[len] += RC5_HALF_BIT_US;
+        }
+        // A trailing space is not an edge.
+        if !level {
+            pulses[len] = 0;
+        } else {
+            len += 1;
+        }
+        (pulses, len)
+    }
+
+    #[test]
+    fn tolerance() {
+        assert!(near(700, NEC_BIT_MARK_US));
+        assert!(!near(710, NEC_BIT_MARK_US));
+        assert!(near(1_300, NEC_ONE_SPACE_US));
+    }
+
+    #[test]
+    fn nec_code_and_repeat() {
+        let mut decoder = IrDecoder::new();
+        let code = send(&mut decoder, 0, &nec_frame([0x00, 0xFF, 0x45, !0x45])).unwrap();
+        assert_eq!(
+            code,
+            IrCode {
+                protocol: IrProtocol::Nec,
+                address: 0x00,
+                command: 0x45,
+                repeat: false,
+            }
+        );
+        assert_eq!(decoder.take_event(), IrEvent::Pressed);
+        assert_eq!(decoder.take_event(), IrEvent::Idle);
+
+        let repeat = send(&mut decoder, 108_000, &NEC_REPEAT).unwrap();
+        assert!(repeat.repeat);
+        assert_eq!(repeat.command, 0x45);
+        assert_eq!(decoder.take_event(), IrEvent::Repeat);
+        // Too long since the last code to be a held key.
+        assert_eq!(send(&mut decoder, 1_000_000, &NEC_REPEAT), None);
+    }
+
+    #[test]
+    fn nec_extended_address_and_bad_command() {
+        let mut decoder = IrDecoder::new();
+        let pulses = nec_frame([0x34, 0x12, 0x10, !0x10]);
+        assert_eq!(send(&mut decoder, 0, &pulses).unwrap().address, 0x1234);
+
+        let pulses = nec_frame([0x00, 0xFF, 0x10, 0x10]);
+        assert_eq!(send(&mut decoder, 200_000, &pulses), None);
+    }
+
+    #[test]
+    fn rc5_code_and_toggle() {
+        let mut decoder = IrDecoder::new();
+        let (pulses, len) = rc5_frame(false, 0x05, 0x0C);
+        let code = send(&mut decoder, 0, &pulses[..len]).unwrap();
+        assert_eq!(
+            code,
+            IrCode {
+                protocol: IrProtocol::Rc5,
+                address: 0x05,
+                command: 0x0C,
+                repeat: false,
+            }
+        );
+        // Same toggle shortly after is the key being held.
+        let code = send(&mut decoder, 114_000, &pulses[..len]).unwrap();
+        assert!(code.repeat);
+        // A new press flips the toggle.
+        let (pulses, len) = rc5_frame(true, 0x05, 0x0C);
+        let code = send(&mut decoder, 228_000, &pulses[..len]).unwrap();
+        assert!(!code.repeat);
+        assert_eq!(decoder.take_event(), IrEvent::Pressed);
+    }
+
+    #[test]
+    fn rc5_ending_in_zero() {
+        let mut decoder = IrDecoder::new();
+        let (pulses, len) = rc5_frame(true, 0x1F, 0x3E);
+        let code = send(&mut decoder, 0, &pulses[..len]).unwrap();
+        assert_eq!((code.address, code.command), (0x1F, 0x3E));
+    }
+
+    #[test]
+    fn noise_is_ignored() {
+        let mut decoder = IrDecoder::new();
+        assert_eq!(send(&mut decoder, 0, &[300, 7_000, 1_200, 40_000]), None);
+        assert_eq!(decoder.last(), None);
+        assert_eq!(decoder.take_event(), IrEvent::Idle);
+    }
+}
@@ -0,0 +1,26 @@
+//! Hardware independent pieces of the roc-microbit platform.
+//!
+//! Everything in here builds for the host so it can be tested with a plain `cargo test`.
+//! The firmware in `platform` only adds the pin and peripheral glue on top.
+#![cfg_attr(not(test), no_std)]
+
+pub mod accel;
+pub mod board;
+pub mod bus;
+pub mod display;
+pub mod gesture;
+pub mod guard;
+pub mod headlight;
+pub mod ir;
+pub mod line;
+pub mod mag;
+pub mod motor;
+pub mod odometry;
+pub mod pca9685;
+pub mod ramp;
+pub mod roc;
+pub mod scan;
+pub mod servo;
+pub mod settings;
+pub mod sonar;
+pub mod wiring;
@@ -0,0 +1,226 @@
+//! Debounced edge tracking for the two line sensors.
+//! The firmware reports raw pin edges from GPIOTE and this turns them into
+//! timestamped transitions, so crossings narrower than a tick are still seen.
+
+use crate::roc::LightLevel;
+
+/// The sensors are clean, but the edge of tape can chatter for a moment.
+pub const DEFAULT_DEBOUNCE_US: u64 = 2_000;
+/// Transitions kept for the firmware to read. Older ones are dropped first.
+pub const EVENT_QUEUE_LEN: usize = 16;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left = 0,
+    Right = 1,
+}
+
+/// A debounced change of level on one sensor.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineEvent {
+    pub side: Side,
+    /// When the first raw edge of the change happened.
+    pub at_us: u64,
+    /// The level the sensor settled at.
+    pub level: LightLevel,
+}
+
+#[derive(Clone, Copy)]
+struct Debouncer {
+    level: LightLevel,
+    // First and latest raw edges since the level last settled.
+    first_edge_us: Option<u64>,
+    last_edge_us: u64,
+}
+
+pub struct LineEdges {
+    debounce_us: u64,
+    sensors: [Debouncer; 2],
+    // Transitions since `take_transitions` was last called, saturating.
+    transitions: [u8; 2],
+    events: [Option<LineEvent>; EVENT_QUEUE_LEN],
+    // Index of the oldest event.
+    head: usize,
+    len: usize,
+}
+
+impl LineEdges {
+    /// Both sensors start out `Bright`, call `settle` once to pick up their real levels.
+    pub const fn new(debounce_us: u64) -> LineEdges {
+        let sensor = Debouncer {
+            level: LightLevel::Bright,
+            first_edge_us: None,
+            last_edge_us: 0,
+        };
+        LineEdges {
+            debounce_us,
+            sensors: [sensor; 2],
+            transitions: [0; 2],
+            events: [None; EVENT_QUEUE_LEN],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn set_debounce_us(&mut self, debounce_us: u64) {
+        self.debounce_us = debounce_us;
+    }
+
+    /// Debounced level of a sensor.
+    pub fn level(&self, side: Side) -> LightLevel {
+        self.sensors[side as usize].level
+    }
+
+    /// Records a raw edge on a sensor's pin.
+    pub fn edge(&mut self, side: Side, at_us: u64) {
+        let sensor = &mut self.sensors[side as usize];
+        sensor.first_edge_us.get_or_insert(at_us);
+        sensor.last_edge_us = at_us;
+    }
+
+    /// When the pin will have been quiet long enough to read, None if there is no edge to settle.
+    pub fn settle_at_us(&self, side: Side) -> Option<u64> {
+        let sensor = &self.sensors[side as usize];
+        sensor.first_edge_us?;
+        Some(sensor.last_edge_us + self.debounce_us)
+    }
+
+    /// Takes the pin's `level` at `now_us` once it has stopped bouncing.
+    /// Returns the transition if the level actually changed.
+    pub fn settle(&mut self, side: Side, now_us: u64, level: LightLevel) -> Option<LineEvent> {
+        let debounce_us = self.debounce_us;
+        let sensor = &mut self.sensors[side as usize];
+        let at_us = match sensor.first_edge_us {
+            Some(_) if now_us < sensor.last_edge_us + debounce_us => return None,
+            Some(first) => first,
+            None => now_us,
+        };
+        sensor.first_edge_us = None;
+        if level == sensor.level {
+            // A glitch that bounced back.
+            return None;
+        }
+        sensor.level = level;
+        let event = LineEvent { side, at_us, level };
+        self.transitions[side as usize] = self.transitions[side as usize].saturating_add(1);
+        self.push(event);
+        Some(event)
+    }
+
+    /// Returns the left and right transition counts since the last call, and resets them.
+    pub fn take_transitions(&mut self) -> (u8, u8) {
+        let [left, right] = core::mem::take(&mut self.transitions);
+        (left, right)
+    }
+
+    /// Oldest transition not yet read.
+    pub fn pop_event(&mut self) -> Option<LineEvent> {
+        let event = self.events[self.head].take()?;
+        self.head = (self.head + 1) % EVENT_QUEUE_LEN;
+        self.len -= 1;
+        Some(event)
+    }
+
+    fn push(&mut self, event: LineEvent) {
+        if self.len == EVENT_QUEUE_LEN {
+            self.head = (self.head + 1) % EVENT_QUEUE_LEN;
+            self.len -= 1;
+        }
+        self.events[(self.head + self.len) % EVENT_QUEUE_LEN] = Some(event);
+        self.len += 1;
+    }
+}
+
+impl Default for LineEdges {
+    fn default() -> LineEdges {
+        LineEdges::new(DEFAULT_DEBOUNCE_US)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bouncing_edge_is_one_transition() {
+        let mut edges = LineEdges::new(1_000);
+        edges.edge(Side::Left, 10_000);
+        edges.edge(Side::Left, 10_200);
+        edges.edge(Side::Left, 10_500);
+        assert_eq!(edges.settle_at_us(Side::Left), Some(11_500));
+        // Still bouncing.
+        assert_eq!(edges.settle(Side::Left, 11_000, LightLevel::Dark), None);
+        let event = edges.settle(Side::Left, 11_500, LightLevel::Dark).unwrap();
+        assert_eq!(event.at_us, 10_000);
+        assert_eq!(edges.level(Side::Left), LightLevel::Dark);
+        assert_eq!(edges.settle_at_us(Side::Left), None);
+        assert_eq!(edges.take_transitions(), (1, 0));
+        assert_eq!(edges.take_transitions(), (0, 0));
+    }
+
+    #[test]
+    fn glitch_is_ignored() {
+        let mut edges = LineEdges::new(1_000);
+        edges.edge(Side::Right, 0);
+        edges.edge(Side::Right, 100);
+        assert_eq!(edges.settle(Side::Right, 1_100, LightLevel::Bright), None);
+        assert_eq!(edges.take_transitions(), (0, 0));
+        assert_eq!(edges.pop_event(), None);
+    }
+
+    #[test]
+    fn narrow_line_counts_twice() {
+        let mut edges = LineEdges::new(1_000);
+        edges.edge(Side::Left, 0);
+        edges.settle(Side::Left, 1_000, LightLevel::Dark);
+        edges.edge(Side::Left, 3_000);
+        edges.settle(Side::Left, 4_000, LightLevel::Bright);
+        edges.edge(Side::Right, 500);
+        edges.settle(Side::Right, 1_500, LightLevel::Dark);
+        assert_eq!(edges.take_transitions(), (2, 1));
+        let sides_and_times: [_; 3] = core::array::from_fn(|_| {
+            let event = edges.pop_event().unwrap();
+            (event.side, event.at_us, event.level)
+        });
+        assert_eq!(
+            sides_and_times,
+            [
+                (Side::Left, 0, LightLevel::Dark),
+                (Side::Left, 3_000, LightLevel::Bright),
+                (Side::Right, 500, LightLevel::Dark),
+            ]
+        );
+        assert_eq!(edges.pop_event(), None);
+    }
+
+    #[test]
+    fn settle_without_edge_picks_up_level() {
+        let mut edges = LineEdges::default();
+        let event = edges.settle(Side::Right, 42, LightLevel::Dark).unwrap();
+        assert_eq!(event.at_us, 42);
+        assert_eq!(edges.level(Side::Right), LightLevel::Dark);
+    }
+
+    #[test]
+    fn full_queue_drops_oldest() {
+        let mut edges = LineEdges::new(0);
+        for i in 0..EVENT_QUEUE_LEN as u64 + 2 {
+            let level = if i % 2 == 0 {
+                LightLevel::Dark
+            } else {
+                LightLevel::Bright
+            };
+            edges.edge(Side::Left, i);
+            edges.settle(Side::Left, i, level);
+        }
+        assert_eq!(edges.pop_event().unwrap().at_us, 2);
+        let mut remaining = 0;
+        while edges.pop_event().is_some() {
+            remaining += 1;
+        }
+        assert_eq!(remaining, EVENT_QUEUE_LEN - 1);
+        assert_eq!(edges.take_transitions(), (EVENT_QUEUE_LEN as u8 + 2, 0));
+    }
+}
@@ -0,0 +1,767 @@
+//! Magnetometer settings, data conversion and heading filter for the LSM303AGR.
+
+use crate::accel::AccelData;
+use crate::display::DisplayData;
+use core::f32::consts::PI;
+
+// CFG_REG_A_M bits.
+const LP: u8 = 0b0001_0000;
+const MD_MASK: u8 = 0b0000_0011;
+// CFG_REG_B_M bits.
+const OFF_CANC_ONE_SHOT: u8 = 0b0001_0000;
+const OFF_CANC: u8 = 0b0000_0010;
+const LPF: u8 = 0b0000_0001;
+
+/// Output data rate. The values are the ODR bits of CFG_REG_A_M.
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MagOdr {
+    Hz10 = 0,
+    Hz20 = 1,
+    Hz50 = 2,
+    Hz100 = 3,
+}
+
+impl MagOdr {
+    /// Time between samples in continuous mode.
+    pub fn period_us(self) -> u32 {
+        match self {
+            MagOdr::Hz10 => 100_000,
+            MagOdr::Hz20 => 50_000,
+            MagOdr::Hz50 => 20_000,
+            MagOdr::Hz100 => 10_000,
+        }
+    }
+}
+
+/// The values are the MD bits of CFG_REG_A_M.
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MagMode {
+    /// Measures at the output data rate.
+    Continuous = 0,
+    /// Measures once each time the mode is written, then goes idle.
+    SingleShot = 1,
+    /// Stops measuring, drawing next to no current.
+    Idle = 3,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MagConfig {
+    pub odr: MagOdr,
+    pub mode: MagMode,
+    /// Averages fewer readings per sample, for a quarter of the current and more noise.
+    pub low_power: bool,
+    /// Takes out the sensor's own offset, which drifts with temperature, by measuring
+    /// with the set pulse flipped every sample.
+    pub offset_cancellation: bool,
+    /// Halves the bandwidth to smooth the readings.
+    pub low_pass: bool,
+}
+
+impl MagConfig {
+    /// Fast and smooth, for following the heading while driving.
+    pub const DEFAULT: MagConfig = MagConfig {
+        odr: MagOdr::Hz100,
+        mode: MagMode::Continuous,
+        low_power: false,
+        offset_cancellation: false,
+        low_pass: true,
+    };
+
+    /// For running from batteries, where a slow compass is good enough.
+    pub const LOW_POWER: MagConfig = MagConfig {
+        odr: MagOdr::Hz10,
+        mode: MagMode::Continuous,
+        low_power: true,
+        offset_cancellation: false,
+        low_pass: false,
+    };
+
+    pub fn cfg_reg_a(&self) -> u8 {
+        let lp = if self.low_power { LP } else { 0 };
+        lp | ((self.odr as u8) << 2) | self.mode as u8
+    }
+
+    pub fn cfg_reg_b(&self) -> u8 {
+        let off_canc = match (self.offset_cancellation, self.mode) {
+            (false, _) => 0,
+            // Single shot measurements need their own bit to flip the set pulse.
+            (true, MagMode::SingleShot) => OFF_CANC | OFF_CANC_ONE_SHOT,
+            (true, _) => OFF_CANC,
+        };
+        let lpf = if self.low_pass { LPF } else { 0 };
+        off_canc | lpf
+    }
+
+    /// Whether CFG_REG_A_M read back as `value` still holds this config, which it does
+    /// not after the chip has been power cycled.
+    pub fn matches_cfg_reg_a(&self, value: u8) -> bool {
+        // A single shot measurement goes back to idle by itself.
+        let mask = match self.mode {
+            MagMode::Continuous => 0xFF,
+            MagMode::SingleShot | MagMode::Idle => !MD_MASK,
+        };
+        value & mask == self.cfg_reg_a() & mask
+    }
+}
+
+impl Default for MagConfig {
+    fn default() -> MagConfig {
+        MagConfig::DEFAULT
+    }
+}
+
+/// How the micro:bit sits on the robot, which says which way the robot faces in the
+/// chip's axes.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mounting {
+    /// Standing up with z pointing forwards, as on the KeyeStudio base.
+    Upright,
+    /// Lying face up with y pointing forwards.
+    Flat,
+}
+
+impl Mounting {
+    pub fn up(self) -> [f32; 3] {
+        let mut up = [0.0; 3];
+        up[self.vertical_axis()] = 1.0;
+        up
+    }
+
+    pub fn forward(self) -> [f32; 3] {
+        match self {
+            Mounting::Upright => [0.0, 0.0, 1.0],
+            Mounting::Flat => [0.0, 1.0, 0.0],
+        }
+    }
+
+    /// The chip axis pointing up when the robot is level.
+    pub fn vertical_axis(self) -> usize {
+        match self {
+            Mounting::Upright => 1,
+            Mounting::Flat => 2,
+        }
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MagData {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl MagData {
+    /// Converts the raw little endian x, y, z output registers to nanoTesla.
+    /// Apply a `MagCalibration` before using it as a compass.
+    pub fn from_raw(data: &[u8; 6]) -> MagData {
+        let x = (u16::from(data[0]) | (u16::from(data[1]) << 8)) as i16;
+        let y = (u16::from(data[2]) | (u16::from(data[3]) << 8)) as i16;
+        let z = (u16::from(data[4]) | (u16::from(data[5]) << 8)) as i16;
+        // These need to be scaled by 1.5 to be converted from raw to milliGuass.
+        // We also convert them from milliGauss to nanoTesla by multiplying by 100.
+        // This leads to times 150.
+        MagData {
+            x: x as i32 * 150,
+            y: y as i32 * 150,
+            z: z as i32 * 150,
+        }
+    }
+
+    /// Heading for a board held upright, with y vertical.
+    pub fn heading(&self) -> f32 {
+        libm::atan2f(self.x as f32, self.z as f32)
+    }
+
+    /// The field in a level frame worked out from the gravity in `accel`, so that `heading`
+    /// stays right when the board is tilted. In the level frame y is up, z is the way the
+    /// robot faces flattened onto the ground and x is to the side, like an upright board.
+    /// Returns the data unchanged if there is no usable gravity, like in free fall.
+    pub fn levelled(&self, accel: &AccelData, mounting: Mounting) -> MagData {
+        let forward = mounting.forward();
+        // At rest the accelerometer reads 1g upwards.
+        let up = match normalize([accel.x as f32, accel.y as f32, accel.z as f32]) {
+            Some(up) => up,
+            None => return self.clone(),
+        };
+        let along = dot(forward, up);
+        let flat_forward = [0, 1, 2].map(|i| forward[i] - along * up[i]);
+        let flat_forward = match normalize(flat_forward) {
+            Some(flat_forward) => flat_forward,
+            None => return self.clone(),
+        };
+        let side = cross(up, flat_forward);
+        let m = [self.x as f32, self.y as f32, self.z as f32];
+        MagData {
+            x: libm::roundf(dot(m, side)) as i32,
+            y: libm::roundf(dot(m, up)) as i32,
+            z: libm::roundf(dot(m, flat_forward)) as i32,
+        }
+    }
+}
+
+/// Hard and soft iron correction for one board in its robot.
+/// Hard iron, from magnets and the usb cable, shifts the field, so it is subtracted first.
+/// Soft iron, from nearby steel, squashes the sphere of readings into an ellipsoid, and
+/// `soft_iron` stretches it back.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MagCalibration {
+    /// In nanoTesla.
+    pub offset: [i32; 3],
+    /// Row major.
+    pub soft_iron: [[f32; 3]; 3],
+}
+
+impl MagCalibration {
+    /// Leaves readings as they are.
+    pub const NONE: MagCalibration = MagCalibration {
+        offset: [0; 3],
+        soft_iron: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+    };
+
+    /// Calculated for the original robot with this method:
+    /// https://www.appelsiini.net/2018/calibrate-magnetometer/
+    /// Note, the USB cable definitely affects the hard iron offset...so this is probably off
+    /// by a few thousand. y was not measured.
+    /// The measured scale was (0.9636, 1.0392). The old firmware multiplied z by 1.17
+    /// instead, a slip that reused the digits of the z offset.
+    pub const DEFAULT: MagCalibration = MagCalibration {
+        offset: [77325, 0, -11700],
+        soft_iron: [[0.9636, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0392]],
+    };
+
+    /// Calibration from the smallest and largest reading seen on each axis while the robot
+    /// was turned through every direction. Each axis is centered and scaled to the
+    /// average radius, which handles soft iron lined up with the axes.
+    /// Axes that hardly moved are only centered.
+    /// Only the diagonal of `soft_iron` is filled in. Extremes can not show an ellipsoid
+    /// that is tilted off the axes, which would take fitting every reading, so tilted soft
+    /// iron is left as a heading error that changes with direction.
+    pub fn from_extremes(min: [i32; 3], max: [i32; 3]) -> MagCalibration {
+        let radius = [0, 1, 2].map(|i| (max[i] as f32 - min[i] as f32) / 2.0);
+        let useful = radius.iter().filter(|r| **r > 0.0).count();
+        let average = if useful > 0 {
+            radius.iter().sum::<f32>() / useful as f32
+        } else {
+            1.0
+        };
+        let mut calibration = MagCalibration::NONE;
+        for i in 0..3 {
+            // Rounded towards negative infinity so that odd spans center the same either way.
+            calibration.offset[i] = ((min[i] as i64 + max[i] as i64).div_euclid(2)) as i32;
+            if radius[i] > 0.0 {
+                calibration.soft_iron[i][i] = average / radius[i];
+            }
+        }
+        calibration
+    }
+
+    pub fn apply(&self, data: &MagData) -> MagData {
+        let centered = [
+            (data.x - self.offset[0]) as f32,
+            (data.y - self.offset[1]) as f32,
+            (data.z - self.offset[2]) as f32,
+        ];
+        let [x, y, z] = self
+            .soft_iron
+            .map(|row| libm::roundf(dot(row, centered)) as i32);
+        MagData { x, y, z }
+    }
+}
+
+impl Default for MagCalibration {
+    fn default() -> MagCalibration {
+        MagCalibration::DEFAULT
+    }
+}
+
+/// Heading sectors that have to be seen for a full turn.
+pub const CALIBRATION_SECTORS: u32 = 16;
+/// Fewer samples than this could easily have missed the true extremes.
+pub const MIN_CALIBRATION_SAMPLES: u32 = 200;
+/// The earth's field is 25,000 to 65,000 nT, so a full turn spans at least twice that.
+pub const MIN_CALIBRATION_SPAN: i32 = 30_000;
+
+/// Collects uncalibrated readings while the robot spins in place, and works out a new
+/// calibration from their extremes.
+/// Spinning flat only turns the field through the horizontal axes. The vertical axis hardly
+/// moves, so its calibration is kept from before.
+pub struct MagCalibrator {
+    mounting: Mounting,
+    min: [i32; 3],
+    max: [i32; 3],
+    samples: u32,
+    // Bit per heading sector seen.
+    sectors: u32,
+}
+
+impl MagCalibrator {
+    pub fn new(mounting: Mounting) -> MagCalibrator {
+        MagCalibrator {
+            mounting,
+            min: [i32::MAX; 3],
+            max: [i32::MIN; 3],
+            samples: 0,
+            sectors: 0,
+        }
+    }
+
+    fn horizontal_axes(&self) -> [usize; 2] {
+        match self.mounting.vertical_axis() {
+            0 => [1, 2],
+            1 => [0, 2],
+            _ => [0, 1],
+        }
+    }
+
+    /// Adds a reading from `MagData::from_raw`, before any calibration.
+    pub fn add(&mut self, raw: &MagData) {
+        let values = [raw.x, raw.y, raw.z];
+        for ((min, max), value) in self.min.iter_mut().zip(&mut self.max).zip(values) {
+            *min = (*min).min(value);
+            *max = (*max).max(value);
+        }
+        self.samples += 1;
+        // Sectors around the center so far. It moves early on, but settles within a turn.
+        let [a, b] = self.horizontal_axes();
+        let center = |i: usize| (self.min[i] as f32 + self.max[i] as f32) / 2.0;
+        let angle = libm::atan2f(values[a] as f32 - center(a), values[b] as f32 - center(b));
+        let turn = (angle + PI) / (2.0 * PI);
+        let sector = ((turn * CALIBRATION_SECTORS as f32) as u32).min(CALIBRATION_SECTORS - 1);
+        self.sectors |= 1 << sector;
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// How much of a full turn has been seen, from 0 to 1.
+    pub fn coverage(&self) -> f32 {
+        self.sectors.count_ones() as f32 / CALIBRATION_SECTORS as f32
+    }
+
+    /// Lights the led on the edge of the display for each sector seen, going round clockwise
+    /// from the top left.
+    pub fn display(&self) -> DisplayData {
+        #[rustfmt::skip]
+        const EDGE: [(usize, usize); CALIBRATION_SECTORS as usize] = [
+            (0, 0), (0, 1), (0, 2), (0, 3), (0, 4), (1, 4), (2, 4), (3, 4),
+            (4, 4), (4, 3), (4, 2), (4, 1), (4, 0), (3, 0), (2, 0), (1, 0),
+        ];
+        let mut bytes = [[0; 5]; 5];
+        for (sector, (row, col)) in EDGE.iter().enumerate() {
+            if self.sectors & (1 << sector) != 0 {
+                bytes[*row][*col] = 1;
+            }
+        }
+        DisplayData::from_bytes(bytes)
+    }
+
+    /// Whether enough has been seen for `calibration` to work.
+    /// Expect it to take a couple of turns.
+    pub fn is_complete(&self) -> bool {
+        let spans = self.horizontal_axes().map(|i| self.max[i] - self.min[i]);
+        self.coverage() >= 1.0
+            && self.samples >= MIN_CALIBRATION_SAMPLES
+            && spans.iter().all(|span| *span >= MIN_CALIBRATION_SPAN)
+    }
+
+    /// The new calibration, with the vertical axis kept from `previous`.
+    /// None until `is_complete`.
+    pub fn calibration(&self, previous: &MagCalibration) -> Option<MagCalibration> {
+        if !self.is_complete() {
+            return None;
+        }
+        let vertical = self.mounting.vertical_axis();
+        let (mut min, mut max) = (self.min, self.max);
+        // A zero span leaves the axis unscaled by `from_extremes`.
+        min[vertical] = 0;
+        max[vertical] = 0;
+        let mut calibration = MagCalibration::from_extremes(min, max);
+        calibration.offset[vertical] = previous.offset[vertical];
+        calibration.soft_iron[vertical] = previous.soft_iron[vertical];
+        Some(calibration)
+    }
+}
+
+pub(crate) fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub(crate) fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+/// None for vectors too short to have a useful direction.
+fn normalize(v: [f32; 3]) -> Option<[f32; 3]> {
+    let length = libm::sqrtf(dot(v, v));
+    if length < 1e-3 {
+        return None;
+    }
+    Some(v.map(|c| c / length))
+}
+
+// Where would I add the fact that magnitude = sqrt(x*x+z*z)?
+// Would this require adding x and z as state variables?
+// Actually I think it may require making magnitude a measurement?
+
+// We currently don't have any other angle sensors, so this will just estimate by itself.
+// Once we have wheel encoders, it should be possible to get a pretty noisy delta angle reading.
+// This is a Kalman filter that will go from x, z to the angle of heading.
+pub struct MagFilter {
+    // State is tracking angle, delta_angle, magnitude, x_bias, z_bias, x_scale, z_scale.
+    x: na::SVector<f32, 7>,    // State Vector
+    p: na::SMatrix<f32, 7, 7>, // Estimate Uncertainty
+    q: na::SMatrix<f32, 7, 7>, // Process Noise Uncertainty
+    r: na::SMatrix<f32, 2, 2>, // Measurement Uncertainty
+}
+
+impl MagFilter {
+    pub fn new(mag_full: (MagData, f32)) -> MagFilter {
+        let (mag_data, heading) = mag_full;
+        let x = mag_data.x as f32;
+        let z = mag_data.z as f32;
+        let magnitude = libm::sqrtf(x * x + z * z);
+        MagFilter {
+            x: na::SVector::from([heading, 0.0, magnitude, 0.0, 0.0, 1.0, 1.0]),
+            // TODO: Actually setup/tune below values this.
+            #[rustfmt::skip]
+            p: na::SMatrix::from_row_slice(&[
+                1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+                0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+                0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0,
+                0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0,
+                0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0,
+                0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0,
+                0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+            ]),
+            #[rustfmt::skip]
+            q: na::SMatrix::from_row_slice(&[
+                1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+                0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+                0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0,
+                0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0,
+                0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0,
+                0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0,
+                0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+            ]),
+            #[rustfmt::skip]
+            r: na::SMatrix::from_row_slice(&[
+                1.0, 0.0,
+                0.0, 1.0,
+            ]),
+        }
+    }
+
+    /// `dt` is the time since the last prediction in seconds.
+    fn predict(&mut self, dt: f32) {
+        // State Update:
+        // angle = angle + delta_angle * dt
+        // Everything else is constant
+        let mut f = na::SMatrix::<f32, 7, 7>::identity();
+        // angle, delta_angle = dt
+        f[(0, 1)] = dt;
+
+        // This function is linear so it is fine to directly apply.
+        // Normally, f would be the jacobian and we would have to directly update x.
+        self.x = f * self.x;
+        self.p = f * self.p * f.transpose() + self.q;
+    }
+
+    fn update(&mut self, mag_full: &(MagData, f32)) -> na::SVector<f32, 7> {
+        let (mag_data, _heading) = mag_full;
+        let angle = self.x[0];
+        // let dangle = self.x[1];
+        let magnitude = self.x[2];
+        let x_bias = self.x[3];
+        let z_bias = self.x[4];
+        let x_scale = self.x[5];
+        let z_scale = self.x[6];
+        let sin_angle = libm::sinf(angle);
+        let cos_angle = libm::cosf(angle);
+        // Measurement equations:
+        // x = (magnitude * sin(angle) * x_scale) + x_bias
+        // z = (magnitude * cos(angle) * z_scale) + z_bias
+        let hx = na::SVector::from([
+            (magnitude * sin_angle * x_scale) + x_bias,
+            (magnitude * cos_angle * z_scale) + z_bias,
+        ]);
+        // Measurement jacobian.
+        #[rustfmt::skip]
+        let h = na::SMatrix::<f32, 2, 7>::from_row_slice(&[
+            // dx/d_angle = magnitude * cos(angle) * x_scale
+            // dz/d_angle = -magnitude * sin(angle) * z_scale
+            magnitude * cos_angle * x_scale, -magnitude * sin_angle * z_scale,
+            // dx/d2_angle = 0: Should this actually be the second deravitive of the above?
+            0.0, 0.0,
+            // dx/d_magnitude = sin(angle) * x_scale
+            // dz/d_magnitude = cos(angle) * z_scale
+            sin_angle * x_scale, cos_angle * z_scale,
+            // dx/d_x_bias = 1
+            1.0, 0.0,
+            // dz/d_z_bias = 1
+            0.0, 1.0,
+            // dx/d_x_scale = magnitide * sin(angle)
+            magnitude * sin_angle, 0.0,
+            // dz/d_z_scale = magnitide * cos(angle)
+            0.0, magnitude * cos_angle,
+        ]);
+
+        // TODO: investigate inverse, maybe use pseudo inverse?
+        let k =
+            self.p * h.transpose() * (h * self.p * h.transpose() + self.r).try_inverse().unwrap();
+        let t = na::SMatrix::identity() - k * h;
+        self.p = t * self.p * t.transpose() + k * self.r * k.transpose();
+        let z = na::SVector::from([mag_data.x as f32, mag_data.z as f32]);
+        self.x += k * (z - hx);
+        self.x
+    }
+
+    /// Current estimate of the heading in radians. It is not wrapped, so it can wind
+    /// past +-PI as the robot turns.
+    pub fn heading(&self) -> f32 {
+        self.x[0]
+    }
+
+    /// `dt` is the time since the last call in seconds.
+    pub fn predict_and_update(
+        &mut self,
+        mag_full: &(MagData, f32),
+        dt: f32,
+    ) -> na::SVector<f32, 7> {
+        self.predict(dt);
+        self.update(mag_full)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_registers() {
+        // What the driver always used to write: continuous at 100Hz with the low pass filter.
+        let config = MagConfig::default();
+        assert_eq!(config.cfg_reg_a(), 0b0000_1100);
+        assert_eq!(config.cfg_reg_b(), 0b0000_0001);
+
+        let config = MagConfig {
+            mode: MagMode::SingleShot,
+            offset_cancellation: true,
+            ..MagConfig::LOW_POWER
+        };
+        assert_eq!(config.cfg_reg_a(), 0b0001_0001);
+        assert_eq!(config.cfg_reg_b(), 0b0001_0010);
+        assert_eq!(MagOdr::Hz20.period_us(), 50_000);
+    }
+
+    #[test]
+    fn power_cycle_is_noticed() {
+        // The chip comes back idle at 10Hz.
+        let reset = 0b0000_0011;
+        assert!(MagConfig::default().matches_cfg_reg_a(0b0000_1100));
+        assert!(!MagConfig::default().matches_cfg_reg_a(reset));
+        let single = MagConfig {
+            mode: MagMode::SingleShot,
+            ..MagConfig::LOW_POWER
+        };
+        // Idle once the measurement is done is fine, losing low power is not.
+        assert!(single.matches_cfg_reg_a(0b0001_0011));
+        assert!(!single.matches_cfg_reg_a(reset));
+    }
+
+    fn reading(x: i32, z: i32) -> (MagData, f32) {
+        let data = MagData { x, y: 0, z };
+        let heading = data.heading();
+        (data, heading)
+    }
+
+    fn mag(x: i32, y: i32, z: i32) -> MagData {
+        MagData { x, y, z }
+    }
+
+    fn accel(x: i32, y: i32, z: i32) -> AccelData {
+        AccelData { x, y, z }
+    }
+
+    #[test]
+    fn from_raw_scales_every_axis() {
+        // x = 515, y = -2, z = -100 in raw counts.
+        let data = MagData::from_raw(&[0x03, 0x02, 0xFE, 0xFF, 0x9C, 0xFF]);
+        assert_eq!(data, mag(77250, -300, -15000));
+    }
+
+    #[test]
+    fn default_calibration_matches_original_robot() {
+        let data = MagCalibration::DEFAULT.apply(&mag(77250, -300, -15000));
+        assert_eq!(data.x, (77250 - 77325) * 9_636 / 10_000);
+        assert_eq!(data.y, -300);
+        assert_eq!(data.z, (-15000 + 11700) * 10_392 / 10_000);
+    }
+
+    #[test]
+    fn calibration_from_extremes() {
+        // Shifted by (1000, -2000, 500), and squashed to half size on z.
+        let calibration =
+            MagCalibration::from_extremes([-39000, -42000, -19500], [41000, 38000, 20500]);
+        assert_eq!(calibration.offset, [1000, -2000, 500]);
+        assert_eq!(
+            calibration.apply(&mag(1000, -2000, 20500)),
+            mag(0, 0, 33333)
+        );
+        assert_eq!(calibration.apply(&mag(41000, -2000, 500)), mag(33333, 0, 0));
+        // Spinning flat never moves the vertical axis, so it is only centered.
+        let flat = MagCalibration::from_extremes([-100, 5000, -300], [300, 5000, 100]);
+        assert_eq!(flat.offset, [100, 5000, -100]);
+        assert_eq!(flat.soft_iron[1][1], 1.0);
+        assert_eq!(flat.apply(&mag(300, 5000, -100)), mag(200, 0, 0));
+    }
+
+    /// Raw readings for a full spin on the upright robot, with hard and soft iron applied.
+    fn spin(steps: u32) -> impl Iterator<Item = MagData> {
+        (0..steps).map(move |i| {
+            let angle = 2.0 * PI * i as f32 / steps as f32;
+            let (sin, cos) = libm::sincosf(angle);
+            mag(
+                (30_000.0 * sin * 1.2) as i32 + 5_000,
+                -40_000,
+                (30_000.0 * cos * 0.8) as i32 - 8_000,
+            )
+        })
+    }
+
+    #[test]
+    fn calibrator_needs_a_full_turn() {
+        let mut calibrator = MagCalibrator::new(Mounting::Upright);
+        for data in spin(400).take(200) {
+            calibrator.add(&data);
+        }
+        assert!(calibrator.coverage() < 0.75);
+        assert!(!calibrator.is_complete());
+        assert_eq!(calibrator.calibration(&MagCalibration::NONE), None);
+    }
+
+    #[test]
+    fn calibrator_finds_hard_and_soft_iron() {
+        let mut calibrator = MagCalibrator::new(Mounting::Upright);
+        // The sectors seen in the first turn are skewed while the center moves.
+        for data in spin(400).chain(spin(400)) {
+            calibrator.add(&data);
+        }
+        assert!(calibrator.is_complete());
+        let previous = MagCalibration::DEFAULT;
+        let calibration = calibrator.calibration(&previous).unwrap();
+        assert_eq!(calibration.offset, [5_000, 0, -8_000]);
+        // The vertical axis is left as it was.
+        assert_eq!(calibration.soft_iron[1], previous.soft_iron[1]);
+        // Every heading comes out round again.
+        for data in spin(36) {
+            let corrected = calibration.apply(&data);
+            let radius = libm::hypotf(corrected.x as f32, corrected.z as f32);
+            assert!((radius - 30_000.0).abs() < 100.0, "{}", radius);
+        }
+    }
+
+    #[test]
+    fn calibrator_display_shows_progress() {
+        let mut calibrator = MagCalibrator::new(Mounting::Upright);
+        assert_eq!(calibrator.display().to_bytes(), [[0; 5]; 5]);
+        for data in spin(400).chain(spin(400)) {
+            calibrator.add(&data);
+        }
+        let bytes = calibrator.display().to_bytes();
+        assert_eq!(bytes[0], [1; 5]);
+        assert_eq!(bytes[2], [1, 0, 0, 0, 1]);
+        assert_eq!(bytes[4], [1; 5]);
+    }
+
+    #[test]
+    fn calibrator_rejects_weak_field() {
+        let mut calibrator = MagCalibrator::new(Mounting::Upright);
+        // Something is shielding the chip, the field barely changes as it turns.
+        for data in spin(400).chain(spin(400)) {
+            calibrator.add(&mag(data.x / 10, data.y, data.z / 10));
+        }
+        assert_eq!(calibrator.coverage(), 1.0);
+        assert!(!calibrator.is_complete());
+    }
+
+    #[test]
+    fn heading_is_atan2_of_x_over_z() {
+        assert_eq!(mag(0, 0, 1000).heading(), 0.0);
+        let east = mag(1000, 0, 0).heading();
+        assert!((east - core::f32::consts::FRAC_PI_2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn level_board_keeps_its_heading() {
+        let data = mag(20000, -40000, 20000);
+        let levelled = data.levelled(&accel(0, 1000, 0), Mounting::Upright);
+        assert_eq!(levelled, data);
+        // No gravity to level with.
+        assert_eq!(data.levelled(&accel(0, 0, 0), Mounting::Upright), data);
+    }
+
+    #[test]
+    fn tilt_does_not_change_heading() {
+        // North is 45 degrees to the side and dips steeply down, as in the northern hemisphere.
+        let (side, ahead, down) = (20000.0, 20000.0, -40000.0);
+        let level = mag(side as i32, down as i32, ahead as i32).heading();
+        // Nose up 30 degrees, as if climbing a ramp: the board turns about x.
+        let (sin, cos) = libm::sincosf(30f32.to_radians());
+        let tilted = mag(
+            side as i32,
+            (down * cos - ahead * sin) as i32,
+            (ahead * cos + down * sin) as i32,
+        );
+        let gravity = accel(0, (1000.0 * cos) as i32, (1000.0 * sin) as i32);
+        // Without levelling the dip leaks into the heading.
+        assert!((tilted.heading() - level).abs() > 0.3);
+        let heading = tilted.levelled(&gravity, Mounting::Upright).heading();
+        assert!((heading - level).abs() < 0.01, "{} vs {}", heading, level);
+    }
+
+    #[test]
+    fn other_mountings() {
+        let data = mag(20000, 20000, -40000);
+        let levelled = data.levelled(&accel(0, 0, 1000), Mounting::Flat);
+        assert_eq!(levelled, mag(-20000, -40000, 20000));
+    }
+
+    #[test]
+    fn filter_starts_at_first_reading() {
+        let filter = MagFilter::new(reading(3000, 4000));
+        assert!((filter.x[0] - reading(3000, 4000).1).abs() < 1e-6);
+        assert!((filter.x[2] - 5000.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn filter_tracks_steady_heading() {
+        let first = reading(3000, 4000);
+        let expected = first.1;
+        let mut filter = MagFilter::new(first);
+        for _ in 0..50 {
+            let states = filter.predict_and_update(&reading(3000, 4000), 0.01);
+            assert!((states[0] - expected).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn predict_integrates_rate() {
+        let mut filter = MagFilter::new(reading(0, 1000));
+        filter.x[1] = 2.0;
+        filter.predict(0.5);
+        assert!((filter.x[0] - 1.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,178 @@
+//! Mapping from the KeyeStudio 4WD Mecanum motors to their PCA9685 channels.
+
+use crate::pca9685::{FULL, MAX_COUNT};
+
+/// Highest speed a motor accepts, the PCA9685 has 12 bits of resolution.
+pub const MAX_SPEED: u16 = MAX_COUNT;
+
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    #[default]
+    Forward = 0,
+    Reverse = 1,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Motor {
+    FrontLeft,
+    BackLeft,
+    FrontRight,
+    BackRight,
+}
+
+/// The three PCA9685 channels that drive a single motor.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotorChannels {
+    /// Held fully on to spin in reverse.
+    pub reverse: u8,
+    /// Held fully on to spin forward.
+    pub forward: u8,
+    /// Duty cycle sets the speed.
+    pub speed: u8,
+}
+
+impl Motor {
+    pub const ALL: [Motor; 4] = [
+        Motor::FrontLeft,
+        Motor::BackLeft,
+        Motor::FrontRight,
+        Motor::BackRight,
+    ];
+
+    pub const fn channels(self) -> MotorChannels {
+        let (reverse, forward, speed) = match self {
+            Motor::FrontLeft => (4, 3, 5),
+            Motor::BackLeft => (10, 9, 11),
+            Motor::FrontRight => (2, 1, 0),
+            Motor::BackRight => (8, 7, 6),
+        };
+        MotorChannels {
+            reverse,
+            forward,
+            speed,
+        }
+    }
+}
+
+impl MotorChannels {
+    /// Returns the `(channel, on, off)` counts needed to drive this motor.
+    pub fn pwm(&self, dir: Direction, speed: u16) -> [(u8, u16, u16); 3] {
+        let (reverse_on, forward_on) = match dir {
+            Direction::Forward => (0, FULL),
+            Direction::Reverse => (FULL, 0),
+        };
+        [
+            (self.reverse, reverse_on, 0),
+            (self.forward, forward_on, 0),
+            (self.speed, 0, speed),
+        ]
+    }
+}
+
+/// Splits a signed speed, negative for reverse, into a direction and a magnitude.
+pub fn split_speed(speed: i16) -> (Direction, u16) {
+    let dir = if speed < 0 {
+        Direction::Reverse
+    } else {
+        Direction::Forward
+    };
+    (dir, speed.unsigned_abs().min(MAX_SPEED))
+}
+
+/// Returns the `(channel, on, off)` counts for every motor at once, so they can be sent as
+/// one batch. `commands` is in `Motor::ALL` order.
+pub fn all_motors_pwm(commands: &[(Direction, u16); 4]) -> [(u8, u16, u16); 12] {
+    let mut updates = [(0, 0, 0); 12];
+    for ((motor, (dir, speed)), chunk) in Motor::ALL
+        .iter()
+        .zip(commands)
+        .zip(updates.chunks_exact_mut(3))
+    {
+        chunk.copy_from_slice(&motor.channels().pwm(*dir, *speed));
+    }
+    updates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pca9685::mock::{MockPca9685, NoDelay};
+    use crate::pca9685::Pca9685;
+    use futures::executor::block_on;
+
+    #[test]
+    fn channels_are_unique() {
+        let mut used = [false; 16];
+        for motor in Motor::ALL {
+            let c = motor.channels();
+            for channel in [c.reverse, c.forward, c.speed] {
+                assert!(!used[channel as usize], "channel {} reused", channel);
+                used[channel as usize] = true;
+            }
+        }
+        // Channels 12 and up are left for the leds.
+        assert!(used[..12].iter().all(|u| *u));
+        assert!(!used[12..].iter().any(|u| *u));
+    }
+
+    #[test]
+    fn front_left_mapping() {
+        assert_eq!(
+            Motor::FrontLeft.channels(),
+            MotorChannels {
+                reverse: 4,
+                forward: 3,
+                speed: 5
+            }
+        );
+    }
+
+    #[test]
+    fn forward_pwm() {
+        let pwm = Motor::FrontLeft.channels().pwm(Direction::Forward, 1000);
+        assert_eq!(pwm, [(4, 0, 0), (3, 4096, 0), (5, 0, 1000)]);
+    }
+
+    #[test]
+    fn reverse_pwm() {
+        let pwm = Motor::BackRight
+            .channels()
+            .pwm(Direction::Reverse, MAX_SPEED);
+        assert_eq!(pwm, [(8, 4096, 0), (7, 0, 0), (6, 0, 4095)]);
+    }
+
+    #[test]
+    fn split_signed_speed() {
+        assert_eq!(split_speed(0), (Direction::Forward, 0));
+        assert_eq!(split_speed(1200), (Direction::Forward, 1200));
+        assert_eq!(split_speed(-1200), (Direction::Reverse, 1200));
+        assert_eq!(split_speed(i16::MIN), (Direction::Reverse, MAX_SPEED));
+    }
+
+    #[test]
+    fn all_motors_batch_into_few_transactions() {
+        let mut pwm = Pca9685::new(MockPca9685::new(0x47), 0x47);
+        block_on(pwm.init(&mut NoDelay)).unwrap();
+
+        let mut commands = [(Direction::Forward, 2000); 4];
+        let before = pwm.bus().transactions;
+        block_on(pwm.set_pwms(&all_motors_pwm(&commands))).unwrap();
+        // Motors cover channels 0 to 11 so the first update is one write.
+        assert_eq!(pwm.bus().transactions, before + 1);
+
+        // Nothing changed.
+        block_on(pwm.set_pwms(&all_motors_pwm(&commands))).unwrap();
+        assert_eq!(pwm.bus().transactions, before + 1);
+
+        // Only speeds changed, channels 0, 5 and 6, and 11.
+        for command in commands.iter_mut() {
+            command.1 = 3000;
+        }
+        block_on(pwm.set_pwms(&all_motors_pwm(&commands))).unwrap();
+        assert_eq!(pwm.bus().transactions, before + 4);
+    }
+}
@@ -0,0 +1,263 @@
+//! Dead reckoning from the wheel speeds sent to the motors and the magnetometer heading.
+//! There are no wheel encoders, so distances come from a model of how fast the wheels
+//! turn for a given speed. That model has not been calibrated against a real robot, so
+//! distances are only a rough guess, while the heading follows the compass.
+
+use crate::motor::MAX_SPEED;
+use crate::roc;
+use core::f32::consts::PI;
+
+/// How fast a wheel moves the robot for a given motor speed.
+/// Motors stall below `deadband`, and are close enough to linear above it.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpeedModel {
+    /// Largest speed that does not turn the wheel.
+    pub deadband: u16,
+    /// Ground speed at `MAX_SPEED`.
+    pub mm_per_s_at_max: f32,
+}
+
+impl SpeedModel {
+    /// Estimated for the KeyeStudio base from its motors' rated speed, not measured.
+    /// Batteries, floor and load can easily change the real speed by a quarter or more.
+    pub const DEFAULT: SpeedModel = SpeedModel {
+        deadband: 900,
+        mm_per_s_at_max: 320.0,
+    };
+
+    /// Signed ground speed of a wheel at the signed motor `speed`.
+    pub fn mm_per_s(&self, speed: i16) -> f32 {
+        let magnitude = speed.unsigned_abs().min(MAX_SPEED);
+        let deadband = self.deadband.min(MAX_SPEED - 1);
+        if magnitude <= deadband {
+            return 0.0;
+        }
+        let fraction = (magnitude - deadband) as f32 / (MAX_SPEED - deadband) as f32;
+        let mm_per_s = fraction * self.mm_per_s_at_max;
+        if speed < 0 {
+            -mm_per_s
+        } else {
+            mm_per_s
+        }
+    }
+}
+
+impl Default for SpeedModel {
+    fn default() -> SpeedModel {
+        SpeedModel::DEFAULT
+    }
+}
+
+/// Position relative to where odometry was last reset.
+/// x is forwards and y is to the left at the reset, and the heading turns counterclockwise.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Pose {
+    pub x_mm: f32,
+    pub y_mm: f32,
+    /// In radians, from -PI to PI.
+    pub heading: f32,
+}
+
+impl Pose {
+    pub fn to_roc(&self) -> roc::Pose {
+        roc::Pose {
+            x_mm: libm::roundf(self.x_mm) as i32,
+            y_mm: libm::roundf(self.y_mm) as i32,
+            heading_centi_deg: libm::roundf(self.heading.to_degrees() * 100.0) as i16,
+        }
+    }
+}
+
+pub struct Odometry {
+    model: SpeedModel,
+    /// Half the wheelbase plus half the track, for turning from the wheels alone.
+    turn_radius_mm: f32,
+    pose: Pose,
+    /// Magnetometer heading at the last reset, None until one has been seen.
+    heading_origin: Option<f32>,
+}
+
+impl Odometry {
+    pub fn new(model: SpeedModel, turn_radius_mm: f32) -> Odometry {
+        Odometry {
+            model,
+            turn_radius_mm,
+            pose: Pose::default(),
+            heading_origin: None,
+        }
+    }
+
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
+    /// Makes the current position the origin, facing along x.
+    pub fn reset(&mut self) {
+        self.pose = Pose::default();
+        self.heading_origin = None;
+    }
+
+    /// Moves the pose on by `dt` seconds at the signed wheel `speeds` in `Motor::ALL` order.
+    /// `mag_heading` is the filtered magnetometer heading, which turns clockwise like a
+    /// compass. Without it the heading comes from the wheels, which drifts quickly.
+    pub fn step(&mut self, speeds: [i16; 4], mag_heading: Option<f32>, dt: f32) -> Pose {
+        let dt = dt.max(0.0);
+        let [front_left, back_left, front_right, back_right] =
+            speeds.map(|speed| self.model.mm_per_s(speed));
+        let forward = (front_left + back_left + front_right + back_right) / 4.0;
+        // Mecanum rollers push sideways when diagonal wheels turn the other way.
+        let left = (-front_left + back_left + front_right - back_right) / 4.0;
+
+        let start = self.pose.heading;
+        let end = match mag_heading {
+            Some(mag_heading) => {
+                let origin = *self.heading_origin.get_or_insert(mag_heading);
+                wrap(origin - mag_heading)
+            }
+            None => {
+                let turn = (-front_left - back_left + front_right + back_right) / 4.0;
+                wrap(start + turn / self.turn_radius_mm * dt)
+            }
+        };
+        // Drive along the average heading over the step.
+        let mid = start + wrap(end - start) / 2.0;
+        let (sin, cos) = (libm::sinf(mid), libm::cosf(mid));
+        self.pose.x_mm += (forward * cos - left * sin) * dt;
+        self.pose.y_mm += (forward * sin + left * cos) * dt;
+        self.pose.heading = end;
+        self.pose
+    }
+}
+
+impl Default for Odometry {
+    /// Suits the KeyeStudio base, which is about 160 mm square at the wheels.
+    fn default() -> Odometry {
+        Odometry::new(SpeedModel::default(), 160.0)
+    }
+}
+
+/// Wraps an angle in radians to -PI to PI.
+fn wrap(angle: f32) -> f32 {
+    let wrapped = libm::remainderf(angle, 2.0 * PI);
+    if wrapped <= -PI {
+        wrapped + 2.0 * PI
+    } else {
+        wrapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::FRAC_PI_2;
+
+    const FULL: i16 = MAX_SPEED as i16;
+    const STEPS_PER_S: usize = 100;
+
+    /// Drives at `speeds` for `seconds` with the compass reading `mag_heading`.
+    fn drive(
+        odometry: &mut Odometry,
+        speeds: [i16; 4],
+        mag_heading: Option<f32>,
+        seconds: f32,
+    ) -> Pose {
+        let steps = (seconds * STEPS_PER_S as f32) as usize;
+        for _ in 0..steps {
+            odometry.step(speeds, mag_heading, 1.0 / STEPS_PER_S as f32);
+        }
+        odometry.pose()
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.5,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn speed_model() {
+        let model = SpeedModel::default();
+        assert_eq!(model.mm_per_s(0), 0.0);
+        assert_eq!(model.mm_per_s(800), 0.0);
+        assert_eq!(model.mm_per_s(FULL), 320.0);
+        assert_eq!(model.mm_per_s(-FULL), -320.0);
+        assert_eq!(model.mm_per_s(i16::MAX), 320.0);
+        let half = (900 + MAX_SPEED) / 2;
+        assert_near(model.mm_per_s(half as i16), 160.0);
+    }
+
+    #[test]
+    fn drive_out_and_back() {
+        let mut odometry = Odometry::default();
+        let out = drive(&mut odometry, [FULL; 4], Some(1.0), 1.5);
+        assert_near(out.x_mm, 480.0);
+        assert_near(out.y_mm, 0.0);
+        assert_near(out.heading, 0.0);
+        let back = drive(&mut odometry, [-FULL; 4], Some(1.0), 1.5);
+        assert_near(back.x_mm, 0.0);
+        assert_near(back.y_mm, 0.0);
+    }
+
+    #[test]
+    fn strafe_left() {
+        let mut odometry = Odometry::default();
+        let pose = drive(&mut odometry, [-FULL, FULL, FULL, -FULL], None, 1.0);
+        assert_near(pose.x_mm, 0.0);
+        assert_near(pose.y_mm, 320.0);
+        assert_near(pose.heading, 0.0);
+    }
+
+    #[test]
+    fn heading_follows_compass() {
+        let mut odometry = Odometry::default();
+        // Compass headings turn clockwise, so a lower reading is a left turn.
+        odometry.step([0; 4], Some(0.5), 0.01);
+        odometry.step([0; 4], Some(0.5 - FRAC_PI_2), 0.01);
+        let pose = drive(&mut odometry, [FULL; 4], Some(0.5 - FRAC_PI_2), 1.0);
+        assert_near(pose.heading * 100.0, FRAC_PI_2 * 100.0);
+        assert_near(pose.x_mm, 0.0);
+        assert_near(pose.y_mm, 320.0);
+        assert_eq!(pose.to_roc().heading_centi_deg, 9000);
+        assert_eq!(pose.to_roc().y_mm, 320);
+    }
+
+    #[test]
+    fn heading_from_wheels_without_compass() {
+        let mut odometry = Odometry::default();
+        // Spinning left in place for a quarter turn at 320mm/s on a 160mm radius.
+        let seconds = FRAC_PI_2 * 160.0 / 320.0;
+        let pose = drive(
+            &mut odometry,
+            [-FULL, -FULL, FULL, FULL],
+            None,
+            seconds + 0.005,
+        );
+        assert!((pose.heading - FRAC_PI_2).abs() < 0.02);
+        assert_near(pose.x_mm, 0.0);
+        assert_near(pose.y_mm, 0.0);
+    }
+
+    #[test]
+    fn reset_takes_a_new_origin() {
+        let mut odometry = Odometry::default();
+        drive(&mut odometry, [FULL; 4], Some(2.0), 1.0);
+        odometry.reset();
+        assert_eq!(odometry.pose(), Pose::default());
+        let pose = drive(&mut odometry, [FULL; 4], Some(3.0), 1.0);
+        assert_near(pose.x_mm, 320.0);
+        assert_near(pose.heading, 0.0);
+    }
+
+    #[test]
+    fn heading_wraps() {
+        assert_near(wrap(3.0 * PI / 2.0) * 100.0, -FRAC_PI_2 * 100.0);
+        assert_near(wrap(-3.0 * PI / 2.0) * 100.0, FRAC_PI_2 * 100.0);
+        assert_eq!(wrap(PI), PI);
+        assert_eq!(wrap(-PI), PI);
+    }
+}
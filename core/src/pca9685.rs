@@ -0,0 +1,551 @@
+//! Async driver for the PCA9685 16 channel, 12 bit PWM chip.
+//! The robot base uses it to drive the motors and leds.
+
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::i2c::I2c;
+
+#[cfg(test)]
+pub(crate) mod mock;
+
+pub const CHANNEL_COUNT: u8 = 16;
+/// Largest on or off count. Counts run from 0 to 4095 every period.
+pub const MAX_COUNT: u16 = 4095;
+/// Setting bit 12 of an on or off count forces the channel fully on or off.
+pub const FULL: u16 = 4096;
+
+/// Frequency of the internal oscillator in Hz.
+const OSC_CLOCK: u32 = 25_000_000;
+/// The oscillator needs at most 500us to stabilize after leaving sleep.
+const OSC_STARTUP_US: u32 = 500;
+
+pub const MODE1: u8 = 0x00;
+pub const MODE2: u8 = 0x01;
+pub const SUBADR1: u8 = 0x02;
+pub const ALLCALLADR: u8 = 0x05;
+pub const LED0_ON_L: u8 = 0x06;
+pub const ALL_LED_ON_L: u8 = 0xFA;
+pub const PRE_SCALE: u8 = 0xFE;
+
+// MODE1 bits.
+pub const MODE1_RESTART: u8 = 0x80;
+pub const MODE1_EXTCLK: u8 = 0x40;
+pub const MODE1_AI: u8 = 0x20;
+pub const MODE1_SLEEP: u8 = 0x10;
+pub const MODE1_SUB1: u8 = 0x08;
+pub const MODE1_SUB2: u8 = 0x04;
+pub const MODE1_SUB3: u8 = 0x02;
+pub const MODE1_ALLCALL: u8 = 0x01;
+
+// MODE2 bits.
+pub const MODE2_INVRT: u8 = 0x10;
+pub const MODE2_OCH: u8 = 0x08;
+pub const MODE2_OUTDRV: u8 = 0x04;
+pub const MODE2_OUTNE: u8 = 0x03;
+
+type Counts = (u16, u16);
+
+pub struct Pca9685<I> {
+    i2c: I,
+    addr: u8,
+    // Last counts written to each channel, None if unknown.
+    // Used to skip writes that would not change anything.
+    written: [Option<Counts>; CHANNEL_COUNT as usize],
+}
+
+impl<I: I2c> Pca9685<I> {
+    /// `init` has to be called before updating any channels.
+    pub fn new(i2c: I, addr: u8) -> Pca9685<I> {
+        Pca9685 {
+            i2c,
+            addr,
+            written: [None; CHANNEL_COUNT as usize],
+        }
+    }
+
+    pub fn release(self) -> I {
+        self.i2c
+    }
+
+    #[cfg(test)]
+    pub(crate) fn bus(&self) -> &I {
+        &self.i2c
+    }
+
+    /// The bus, for recovering it after errors. Call `forget_outputs` if the chip may have
+    /// missed writes while the bus was stuck.
+    pub fn bus_mut(&mut self) -> &mut I {
+        &mut self.i2c
+    }
+
+    /// Puts the chip in a known state with every channel off and the oscillator running.
+    pub async fn init(&mut self, delay: &mut impl DelayNs) -> Result<(), I::Error> {
+        self.set_mode1(0).await?;
+        self.set_all_pwm(0, 0).await?;
+        self.set_mode2(MODE2_OUTDRV).await?;
+        self.set_mode1(MODE1_ALLCALL).await?;
+        delay.delay_ms(5).await;
+        self.wake(delay).await?;
+        delay.delay_ms(5).await;
+        Ok(())
+    }
+
+    async fn write_register(&mut self, reg: u8, value: u8) -> Result<(), I::Error> {
+        self.i2c.write(self.addr, &[reg, value]).await
+    }
+
+    async fn read_register(&mut self, reg: u8) -> Result<u8, I::Error> {
+        let mut data = [0];
+        self.i2c.write_read(self.addr, &[reg], &mut data).await?;
+        Ok(data[0])
+    }
+
+    pub async fn mode1(&mut self) -> Result<u8, I::Error> {
+        self.read_register(MODE1).await
+    }
+
+    /// Auto increment is always kept on since channel updates depend on it.
+    pub async fn set_mode1(&mut self, mode: u8) -> Result<(), I::Error> {
+        self.write_register(MODE1, mode | MODE1_AI).await
+    }
+
+    pub async fn mode2(&mut self) -> Result<u8, I::Error> {
+        self.read_register(MODE2).await
+    }
+
+    pub async fn set_mode2(&mut self, mode: u8) -> Result<(), I::Error> {
+        self.write_register(MODE2, mode).await
+    }
+
+    /// Stops the oscillator. Every channel is off while asleep.
+    pub async fn sleep(&mut self) -> Result<(), I::Error> {
+        let mode = self.mode1().await?;
+        // Writing a 1 to RESTART would restart the outputs, so never write it back.
+        self.set_mode1((mode & !MODE1_RESTART) | MODE1_SLEEP).await
+    }
+
+    /// Starts the oscillator without restoring the previous outputs.
+    pub async fn wake(&mut self, delay: &mut impl DelayNs) -> Result<(), I::Error> {
+        let mode = self.mode1().await?;
+        self.set_mode1(mode & !(MODE1_SLEEP | MODE1_RESTART))
+            .await?;
+        delay.delay_us(OSC_STARTUP_US).await;
+        Ok(())
+    }
+
+    /// Leaves sleep and restores the outputs that were active before going to sleep.
+    /// Returns false if there was nothing to restart.
+    pub async fn restart(&mut self, delay: &mut impl DelayNs) -> Result<bool, I::Error> {
+        let mode = self.mode1().await?;
+        if mode & MODE1_RESTART == 0 {
+            return Ok(false);
+        }
+        self.set_mode1(mode & !(MODE1_SLEEP | MODE1_RESTART))
+            .await?;
+        delay.delay_us(OSC_STARTUP_US).await;
+        self.set_mode1((mode & !MODE1_SLEEP) | MODE1_RESTART)
+            .await?;
+        Ok(true)
+    }
+
+    /// Sets the PWM frequency of every channel.
+    /// The chip has to sleep to change it, so outputs are restarted afterwards.
+    pub async fn set_frequency(
+        &mut self,
+        hz: u32,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), I::Error> {
+        let prescale = prescale_for(hz);
+        let mode = self.mode1().await? & !MODE1_RESTART;
+        // PRE_SCALE can only be written while asleep.
+        self.set_mode1(mode | MODE1_SLEEP).await?;
+        self.write_register(PRE_SCALE, prescale).await?;
+        self.set_mode1(mode & !MODE1_SLEEP).await?;
+        delay.delay_us(OSC_STARTUP_US).await;
+        self.set_mode1((mode & !MODE1_SLEEP) | MODE1_RESTART).await
+    }
+
+    /// Returns the current PWM frequency in Hz.
+    pub async fn frequency(&mut self) -> Result<u32, I::Error> {
+        let prescale = self.read_register(PRE_SCALE).await?;
+        Ok(frequency_for(prescale))
+    }
+
+    /// Responds to `address` as well as the normal address, so one write can reach every chip.
+    /// `None` disables the all call address.
+    pub async fn set_all_call(&mut self, address: Option<u8>) -> Result<(), I::Error> {
+        let mode = self.mode1().await? & !MODE1_RESTART;
+        match address {
+            Some(address) => {
+                self.write_register(ALLCALLADR, address << 1).await?;
+                self.set_mode1(mode | MODE1_ALLCALL).await
+            }
+            None => self.set_mode1(mode & !MODE1_ALLCALL).await,
+        }
+    }
+
+    /// Forgets the counts written to every channel, so the next update always writes.
+    /// Needed if the chip may have been reset behind our back.
+    pub fn forget_outputs(&mut self) {
+        self.written = [None; CHANNEL_COUNT as usize];
+    }
+
+    /// Writes the counts of consecutive channels starting at register `base` in one transaction.
+    async fn write_counts(&mut self, base: u8, counts: &[Counts]) -> Result<(), I::Error> {
+        let mut buf = [0; 1 + 4 * CHANNEL_COUNT as usize];
+        buf[0] = base;
+        for (chunk, (on, off)) in buf[1..].chunks_exact_mut(4).zip(counts) {
+            chunk.copy_from_slice(&[
+                (on & 0xFF) as u8,
+                (on >> 8) as u8,
+                (off & 0xFF) as u8,
+                (off >> 8) as u8,
+            ]);
+        }
+        self.i2c
+            .write(self.addr, &buf[..1 + 4 * counts.len()])
+            .await
+    }
+
+    /// Writes a run of consecutive channels and remembers what was written.
+    async fn write_channels(&mut self, first: u8, counts: &[Counts]) -> Result<(), I::Error> {
+        let range = first as usize..first as usize + counts.len();
+        match self.write_counts(LED0_ON_L + 4 * first, counts).await {
+            Ok(()) => {
+                for (written, c) in self.written[range].iter_mut().zip(counts) {
+                    *written = Some(*c);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // A partial write may have landed, so nothing in the run is known anymore.
+                self.written[range].fill(None);
+                Err(e)
+            }
+        }
+    }
+
+    /// Sets the counts where a channel turns on and off within a period.
+    /// A count of `FULL` forces the channel fully on or off, with full off taking priority.
+    pub async fn set_pwm(&mut self, channel: u8, on: u16, off: u16) -> Result<(), I::Error> {
+        assert!(channel < CHANNEL_COUNT, "Invalid channel: {}", channel);
+        if self.written[channel as usize] == Some((on, off)) {
+            return Ok(());
+        }
+        self.write_channels(channel, &[(on, off)]).await
+    }
+
+    /// Applies many `(channel, on, off)` updates at once.
+    /// Unchanged channels are skipped and each run of consecutive channels is a single write.
+    /// Runs separated by a couple of unchanged channels are merged.
+    pub async fn set_pwms(&mut self, updates: &[(u8, u16, u16)]) -> Result<(), I::Error> {
+        let mut pending: [Option<Counts>; CHANNEL_COUNT as usize] = [None; CHANNEL_COUNT as usize];
+        for &(channel, on, off) in updates {
+            assert!(channel < CHANNEL_COUNT, "Invalid channel: {}", channel);
+            pending[channel as usize] = Some((on, off));
+        }
+        for (pending, written) in pending.iter_mut().zip(self.written.iter()) {
+            if pending == written {
+                *pending = None;
+            }
+        }
+
+        let mut run = [(0, 0); CHANNEL_COUNT as usize];
+        let mut channel = 0;
+        while channel < CHANNEL_COUNT as usize {
+            if pending[channel].is_none() {
+                channel += 1;
+                continue;
+            }
+            let first = channel;
+            let mut len = 0;
+            while channel < CHANNEL_COUNT as usize {
+                if let Some(counts) = pending[channel] {
+                    run[len] = counts;
+                    len += 1;
+                    channel += 1;
+                } else if let Some(gap) = self.bridgeable_gap(&pending, channel) {
+                    // Rewriting a few known channels is cheaper than starting a new transaction.
+                    for i in channel..channel + gap {
+                        run[len] = self.written[i].unwrap();
+                        len += 1;
+                    }
+                    channel += gap;
+                } else {
+                    break;
+                }
+            }
+            self.write_channels(first as u8, &run[..len]).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the length of the gap of unchanged channels starting at `start`,
+    /// if it is short, fully known and followed by another pending channel.
+    fn bridgeable_gap(&self, pending: &[Option<Counts>], start: usize) -> Option<usize> {
+        const MAX_GAP: usize = 2;
+        let mut gap = 0;
+        while gap < MAX_GAP && start + gap < pending.len() {
+            if pending[start + gap].is_some() {
+                return Some(gap);
+            }
+            self.written[start + gap]?;
+            gap += 1;
+        }
+        match pending.get(start + gap) {
+            Some(Some(_)) if gap > 0 => Some(gap),
+            _ => None,
+        }
+    }
+
+    pub async fn set_all_pwm(&mut self, on: u16, off: u16) -> Result<(), I::Error> {
+        match self.write_counts(ALL_LED_ON_L, &[(on, off)]).await {
+            Ok(()) => {
+                self.written = [Some((on, off)); CHANNEL_COUNT as usize];
+                Ok(())
+            }
+            Err(e) => {
+                self.forget_outputs();
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn set_full_on(&mut self, channel: u8) -> Result<(), I::Error> {
+        self.set_pwm(channel, FULL, 0).await
+    }
+
+    pub async fn set_full_off(&mut self, channel: u8) -> Result<(), I::Error> {
+        self.set_pwm(channel, 0, FULL).await
+    }
+
+    /// Sets a channel to be on for `duty` out of every 4095 counts.
+    pub async fn set_duty(&mut self, channel: u8, duty: u16) -> Result<(), I::Error> {
+        match duty {
+            0 => self.set_full_off(channel).await,
+            d if d >= MAX_COUNT => self.set_full_on(channel).await,
+            d => self.set_pwm(channel, 0, d).await,
+        }
+    }
+}
+
+/// Whether a MODE1 value read back means the chip has been power cycled since `init`.
+/// Auto increment is always kept on, and comes back off at power on.
+pub fn was_reset(mode1: u8) -> bool {
+    mode1 & MODE1_AI == 0
+}
+
+/// Prescale value that gets closest to `hz`, clamped to what the chip supports.
+pub fn prescale_for(hz: u32) -> u8 {
+    let period = 4096 * hz.max(1) as u64;
+    let prescale = (OSC_CLOCK as u64 + period / 2) / period;
+    prescale.saturating_sub(1).clamp(3, 255) as u8
+}
+
+pub fn frequency_for(prescale: u8) -> u32 {
+    OSC_CLOCK / (4096 * (prescale as u32 + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{MockPca9685, NoDelay};
+    use super::*;
+    use futures::executor::block_on;
+
+    const ADDR: u8 = 0x47;
+
+    fn driver() -> Pca9685<MockPca9685> {
+        Pca9685::new(MockPca9685::new(ADDR), ADDR)
+    }
+
+    fn init_driver() -> Pca9685<MockPca9685> {
+        let mut pwm = driver();
+        block_on(pwm.init(&mut NoDelay)).unwrap();
+        pwm
+    }
+
+    #[test]
+    fn prescale_matches_datasheet() {
+        // The datasheet example is 200Hz -> 30 (0x1E), and 1526Hz is the max at 3.
+        assert_eq!(prescale_for(200), 30);
+        assert_eq!(prescale_for(1526), 3);
+        assert_eq!(prescale_for(24), 253);
+        assert_eq!(prescale_for(20), 255);
+        assert_eq!(prescale_for(50), 121);
+        assert_eq!(prescale_for(100_000), 3);
+        assert_eq!(prescale_for(0), 255);
+        assert_eq!(frequency_for(121), 50);
+    }
+
+    #[test]
+    fn init_wakes_with_everything_off() {
+        let pwm = init_driver();
+        let chip = pwm.release();
+        assert_eq!(chip.reg(MODE1) & MODE1_SLEEP, 0);
+        assert_eq!(chip.reg(MODE1) & MODE1_ALLCALL, MODE1_ALLCALL);
+        assert_eq!(chip.reg(MODE1) & MODE1_AI, MODE1_AI);
+        assert_eq!(chip.reg(MODE2), MODE2_OUTDRV);
+        for channel in 0..CHANNEL_COUNT {
+            assert_eq!(chip.channel(channel), (0, 0));
+        }
+    }
+
+    #[test]
+    fn set_pwm_writes_channel_registers() {
+        let mut pwm = init_driver();
+        block_on(pwm.set_pwm(5, 0x123, 0xABC)).unwrap();
+        let chip = pwm.release();
+        assert_eq!(chip.channel(5), (0x123, 0xABC));
+        assert_eq!(chip.reg(LED0_ON_L + 20), 0x23);
+        assert_eq!(chip.reg(LED0_ON_L + 23), 0x0A);
+        assert_eq!(chip.channel(4), (0, 0));
+        assert_eq!(chip.channel(6), (0, 0));
+    }
+
+    #[test]
+    fn full_on_and_off_bits() {
+        let mut pwm = init_driver();
+        block_on(pwm.set_full_on(2)).unwrap();
+        block_on(pwm.set_full_off(3)).unwrap();
+        block_on(pwm.set_duty(4, 0)).unwrap();
+        block_on(pwm.set_duty(5, MAX_COUNT)).unwrap();
+        block_on(pwm.set_duty(6, 2000)).unwrap();
+        let chip = pwm.release();
+        assert_eq!(chip.channel(2), (FULL, 0));
+        assert_eq!(chip.channel(3), (0, FULL));
+        assert_eq!(chip.channel(4), (0, FULL));
+        assert_eq!(chip.channel(5), (FULL, 0));
+        assert_eq!(chip.channel(6), (0, 2000));
+    }
+
+    #[test]
+    fn set_all_pwm_reaches_every_channel() {
+        let mut pwm = init_driver();
+        block_on(pwm.set_all_pwm(0, FULL)).unwrap();
+        let chip = pwm.release();
+        for channel in 0..CHANNEL_COUNT {
+            assert_eq!(chip.channel(channel), (0, FULL));
+        }
+    }
+
+    #[test]
+    fn set_frequency_sleeps_to_write_prescale() {
+        let mut pwm = init_driver();
+        block_on(pwm.set_frequency(50, &mut NoDelay)).unwrap();
+        assert_eq!(block_on(pwm.frequency()).unwrap(), 50);
+        let chip = pwm.release();
+        assert_eq!(chip.reg(PRE_SCALE), 121);
+        assert_eq!(chip.reg(MODE1) & MODE1_SLEEP, 0);
+        // The mock clears RESTART once it has been acted on, like the chip.
+        assert_eq!(chip.reg(MODE1) & MODE1_RESTART, 0);
+    }
+
+    #[test]
+    fn sleep_and_restart() {
+        let mut pwm = init_driver();
+        block_on(pwm.set_pwm(1, 0, 1000)).unwrap();
+        block_on(pwm.sleep()).unwrap();
+        assert_eq!(block_on(pwm.mode1()).unwrap() & MODE1_SLEEP, MODE1_SLEEP);
+        assert!(block_on(pwm.restart(&mut NoDelay)).unwrap());
+        assert_eq!(block_on(pwm.mode1()).unwrap() & MODE1_SLEEP, 0);
+        assert!(!block_on(pwm.restart(&mut NoDelay)).unwrap());
+        assert_eq!(pwm.release().channel(1), (0, 1000));
+    }
+
+    #[test]
+    fn all_call() {
+        let mut pwm = driver();
+        block_on(pwm.set_all_call(Some(0x70))).unwrap();
+        assert_eq!(
+            block_on(pwm.mode1()).unwrap() & MODE1_ALLCALL,
+            MODE1_ALLCALL
+        );
+        block_on(pwm.set_all_call(None)).unwrap();
+        let chip = pwm.release();
+        assert_eq!(chip.reg(ALLCALLADR), 0xE0);
+        assert_eq!(chip.reg(MODE1) & MODE1_ALLCALL, 0);
+    }
+
+    #[test]
+    fn channel_update_is_one_transaction() {
+        let mut pwm = init_driver();
+        let before = pwm.i2c.transactions;
+        block_on(pwm.set_pwm(7, 0x102, 0x304)).unwrap();
+        assert_eq!(pwm.i2c.transactions, before + 1);
+        assert_eq!(pwm.release().channel(7), (0x102, 0x304));
+    }
+
+    #[test]
+    fn unchanged_updates_are_skipped() {
+        let mut pwm = init_driver();
+        block_on(pwm.set_pwm(7, 0, 1000)).unwrap();
+        let before = pwm.i2c.transactions;
+        block_on(pwm.set_pwm(7, 0, 1000)).unwrap();
+        // Still zero from init.
+        block_on(pwm.set_pwms(&[(3, 0, 0), (7, 0, 1000)])).unwrap();
+        assert_eq!(pwm.i2c.transactions, before);
+
+        pwm.forget_outputs();
+        block_on(pwm.set_pwm(7, 0, 1000)).unwrap();
+        assert_eq!(pwm.i2c.transactions, before + 1);
+    }
+
+    #[test]
+    fn set_pwms_writes_runs() {
+        let mut pwm = init_driver();
+        let before = pwm.i2c.transactions;
+        // Out of order, with channels 6 to 8 unchanged, so this is two runs: 0..=5 and 9..=10.
+        // Channel 2 is unchanged too, but short gaps are bridged.
+        let updates = [
+            (5, 0, 500),
+            (0, FULL, 0),
+            (4, 0, 400),
+            (1, 0, 100),
+            (2, 0, 0),
+            (3, 0, 300),
+            (10, 0, 1000),
+            (9, 0, 900),
+        ];
+        block_on(pwm.set_pwms(&updates)).unwrap();
+        assert_eq!(pwm.i2c.transactions, before + 2);
+        let chip = pwm.release();
+        for (channel, on, off) in updates {
+            assert_eq!(chip.channel(channel), (on, off));
+        }
+        assert_eq!(chip.channel(6), (0, 0));
+    }
+
+    #[test]
+    fn unknown_gaps_are_not_bridged() {
+        let mut pwm = init_driver();
+        pwm.forget_outputs();
+        let before = pwm.i2c.transactions;
+        block_on(pwm.set_pwms(&[(0, 0, 10), (2, 0, 20)])).unwrap();
+        assert_eq!(pwm.i2c.transactions, before + 2);
+        assert_eq!(pwm.release().channel(1), (0, 0));
+    }
+
+    #[test]
+    fn failed_write_forgets_channels() {
+        let mut pwm = init_driver();
+        pwm.addr = 0x40;
+        assert!(block_on(pwm.set_pwms(&[(0, 0, 10), (1, 0, 20)])).is_err());
+        pwm.addr = ADDR;
+        let before = pwm.i2c.transactions;
+        block_on(pwm.set_pwms(&[(0, 0, 0), (1, 0, 0)])).unwrap();
+        assert_eq!(pwm.i2c.transactions, before + 1);
+    }
+
+    #[test]
+    fn power_cycle_is_noticed() {
+        let mut pwm = init_driver();
+        assert!(!was_reset(block_on(pwm.mode1()).unwrap()));
+        pwm.i2c = MockPca9685::new(ADDR);
+        assert!(was_reset(block_on(pwm.mode1()).unwrap()));
+    }
+
+    #[test]
+    fn wrong_address_is_not_acknowledged() {
+        let mut pwm = Pca9685::new(MockPca9685::new(ADDR), 0x40);
+        assert!(block_on(pwm.set_pwm(0, 0, 0)).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! Register level model of a PCA9685 for host tests.
+
+use super::{ALL_LED_ON_L, LED0_ON_L, MODE1, MODE1_AI, MODE1_RESTART, MODE1_SLEEP, PRE_SCALE};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::i2c::{
+    ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation, SevenBitAddress,
+};
+
+const LED15_OFF_H: u8 = LED0_ON_L + 4 * 16 - 1;
+
+#[derive(Debug)]
+pub struct MockError;
+
+impl embedded_hal_async::i2c::Error for MockError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)
+    }
+}
+
+pub struct MockPca9685 {
+    addr: u8,
+    regs: [u8; 256],
+    pointer: u8,
+    /// Number of I2C transactions addressed to the chip.
+    pub transactions: usize,
+}
+
+impl MockPca9685 {
+    pub fn new(addr: u8) -> MockPca9685 {
+        let mut regs = [0; 256];
+        // Power on state: asleep, responding to all call, 200Hz.
+        regs[MODE1 as usize] = 0x11;
+        regs[super::MODE2 as usize] = 0x04;
+        regs[super::ALLCALLADR as usize] = 0xE0;
+        regs[PRE_SCALE as usize] = 0x1E;
+        // Every channel starts full off.
+        for channel in 0..16 {
+            regs[(LED0_ON_L + 4 * channel + 3) as usize] = 0x10;
+        }
+        MockPca9685 {
+            addr,
+            regs,
+            pointer: 0,
+            transactions: 0,
+        }
+    }
+
+    pub fn reg(&self, reg: u8) -> u8 {
+        self.regs[reg as usize]
+    }
+
+    /// Returns the on and off counts of a channel, including the full on/off bits.
+    pub fn channel(&self, channel: u8) -> (u16, u16) {
+        let base = (LED0_ON_L + 4 * channel) as usize;
+        let on = u16::from(self.regs[base]) | (u16::from(self.regs[base + 1]) << 8);
+        let off = u16::from(self.regs[base + 2]) | (u16::from(self.regs[base + 3]) << 8);
+        (on, off)
+    }
+
+    fn advance(&mut self) {
+        if self.regs[MODE1 as usize] & MODE1_AI == 0 {
+            return;
+        }
+        self.pointer = match self.pointer {
+            LED15_OFF_H => MODE1,
+            p => p.wrapping_add(1),
+        };
+    }
+
+    fn write_byte(&mut self, value: u8) {
+        match self.pointer {
+            MODE1 => {
+                let old = self.regs[MODE1 as usize];
+                // RESTART is cleared by writing a 1 and ignores writes of 0.
+                let restart = if value & MODE1_RESTART != 0 {
+                    0
+                } else if old & MODE1_SLEEP == 0 && value & MODE1_SLEEP != 0 {
+                    // Going to sleep remembers that outputs need restarting.
+                    MODE1_RESTART
+                } else {
+                    old & MODE1_RESTART
+                };
+                self.regs[MODE1 as usize] = (value & !MODE1_RESTART) | restart;
+            }
+            PRE_SCALE => {
+                // Prescale writes are blocked unless the chip is asleep.
+                if self.regs[MODE1 as usize] & MODE1_SLEEP != 0 {
+                    self.regs[PRE_SCALE as usize] = value;
+                }
+            }
+            reg @ ALL_LED_ON_L..=0xFD => {
+                for channel in 0..16 {
+                    let offset = reg - ALL_LED_ON_L;
+                    self.regs[(LED0_ON_L + 4 * channel + offset) as usize] = value;
+                }
+            }
+            reg => self.regs[reg as usize] = value,
+        }
+        self.advance();
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let value = match self.pointer {
+            // The all led registers always read back as zero.
+            ALL_LED_ON_L..=0xFD => 0,
+            reg => self.regs[reg as usize],
+        };
+        self.advance();
+        value
+    }
+}
+
+impl ErrorType for MockPca9685 {
+    type Error = MockError;
+}
+
+impl I2c<SevenBitAddress> for MockPca9685 {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if address != self.addr {
+            return Err(MockError);
+        }
+        self.transactions += 1;
+        for op in operations {
+            match op {
+                Operation::Write(bytes) => {
+                    if let Some((reg, values)) = bytes.split_first() {
+                        self.pointer = *reg;
+                        for value in values {
+                            self.write_byte(*value);
+                        }
+                    }
+                }
+                Operation::Read(buf) => {
+                    for value in buf.iter_mut() {
+                        *value = self.read_byte();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Delay that returns immediately.
+pub struct NoDelay;
+
+impl DelayNs for NoDelay {
+    async fn delay_ns(&mut self, _ns: u32) {}
+}
@@ -0,0 +1,138 @@
+//! Slew rate limiting between the wheel speeds an app asks for and what the motors get.
+//! Without it, flipping from full forward to full reverse makes the wheels slip.
+
+use crate::motor::MAX_SPEED;
+
+/// Zero to full speed in about half a second.
+pub const DEFAULT_MAX_CHANGE_PER_S: u16 = 8000;
+
+/// Ramps the signed speed of each wheel, in `Motor::ALL` order, towards a target.
+/// Speeds run from `-MAX_SPEED` (full reverse) to `MAX_SPEED` (full forward),
+/// so a reversal naturally passes through zero.
+pub struct SpeedRamp {
+    max_change_per_s: f32,
+    current: [f32; 4],
+    target: [i16; 4],
+}
+
+impl SpeedRamp {
+    pub fn new(max_change_per_s: u16) -> SpeedRamp {
+        SpeedRamp {
+            max_change_per_s: max_change_per_s as f32,
+            current: [0.0; 4],
+            target: [0; 4],
+        }
+    }
+
+    pub fn set_max_change_per_s(&mut self, max_change_per_s: u16) {
+        self.max_change_per_s = max_change_per_s as f32;
+    }
+
+    pub fn set_target(&mut self, target: [i16; 4]) {
+        let max = MAX_SPEED as i16;
+        self.target = target.map(|speed| speed.clamp(-max, max));
+    }
+
+    pub fn target(&self) -> [i16; 4] {
+        self.target
+    }
+
+    pub fn current(&self) -> [i16; 4] {
+        self.current.map(|speed| libm::roundf(speed) as i16)
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.current() == self.target
+    }
+
+    /// Stops every wheel immediately, skipping the ramp.
+    pub fn stop_now(&mut self) {
+        self.current = [0.0; 4];
+        self.target = [0; 4];
+    }
+
+    /// Moves each wheel towards its target by at most the allowed change for `dt` seconds.
+    pub fn step(&mut self, dt: f32) -> [i16; 4] {
+        let max_change = self.max_change_per_s * dt.max(0.0);
+        for (current, target) in self.current.iter_mut().zip(self.target) {
+            let diff = target as f32 - *current;
+            *current += diff.clamp(-max_change, max_change);
+        }
+        self.current()
+    }
+}
+
+impl Default for SpeedRamp {
+    fn default() -> SpeedRamp {
+        SpeedRamp::new(DEFAULT_MAX_CHANGE_PER_S)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_change_per_step() {
+        let mut ramp = SpeedRamp::new(1000);
+        ramp.set_target([4000, -4000, 50, 0]);
+        assert_eq!(ramp.step(0.1), [100, -100, 50, 0]);
+        assert_eq!(ramp.step(0.1), [200, -200, 50, 0]);
+        assert!(!ramp.is_settled());
+    }
+
+    #[test]
+    fn small_steps_accumulate() {
+        let mut ramp = SpeedRamp::new(1000);
+        ramp.set_target([1000; 4]);
+        for _ in 0..1000 {
+            ramp.step(0.0001);
+        }
+        assert_eq!(ramp.current(), [100; 4]);
+    }
+
+    #[test]
+    fn reverses_through_zero() {
+        let mut ramp = SpeedRamp::new(4000);
+        ramp.set_target([MAX_SPEED as i16; 4]);
+        ramp.step(10.0);
+        assert!(ramp.is_settled());
+
+        ramp.set_target([-(MAX_SPEED as i16); 4]);
+        let mut last = MAX_SPEED as i16;
+        let mut crossed_zero = false;
+        while !ramp.is_settled() {
+            let speed = ramp.step(0.01)[0];
+            assert!(speed < last);
+            assert!(last - speed <= 40);
+            crossed_zero |= speed.abs() <= 20;
+            last = speed;
+        }
+        assert!(crossed_zero);
+        assert_eq!(last, -(MAX_SPEED as i16));
+    }
+
+    #[test]
+    fn targets_are_clamped() {
+        let mut ramp = SpeedRamp::new(1000);
+        ramp.set_target([i16::MAX, i16::MIN, 0, 0]);
+        assert_eq!(ramp.target(), [4095, -4095, 0, 0]);
+    }
+
+    #[test]
+    fn stop_now_skips_ramp() {
+        let mut ramp = SpeedRamp::new(1000);
+        ramp.set_target([1000; 4]);
+        ramp.step(0.5);
+        ramp.stop_now();
+        assert_eq!(ramp.current(), [0; 4]);
+        assert!(ramp.is_settled());
+    }
+
+    #[test]
+    fn negative_dt_does_nothing() {
+        let mut ramp = SpeedRamp::new(1000);
+        ramp.set_target([1000; 4]);
+        assert_eq!(ramp.step(-1.0), [0; 4]);
+    }
+}
@@ -0,0 +1,358 @@
+//! Structs shared with the Roc app across the `mainForHost` ABI.
+//! Their layout has to match `platform/IO.roc`.
+//! Roc numbers tags alphabetically, so enum values have to stay in that order.
+
+use crate::accel::AccelStats;
+use crate::display::DisplayData;
+use crate::ir::IrCode;
+use crate::scan::{Scan, SCAN_POINTS};
+use crate::sonar::SonarReading;
+
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightLevel {
+    #[default]
+    Bright = 0,
+    Dark = 1,
+}
+
+/// Whether the collision guard changed the last wheel command.
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardStatus {
+    Blocked = 0,
+    #[default]
+    Clear = 1,
+    Slowed = 2,
+}
+
+/// What the IR remote did since the last call.
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrEvent {
+    #[default]
+    Idle = 0,
+    /// A key was newly pressed.
+    Pressed = 1,
+    /// The last key is still held down.
+    Repeat = 2,
+}
+
+/// The latest thing done to the robot since the last call, seen by the accelerometer.
+/// Tilts and faces are from the robot's point of view, see `gesture`.
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// Turned upside down.
+    FaceDown = 0,
+    /// Back on its wheels.
+    FaceUp = 1,
+    /// Dropped.
+    FreeFall = 2,
+    /// Picked up off the ground.
+    Lifted = 3,
+    #[default]
+    None = 4,
+    Shake = 5,
+    /// Nose up, like on its back wheels.
+    TiltBackward = 6,
+    /// Nose down.
+    TiltForward = 7,
+    /// Left side down.
+    TiltLeft = 8,
+    /// Right side down.
+    TiltRight = 9,
+}
+
+/// What the headlights do until the next call. The host keeps the patterns going.
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadlightMode {
+    Blink = 0,
+    Breathe = 1,
+    #[default]
+    Off = 2,
+    On = 3,
+    /// On, blinking on the side the robot turns towards.
+    TurnSignals = 4,
+}
+
+/// Whether a device on the I2C bus is working.
+#[repr(u8)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceStatus {
+    #[default]
+    Available = 0,
+    /// It stopped answering and is left out until it comes back.
+    Unavailable = 1,
+}
+
+/// One point of a sonar scan.
+#[repr(C)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapPoint {
+    /// 0 where nothing was in range.
+    pub distance_mm: u32,
+    /// Servo angle it was pinged at, 90 being straight ahead.
+    pub angle_deg: u8,
+}
+
+/// The points of the last sonar scan, from the start to the end angle of its `ScanConfig`.
+#[repr(C)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct ScanMap {
+    pub points: [MapPoint; SCAN_POINTS],
+}
+
+/// Where the robot is relative to where odometry was last reset.
+/// x is forwards and y is to the left at the reset, and the heading turns counterclockwise.
+#[repr(C)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Pose {
+    pub x_mm: i32,
+    pub y_mm: i32,
+    /// From -18000 to 18000.
+    pub heading_centi_deg: i16,
+}
+
+/// Roc orders record fields by alignment and then by name, so fields here follow that order.
+#[repr(C)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug, Clone)]
+pub struct RocInput {
+    pub state: u64,
+    /// Time since the sonar last saw something agreeing with `distance_mm`.
+    pub distance_age_ms: u32,
+    /// Filtered sonar distance, 0 if nothing is in range.
+    pub distance_mm: u32,
+    /// Dead reckoned from the wheel speeds and compass, see `odometry`.
+    pub pose: Pose,
+    pub scan: ScanMap,
+    /// Largest acceleration since the last call, see `AccelStats`.
+    pub accel_peak_mg: u16,
+    /// How much the acceleration shook about its average since the last call.
+    pub accel_rms_mg: u16,
+    /// Address of the last IR key received.
+    pub ir_address: u16,
+    pub distance_valid: bool,
+    pub gesture: Gesture,
+    pub guard: GuardStatus,
+    /// The accelerometer and magnetometer.
+    pub imu: DeviceStatus,
+    /// Command of the last IR key received.
+    pub ir_command: u8,
+    pub ir_event: IrEvent,
+    pub light_left: LightLevel,
+    pub light_right: LightLevel,
+    /// The PCA9685 driving the motors, headlights and spare servos.
+    pub motors: DeviceStatus,
+    /// Debounced line sensor changes since the last call, saturating at 255.
+    pub transitions_left: u8,
+    pub transitions_right: u8,
+}
+
+impl RocInput {
+    pub fn set_sonar(&mut self, reading: &SonarReading) {
+        self.distance_mm = reading.distance_mm.unwrap_or(0);
+        self.distance_valid = reading.valid;
+        self.distance_age_ms = match reading.age_us {
+            Some(age) => (age / 1000).min(u32::MAX as u64) as u32,
+            None => u32::MAX,
+        };
+    }
+
+    pub fn set_scan(&mut self, scan: &Scan) {
+        self.scan = scan.to_map();
+    }
+
+    pub fn set_accel_stats(&mut self, stats: &AccelStats) {
+        let clamp = |mg: f32| libm::roundf(mg).clamp(0.0, u16::MAX as f32) as u16;
+        self.accel_peak_mg = clamp(stats.peak_mg() as f32);
+        self.accel_rms_mg = clamp(stats.rms_mg());
+    }
+
+    /// `last` is kept until another key arrives, `event` says whether it is new.
+    pub fn set_ir(&mut self, event: IrEvent, last: Option<IrCode>) {
+        self.ir_event = event;
+        if let Some(code) = last {
+            self.ir_address = code.address;
+            self.ir_command = code.command;
+        }
+    }
+}
+
+#[repr(C)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Default, Debug)]
+pub struct RocOutput {
+    pub delay_ms: u64,
+    pub state: u64,
+    pub display: DisplayData,
+    pub headlights: HeadlightMode,
+    /// Makes the current position the odometry origin before the next call.
+    pub reset_pose: bool,
+    /// Asks the host to sweep the sonar before the next call.
+    pub scan: bool,
+    pub speed_left: i8,
+    pub speed_right: i8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accel::AccelData;
+    use core::mem::{offset_of, size_of};
+
+    #[test]
+    fn light_level_values() {
+        assert_eq!(LightLevel::Bright as u8, 0);
+        assert_eq!(LightLevel::Dark as u8, 1);
+        assert_eq!(size_of::<LightLevel>(), 1);
+    }
+
+    #[test]
+    fn guard_status_values() {
+        assert_eq!(GuardStatus::Blocked as u8, 0);
+        assert_eq!(GuardStatus::Clear as u8, 1);
+        assert_eq!(GuardStatus::Slowed as u8, 2);
+        assert_eq!(GuardStatus::default(), GuardStatus::Clear);
+    }
+
+    #[test]
+    fn ir_event_values() {
+        assert_eq!(IrEvent::Idle as u8, 0);
+        assert_eq!(IrEvent::Pressed as u8, 1);
+        assert_eq!(IrEvent::Repeat as u8, 2);
+    }
+
+    #[test]
+    fn gesture_values() {
+        assert_eq!(Gesture::FaceDown as u8, 0);
+        assert_eq!(Gesture::FaceUp as u8, 1);
+        assert_eq!(Gesture::FreeFall as u8, 2);
+        assert_eq!(Gesture::Lifted as u8, 3);
+        assert_eq!(Gesture::None as u8, 4);
+        assert_eq!(Gesture::Shake as u8, 5);
+        assert_eq!(Gesture::TiltBackward as u8, 6);
+        assert_eq!(Gesture::TiltForward as u8, 7);
+        assert_eq!(Gesture::TiltLeft as u8, 8);
+        assert_eq!(Gesture::TiltRight as u8, 9);
+        assert_eq!(Gesture::default(), Gesture::None);
+    }
+
+    #[test]
+    fn headlight_mode_values() {
+        assert_eq!(HeadlightMode::Blink as u8, 0);
+        assert_eq!(HeadlightMode::Breathe as u8, 1);
+        assert_eq!(HeadlightMode::Off as u8, 2);
+        assert_eq!(HeadlightMode::On as u8, 3);
+        assert_eq!(HeadlightMode::TurnSignals as u8, 4);
+        assert_eq!(HeadlightMode::default(), HeadlightMode::Off);
+    }
+
+    #[test]
+    fn device_status_values() {
+        assert_eq!(DeviceStatus::Available as u8, 0);
+        assert_eq!(DeviceStatus::Unavailable as u8, 1);
+        assert_eq!(DeviceStatus::default(), DeviceStatus::Available);
+    }
+
+    #[test]
+    fn input_layout() {
+        assert_eq!(offset_of!(RocInput, state), 0);
+        assert_eq!(offset_of!(RocInput, distance_age_ms), 8);
+        assert_eq!(offset_of!(RocInput, distance_mm), 12);
+        assert_eq!(offset_of!(RocInput, pose), 16);
+        assert_eq!(offset_of!(RocInput, scan), 28);
+        assert_eq!(offset_of!(RocInput, accel_peak_mg), 100);
+        assert_eq!(offset_of!(RocInput, accel_rms_mg), 102);
+        assert_eq!(offset_of!(RocInput, ir_address), 104);
+        assert_eq!(offset_of!(RocInput, distance_valid), 106);
+        assert_eq!(offset_of!(RocInput, gesture), 107);
+        assert_eq!(offset_of!(RocInput, guard), 108);
+        assert_eq!(offset_of!(RocInput, imu), 109);
+        assert_eq!(offset_of!(RocInput, ir_command), 110);
+        assert_eq!(offset_of!(RocInput, ir_event), 111);
+        assert_eq!(offset_of!(RocInput, light_left), 112);
+        assert_eq!(offset_of!(RocInput, light_right), 113);
+        assert_eq!(offset_of!(RocInput, motors), 114);
+        assert_eq!(offset_of!(RocInput, transitions_left), 115);
+        assert_eq!(offset_of!(RocInput, transitions_right), 116);
+
+        assert_eq!(offset_of!(MapPoint, distance_mm), 0);
+        assert_eq!(offset_of!(MapPoint, angle_deg), 4);
+        assert_eq!(size_of::<MapPoint>(), 8);
+        assert_eq!(size_of::<ScanMap>(), 72);
+
+        assert_eq!(offset_of!(Pose, x_mm), 0);
+        assert_eq!(offset_of!(Pose, y_mm), 4);
+        assert_eq!(offset_of!(Pose, heading_centi_deg), 8);
+        assert_eq!(size_of::<Pose>(), 12);
+    }
+
+    #[test]
+    fn accel_stats_to_input() {
+        let mut stats = AccelStats::default();
+        stats.add(&AccelData {
+            x: 0,
+            y: 2500,
+            z: 0,
+        });
+        stats.add(&AccelData {
+            x: 0,
+            y: 1500,
+            z: 0,
+        });
+        let mut input = RocInput::default();
+        input.set_accel_stats(&stats);
+        assert_eq!(input.accel_peak_mg, 2500);
+        assert_eq!(input.accel_rms_mg, 500);
+        input.set_accel_stats(&AccelStats::default());
+        assert_eq!(input.accel_peak_mg, 0);
+        assert_eq!(input.accel_rms_mg, 0);
+    }
+
+    #[test]
+    fn sonar_reading_to_input() {
+        let mut input = RocInput::default();
+        input.set_sonar(&SonarReading {
+            distance_mm: Some(420),
+            valid: true,
+            age_us: Some(61_500),
+        });
+        assert_eq!(input.distance_mm, 420);
+        assert!(input.distance_valid);
+        assert_eq!(input.distance_age_ms, 61);
+
+        input.set_sonar(&SonarReading {
+            distance_mm: None,
+            valid: false,
+            age_us: None,
+        });
+        assert_eq!(input.distance_mm, 0);
+        assert!(!input.distance_valid);
+        assert_eq!(input.distance_age_ms, u32::MAX);
+    }
+
+    #[test]
+    fn output_layout() {
+        assert_eq!(offset_of!(RocOutput, delay_ms), 0);
+        assert_eq!(offset_of!(RocOutput, state), 8);
+        assert_eq!(offset_of!(RocOutput, display), 16);
+        assert_eq!(offset_of!(RocOutput, headlights), 41);
+        assert_eq!(offset_of!(RocOutput, reset_pose), 42);
+        assert_eq!(offset_of!(RocOutput, scan), 43);
+        assert_eq!(offset_of!(RocOutput, speed_left), 44);
+        assert_eq!(offset_of!(RocOutput, speed_right), 45);
+        assert_eq!(size_of::<RocOutput>(), 48);
+    }
+}
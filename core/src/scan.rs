@@ -0,0 +1,190 @@
+//! Sweeping the sonar across an arc with the servo it is mounted on.
+
+use crate::roc::{MapPoint, ScanMap};
+use crate::sonar::MAX_SENSOR_DISTANCE_MM;
+
+/// Number of angles pinged in a scan. Fixed so the result fits in `ScanMap`.
+pub const SCAN_POINTS: usize = 9;
+/// Time to let the servo stop shaking after any move.
+pub const SETTLE_BASE_MS: u32 = 20;
+/// Micro servos move about 60 degrees per 100ms, plus some margin.
+pub const SETTLE_MS_PER_DEGREE: u32 = 2;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanConfig {
+    pub start_angle: u8,
+    pub end_angle: u8,
+}
+
+impl ScanConfig {
+    /// Angle of the `i`th point, spread evenly from `start_angle` to `end_angle`.
+    pub fn angle(&self, i: usize) -> u8 {
+        let start = self.start_angle as i32;
+        let span = self.end_angle as i32 - start;
+        (start + span * i as i32 / (SCAN_POINTS as i32 - 1)) as u8
+    }
+
+    pub fn angles(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..SCAN_POINTS).map(|i| self.angle(i))
+    }
+}
+
+impl Default for ScanConfig {
+    fn default() -> ScanConfig {
+        ScanConfig {
+            start_angle: 0,
+            end_angle: 180,
+        }
+    }
+}
+
+/// How long to wait for the servo to reach `to` from `from` before pinging.
+pub fn settle_ms(from: u8, to: u8) -> u32 {
+    SETTLE_BASE_MS + SETTLE_MS_PER_DEGREE * from.abs_diff(to) as u32
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScanPoint {
+    pub angle: u8,
+    /// None if the ping got no echo, so nothing is in range.
+    pub distance_mm: Option<u32>,
+}
+
+impl ScanPoint {
+    /// Distance with "nothing in range" counted as the maximum range.
+    pub fn open_distance_mm(&self) -> u32 {
+        self.distance_mm.unwrap_or(MAX_SENSOR_DISTANCE_MM as u32)
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Scan {
+    pub points: [ScanPoint; SCAN_POINTS],
+}
+
+impl Scan {
+    /// The point with the most room in front of it.
+    /// Ties go to the point closest to the middle of the scan, which is usually straight ahead.
+    pub fn most_open(&self) -> ScanPoint {
+        let middle = SCAN_POINTS / 2;
+        let mut best = middle;
+        for (i, point) in self.points.iter().enumerate() {
+            let distance = point.open_distance_mm();
+            let best_distance = self.points[best].open_distance_mm();
+            if distance > best_distance
+                || (distance == best_distance && i.abs_diff(middle) < best.abs_diff(middle))
+            {
+                best = i;
+            }
+        }
+        self.points[best]
+    }
+
+    pub fn to_map(&self) -> ScanMap {
+        ScanMap {
+            points: self.points.map(|p| MapPoint {
+                distance_mm: p.distance_mm.unwrap_or(0),
+                angle_deg: p.angle,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan_of(distances: [Option<u32>; SCAN_POINTS]) -> Scan {
+        let config = ScanConfig::default();
+        let mut scan = Scan::default();
+        for (i, (point, distance)) in scan.points.iter_mut().zip(distances).enumerate() {
+            *point = ScanPoint {
+                angle: config.angle(i),
+                distance_mm: distance,
+            };
+        }
+        scan
+    }
+
+    #[test]
+    fn angles_cover_the_arc() {
+        let angles: [u8; SCAN_POINTS] = core::array::from_fn(|i| ScanConfig::default().angle(i));
+        assert_eq!(angles, [0, 22, 45, 67, 90, 112, 135, 157, 180]);
+        let narrow = ScanConfig {
+            start_angle: 60,
+            end_angle: 120,
+        };
+        assert_eq!(narrow.angles().next(), Some(60));
+        assert_eq!(narrow.angles().last(), Some(120));
+    }
+
+    #[test]
+    fn reversed_arc() {
+        let config = ScanConfig {
+            start_angle: 180,
+            end_angle: 0,
+        };
+        assert_eq!(config.angle(0), 180);
+        assert_eq!(config.angle(SCAN_POINTS - 1), 0);
+    }
+
+    #[test]
+    fn settle_time_grows_with_move() {
+        assert_eq!(settle_ms(90, 90), SETTLE_BASE_MS);
+        assert_eq!(settle_ms(0, 22), SETTLE_BASE_MS + 44);
+        assert_eq!(settle_ms(22, 0), settle_ms(0, 22));
+    }
+
+    #[test]
+    fn most_open_direction() {
+        let scan = scan_of([
+            Some(300),
+            Some(400),
+            Some(2500),
+            Some(500),
+            Some(200),
+            Some(100),
+            Some(100),
+            Some(100),
+            Some(100),
+        ]);
+        assert_eq!(scan.most_open().angle, 45);
+    }
+
+    #[test]
+    fn no_echo_counts_as_open() {
+        let mut distances = [Some(500); SCAN_POINTS];
+        distances[7] = None;
+        assert_eq!(scan_of(distances).most_open().angle, 157);
+    }
+
+    #[test]
+    fn ties_prefer_straight_ahead() {
+        assert_eq!(scan_of([None; SCAN_POINTS]).most_open().angle, 90);
+    }
+
+    #[test]
+    fn map_for_roc() {
+        let mut distances = [Some(500); SCAN_POINTS];
+        distances[0] = None;
+        let map = scan_of(distances).to_map();
+        assert_eq!(
+            map.points[0],
+            MapPoint {
+                distance_mm: 0,
+                angle_deg: 0
+            }
+        );
+        assert_eq!(
+            map.points[1],
+            MapPoint {
+                distance_mm: 500,
+                angle_deg: 22
+            }
+        );
+        assert_eq!(map.points[8].angle_deg, 180);
+    }
+}
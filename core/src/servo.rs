@@ -0,0 +1,292 @@
+//! Angle to duty mapping for the micro servo on the nRF `SimplePwm`.
+
+use crate::display::DisplayData;
+
+// microservo requires 50hz or 20ms period
+// set_period can only set down to 125khz so we cant use it directly
+// Div128 is 125khz or 0.000008s or 0.008ms, 20/0.008 is 2500 is top
+pub const MAX_DUTY: u16 = 2500;
+
+pub const MAX_ANGLE: u8 = 180;
+
+/// Fast enough to look instant on the micro servo, slow enough not to brown out the board.
+pub const DEFAULT_DEGREES_PER_S: u16 = 300;
+
+/// Furthest the trim self-test takes the trim. Past that the horn is better moved a spline.
+pub const MAX_TRIM_DEG: i8 = 20;
+
+/// How a particular servo maps angles to pulse widths.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServoCalibration {
+    /// Added to every angle so that 90 points straight ahead.
+    pub trim_deg: i8,
+    /// Pulse width at 0 degrees. Never sent anything shorter.
+    pub min_pulse_us: u16,
+    /// Pulse width at 180 degrees. Never sent anything longer.
+    pub max_pulse_us: u16,
+}
+
+impl ServoCalibration {
+    /// Pulse width for `angle`, clamped to `0..=MAX_ANGLE` and the calibrated pulse range.
+    pub fn pulse_us(&self, angle: u8) -> u16 {
+        let angle = i32::from(angle.min(MAX_ANGLE)) + i32::from(self.trim_deg);
+        let (min, max) = (
+            i32::from(self.min_pulse_us),
+            i32::from(self.max_pulse_us.max(self.min_pulse_us)),
+        );
+        // Angle range: about 180°(in 500→2500μsec)
+        let us = min + angle * (max - min) / MAX_ANGLE as i32;
+        us.clamp(min, max) as u16
+    }
+
+    /// `SimplePwm` duty for `angle`. The pwm is inverted, so this counts the low time.
+    pub fn duty(&self, angle: u8) -> u16 {
+        // Divide by 0.008 (multiply by 125) and divide by 1000 to get final value in ms.
+        let high = u32::from(self.pulse_us(angle)) * 125 / 1000;
+        MAX_DUTY.saturating_sub(high as u16)
+    }
+}
+
+impl ServoCalibration {
+    /// The same calibration with the trim moved by `delta_deg`, within `MAX_TRIM_DEG`.
+    pub fn trimmed_by(&self, delta_deg: i8) -> ServoCalibration {
+        ServoCalibration {
+            trim_deg: self
+                .trim_deg
+                .saturating_add(delta_deg)
+                .clamp(-MAX_TRIM_DEG, MAX_TRIM_DEG),
+            ..*self
+        }
+    }
+
+    /// Shows the trim as a dot on the top row above a line down the middle,
+    /// a column off center for every 5 degrees.
+    pub fn trim_display(&self) -> DisplayData {
+        let mut bytes = [[0; 5]; 5];
+        for row in &mut bytes[2..] {
+            row[2] = 1;
+        }
+        let col = 2 + (i32::from(self.trim_deg) * 2 + 5).div_euclid(10);
+        bytes[0][col.clamp(0, 4) as usize] = 1;
+        DisplayData::from_bytes(bytes)
+    }
+
+    /// The micro servo on the KeyeStudio base. It seems to be slightly off center.
+    pub const DEFAULT: ServoCalibration = ServoCalibration {
+        trim_deg: 5,
+        min_pulse_us: 500,
+        max_pulse_us: 2500,
+    };
+}
+
+impl Default for ServoCalibration {
+    fn default() -> ServoCalibration {
+        ServoCalibration::DEFAULT
+    }
+}
+
+/// Moves the servo towards a target angle at a limited rate.
+pub struct ServoController {
+    calibration: ServoCalibration,
+    /// 0 moves instantly.
+    degrees_per_s: f32,
+    /// None until the first move, since the servo could be anywhere at power on.
+    current: Option<f32>,
+    target: u8,
+}
+
+impl ServoController {
+    pub fn new(calibration: ServoCalibration) -> ServoController {
+        ServoController {
+            calibration,
+            degrees_per_s: DEFAULT_DEGREES_PER_S as f32,
+            current: None,
+            target: MAX_ANGLE / 2,
+        }
+    }
+
+    pub fn calibration(&self) -> ServoCalibration {
+        self.calibration
+    }
+
+    pub fn set_calibration(&mut self, calibration: ServoCalibration) {
+        self.calibration = calibration;
+    }
+
+    /// Sets the move rate. 0 jumps straight to the target.
+    pub fn set_degrees_per_s(&mut self, degrees_per_s: u16) {
+        self.degrees_per_s = degrees_per_s as f32;
+    }
+
+    /// Starts a move to `angle`, clamped to `0..=MAX_ANGLE`.
+    pub fn move_to(&mut self, angle: u8) {
+        self.target = angle.min(MAX_ANGLE);
+    }
+
+    /// Jumps to `angle` on the next step regardless of the move rate.
+    pub fn jump_to(&mut self, angle: u8) {
+        self.move_to(angle);
+        self.current = Some(self.target as f32);
+    }
+
+    pub fn target(&self) -> u8 {
+        self.target
+    }
+
+    /// Where the servo was last sent, None if it has never moved.
+    pub fn angle(&self) -> Option<u8> {
+        self.current.map(|angle| libm::roundf(angle) as u8)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.angle() == Some(self.target)
+    }
+
+    /// Moves towards the target by at most the allowed change for `dt` seconds.
+    /// Returns the duty to send to the servo.
+    pub fn step(&mut self, dt: f32) -> u16 {
+        let target = self.target as f32;
+        let next = match self.current {
+            Some(current) if self.degrees_per_s > 0.0 => {
+                let max_change = self.degrees_per_s * dt.max(0.0);
+                current + (target - current).clamp(-max_change, max_change)
+            }
+            _ => target,
+        };
+        self.current = Some(next);
+        self.duty()
+    }
+
+    /// Duty for the current angle, or the target if the servo has never moved.
+    pub fn duty(&self) -> u16 {
+        self.calibration.duty(self.angle().unwrap_or(self.target))
+    }
+}
+
+impl Default for ServoController {
+    fn default() -> ServoController {
+        ServoController::new(ServoCalibration::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn center() {
+        // 95 degrees after trim is 1555us, or 194 ticks high.
+        assert_eq!(ServoCalibration::default().duty(90), MAX_DUTY - 194);
+    }
+
+    #[test]
+    fn extremes() {
+        let calibration = ServoCalibration::default();
+        assert_eq!(calibration.duty(0), MAX_DUTY - 69);
+        // The trim would push 180 past the max pulse, so it is clamped.
+        assert_eq!(calibration.duty(180), MAX_DUTY - 312);
+    }
+
+    #[test]
+    fn monotonic() {
+        let calibration = ServoCalibration::default();
+        for angle in 0..180 {
+            assert!(calibration.duty(angle) >= calibration.duty(angle + 1));
+        }
+    }
+
+    #[test]
+    fn out_of_range_angles_are_clamped() {
+        let calibration = ServoCalibration::default();
+        // Used to overflow with the trim added.
+        assert_eq!(calibration.pulse_us(255), 2500);
+        assert_eq!(calibration.pulse_us(200), calibration.pulse_us(180));
+        let negative = ServoCalibration {
+            trim_deg: -10,
+            ..calibration
+        };
+        assert_eq!(negative.pulse_us(0), 500);
+    }
+
+    #[test]
+    fn custom_pulse_range() {
+        let calibration = ServoCalibration {
+            trim_deg: 0,
+            min_pulse_us: 1000,
+            max_pulse_us: 2000,
+        };
+        assert_eq!(calibration.pulse_us(0), 1000);
+        assert_eq!(calibration.pulse_us(90), 1500);
+        assert_eq!(calibration.pulse_us(180), 2000);
+    }
+
+    #[test]
+    fn trim_is_limited() {
+        let calibration = ServoCalibration::default();
+        assert_eq!(calibration.trimmed_by(-3).trim_deg, 2);
+        assert_eq!(calibration.trimmed_by(100).trim_deg, MAX_TRIM_DEG);
+        assert_eq!(calibration.trimmed_by(-100).trim_deg, -MAX_TRIM_DEG);
+        assert_eq!(
+            calibration.trimmed_by(1).min_pulse_us,
+            calibration.min_pulse_us
+        );
+    }
+
+    #[test]
+    fn trim_display_moves_with_trim() {
+        let top_col = |trim_deg| {
+            let calibration = ServoCalibration {
+                trim_deg,
+                ..ServoCalibration::default()
+            };
+            let bytes = calibration.trim_display().to_bytes();
+            bytes[0].iter().position(|&led| led == 1)
+        };
+        assert_eq!(top_col(0), Some(2));
+        assert_eq!(top_col(2), Some(2));
+        assert_eq!(top_col(3), Some(3));
+        assert_eq!(top_col(-3), Some(1));
+        assert_eq!(top_col(-10), Some(0));
+        assert_eq!(top_col(MAX_TRIM_DEG), Some(4));
+    }
+
+    #[test]
+    fn first_move_jumps() {
+        let mut servo = ServoController::default();
+        assert_eq!(servo.angle(), None);
+        assert!(!servo.is_finished());
+        servo.move_to(30);
+        servo.step(0.0);
+        assert_eq!(servo.angle(), Some(30));
+        assert!(servo.is_finished());
+    }
+
+    #[test]
+    fn limits_move_rate() {
+        let mut servo = ServoController::default();
+        servo.set_degrees_per_s(100);
+        servo.jump_to(90);
+        servo.move_to(0);
+        servo.step(0.1);
+        assert_eq!(servo.angle(), Some(80));
+        assert!(!servo.is_finished());
+        for _ in 0..8 {
+            servo.step(0.1);
+        }
+        assert_eq!(servo.angle(), Some(0));
+        assert!(servo.is_finished());
+        assert_eq!(servo.duty(), servo.calibration().duty(0));
+    }
+
+    #[test]
+    fn zero_rate_is_instant() {
+        let mut servo = ServoController::default();
+        servo.set_degrees_per_s(0);
+        servo.jump_to(0);
+        servo.move_to(250);
+        assert_eq!(servo.target(), 180);
+        servo.step(0.001);
+        assert!(servo.is_finished());
+    }
+}
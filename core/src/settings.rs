@@ -0,0 +1,170 @@
+//! Settings kept in flash across power cycles, and their byte layout.
+
+use crate::mag::MagCalibration;
+use crate::motor::Motor;
+use crate::servo::ServoCalibration;
+use crate::wiring::MotorCorrection;
+
+/// Bytes taken in flash. A multiple of 4 so it can be written a word at a time.
+pub const SETTINGS_LEN: usize = 68;
+const MAGIC: [u8; 4] = *b"RMBS";
+const VERSION: u8 = 1;
+const CHECKSUM_AT: usize = SETTINGS_LEN - 2;
+const MAG_OFFSET_AT: usize = 10;
+const MAG_SOFT_IRON_AT: usize = MAG_OFFSET_AT + 12;
+// A flag for whether there is one, then trim, min and max pulse.
+const SERVO_AT: usize = MAG_SOFT_IRON_AT + 36;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub motors: MotorCorrection,
+    pub mag: MagCalibration,
+    /// None keeps the board profile's calibration.
+    pub servo: Option<ServoCalibration>,
+}
+
+fn checksum(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .fold(0u16, |sum, byte| sum.rotate_left(1) ^ u16::from(*byte))
+}
+
+impl Settings {
+    pub fn to_bytes(&self) -> [u8; SETTINGS_LEN] {
+        let mut bytes = [0; SETTINGS_LEN];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4] = VERSION;
+        for (byte, source) in bytes[5..9].iter_mut().zip(self.motors.sources) {
+            *byte = source as u8;
+        }
+        bytes[9] = self
+            .motors
+            .inverted
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, inverted)| bits | (*inverted as u8) << i);
+        for (chunk, offset) in bytes[MAG_OFFSET_AT..MAG_SOFT_IRON_AT]
+            .chunks_exact_mut(4)
+            .zip(self.mag.offset)
+        {
+            chunk.copy_from_slice(&offset.to_le_bytes());
+        }
+        for (chunk, value) in bytes[MAG_SOFT_IRON_AT..MAG_SOFT_IRON_AT + 36]
+            .chunks_exact_mut(4)
+            .zip(self.mag.soft_iron.iter().flatten())
+        {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        if let Some(servo) = self.servo {
+            bytes[SERVO_AT] = 1;
+            bytes[SERVO_AT + 1] = servo.trim_deg as u8;
+            bytes[SERVO_AT + 2..SERVO_AT + 4].copy_from_slice(&servo.min_pulse_us.to_le_bytes());
+            bytes[SERVO_AT + 4..SERVO_AT + 6].copy_from_slice(&servo.max_pulse_us.to_le_bytes());
+        }
+        let sum = checksum(&bytes[..CHECKSUM_AT]);
+        bytes[CHECKSUM_AT..].copy_from_slice(&sum.to_le_bytes());
+        bytes
+    }
+
+    /// Returns None for erased flash, settings from an unknown version, or corrupt bytes.
+    pub fn from_bytes(bytes: &[u8; SETTINGS_LEN]) -> Option<Settings> {
+        if bytes[..4] != MAGIC || bytes[4] != VERSION {
+            return None;
+        }
+        let sum = u16::from_le_bytes([bytes[CHECKSUM_AT], bytes[CHECKSUM_AT + 1]]);
+        if sum != checksum(&bytes[..CHECKSUM_AT]) {
+            return None;
+        }
+        let mut sources = Motor::ALL;
+        for (source, byte) in sources.iter_mut().zip(&bytes[5..9]) {
+            *source = *Motor::ALL.get(*byte as usize)?;
+        }
+        let inverted = core::array::from_fn(|i| bytes[9] >> i & 1 == 1);
+        let motors = MotorCorrection { sources, inverted };
+        let word = |at: usize| [bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]];
+        let offset = core::array::from_fn(|i| i32::from_le_bytes(word(MAG_OFFSET_AT + 4 * i)));
+        let soft_iron = core::array::from_fn(|row| {
+            core::array::from_fn(|col| {
+                f32::from_le_bytes(word(MAG_SOFT_IRON_AT + 4 * (3 * row + col)))
+            })
+        });
+        let servo = match bytes[SERVO_AT] {
+            0 => None,
+            1 => Some(ServoCalibration {
+                trim_deg: bytes[SERVO_AT + 1] as i8,
+                min_pulse_us: u16::from_le_bytes([bytes[SERVO_AT + 2], bytes[SERVO_AT + 3]]),
+                max_pulse_us: u16::from_le_bytes([bytes[SERVO_AT + 4], bytes[SERVO_AT + 5]]),
+            }),
+            _ => return None,
+        };
+        Some(Settings {
+            motors,
+            mag: MagCalibration { offset, soft_iron },
+            servo,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            motors: MotorCorrection {
+                sources: [
+                    Motor::BackLeft,
+                    Motor::FrontLeft,
+                    Motor::FrontRight,
+                    Motor::BackRight,
+                ],
+                inverted: [false, true, false, true],
+            },
+            mag: MagCalibration {
+                offset: [-1234, 56789, 0],
+                soft_iron: [[1.1, 0.0, 0.01], [0.0, 1.0, 0.0], [-0.01, 0.0, 0.9]],
+            },
+            servo: Some(ServoCalibration {
+                trim_deg: -7,
+                min_pulse_us: 600,
+                max_pulse_us: 2400,
+            }),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = settings().to_bytes();
+        assert_eq!(&bytes[..4], b"RMBS");
+        assert_eq!(Settings::from_bytes(&bytes), Some(settings()));
+        assert_eq!(
+            Settings::from_bytes(&Settings::default().to_bytes()),
+            Some(Settings::default())
+        );
+    }
+
+    #[test]
+    fn erased_flash_is_rejected() {
+        assert_eq!(Settings::from_bytes(&[0xFF; SETTINGS_LEN]), None);
+    }
+
+    #[test]
+    fn corruption_is_rejected() {
+        let mut bytes = settings().to_bytes();
+        bytes[9] ^= 1;
+        assert_eq!(Settings::from_bytes(&bytes), None);
+
+        let mut bytes = settings().to_bytes();
+        bytes[4] = VERSION + 1;
+        assert_eq!(Settings::from_bytes(&bytes), None);
+
+        let mut bytes = settings().to_bytes();
+        bytes[MAG_SOFT_IRON_AT] ^= 0x40;
+        assert_eq!(Settings::from_bytes(&bytes), None);
+
+        let mut bytes = settings().to_bytes();
+        bytes[SERVO_AT + 4] ^= 1;
+        assert_eq!(Settings::from_bytes(&bytes), None);
+    }
+}
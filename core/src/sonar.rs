@@ -0,0 +1,254 @@
+//! Echo timing maths for the HC-SR04 style sonar on the robot base.
+
+/// How long to wait for the echo pulse to start after triggering.
+pub const MAX_SENSOR_DELAY_US: u64 = 35000;
+pub const MAX_SENSOR_DISTANCE_MM: u64 = 3000;
+/// HC-SR04 style sensors need about 60ms between pings so old echoes die out.
+pub const MIN_PING_INTERVAL_US: u64 = 60_000;
+/// Number of recent pings the filter looks at.
+pub const FILTER_WINDOW: usize = 5;
+/// How many pings in the window have to agree before a reading is trusted.
+pub const MIN_AGREEING_SAMPLES: usize = 3;
+/// Pings further than this from the median are outliers, or 10% of the distance if larger.
+pub const OUTLIER_TOLERANCE_MM: u32 = 50;
+/// Temperature to assume until a real reading is available, in hundredths of a degree C.
+pub const DEFAULT_TEMPERATURE_CENTI_C: i32 = 2000;
+
+/// Speed of sound in air at the given temperature, in mm/s.
+/// Uses the linear approximation 331.3 + 0.606 * T m/s, which is good for everyday temperatures.
+pub fn speed_of_sound_mm_per_s(temperature_centi_c: i32) -> u64 {
+    let speed = (33_130_000 + 606 * temperature_centi_c as i64) / 100;
+    // Clamp to something sensible in case of a broken temperature reading.
+    speed.clamp(300_000, 380_000) as u64
+}
+
+/// Longest echo pulse that is still within `MAX_SENSOR_DISTANCE_MM`.
+pub fn max_echo_time_us(temperature_centi_c: i32) -> u64 {
+    let speed = speed_of_sound_mm_per_s(temperature_centi_c);
+    // There and back again, plus half a mm so that the maximum distance still rounds in range.
+    (MAX_SENSOR_DISTANCE_MM * 2 + 1) * 1_000_000 / speed
+}
+
+/// Converts the length of the echo pulse to a distance, rounding to the nearest mm.
+pub fn echo_time_to_mm(echo_time_us: u64, temperature_centi_c: i32) -> u32 {
+    let speed = speed_of_sound_mm_per_s(temperature_centi_c);
+    // The pulse covers the distance twice.
+    ((echo_time_us * speed + 1_000_000) / 2_000_000) as u32
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SonarReading {
+    /// Median of the recent pings, or None if none of them got an echo.
+    pub distance_mm: Option<u32>,
+    /// Enough recent pings agree with the distance to trust it, or agree that nothing is
+    /// in range.
+    pub valid: bool,
+    /// Time since the newest ping that agreed with the distance.
+    pub age_us: Option<u64>,
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    at_us: u64,
+    distance_mm: Option<u32>,
+}
+
+/// Keeps a window of recent pings and rejects the spikes and dropouts single pings are prone to.
+/// Timestamps are in microseconds from any fixed starting point.
+pub struct SonarFilter {
+    samples: [Option<Sample>; FILTER_WINDOW],
+    next: usize,
+    last_ping_us: Option<u64>,
+}
+
+impl SonarFilter {
+    pub fn new() -> SonarFilter {
+        SonarFilter {
+            samples: [None; FILTER_WINDOW],
+            next: 0,
+            last_ping_us: None,
+        }
+    }
+
+    /// Earliest time the next ping is allowed.
+    pub fn next_ping_us(&self) -> u64 {
+        match self.last_ping_us {
+            Some(last) => last + MIN_PING_INTERVAL_US,
+            None => 0,
+        }
+    }
+
+    /// Records that a ping was sent at `at_us` without adding it to the window.
+    pub fn record_ping(&mut self, at_us: u64) {
+        self.last_ping_us = Some(at_us);
+    }
+
+    /// Records a ping sent at `at_us` and its measured distance.
+    pub fn add(&mut self, at_us: u64, distance_mm: Option<u32>) {
+        self.record_ping(at_us);
+        self.samples[self.next] = Some(Sample { at_us, distance_mm });
+        self.next = (self.next + 1) % FILTER_WINDOW;
+    }
+
+    pub fn reading(&self, now_us: u64) -> SonarReading {
+        let mut distances = [0; FILTER_WINDOW];
+        let mut len = 0;
+        for sample in self.samples.iter().flatten() {
+            if let Some(d) = sample.distance_mm {
+                distances[len] = d;
+                len += 1;
+            }
+        }
+        if len == 0 {
+            // None of the pings got an echo, which they all agree on.
+            let pings = self.samples.iter().flatten();
+            let newest = pings.clone().map(|sample| sample.at_us).max();
+            return SonarReading {
+                distance_mm: None,
+                valid: pings.count() >= MIN_AGREEING_SAMPLES,
+                age_us: newest.map(|at| now_us.saturating_sub(at)),
+            };
+        }
+        let distances = &mut distances[..len];
+        distances.sort_unstable();
+        let median = distances[len / 2];
+
+        let tolerance = OUTLIER_TOLERANCE_MM.max(median / 10);
+        let mut agreeing = 0;
+        let mut newest = None;
+        for sample in self.samples.iter().flatten() {
+            if let Some(d) = sample.distance_mm {
+                if d.abs_diff(median) <= tolerance {
+                    agreeing += 1;
+                    newest = newest.max(Some(sample.at_us));
+                }
+            }
+        }
+        SonarReading {
+            distance_mm: Some(median),
+            valid: agreeing >= MIN_AGREEING_SAMPLES,
+            age_us: newest.map(|at| now_us.saturating_sub(at)),
+        }
+    }
+}
+
+impl Default for SonarFilter {
+    fn default() -> SonarFilter {
+        SonarFilter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_of_sound() {
+        assert_eq!(speed_of_sound_mm_per_s(0), 331_300);
+        assert_eq!(speed_of_sound_mm_per_s(2000), 343_420);
+        assert_eq!(speed_of_sound_mm_per_s(-1000), 325_240);
+        assert_eq!(speed_of_sound_mm_per_s(i32::MAX), 380_000);
+    }
+
+    #[test]
+    fn room_temperature_matches_58us_per_cm() {
+        // The old constant assumed 58us per cm round trip.
+        assert_eq!(echo_time_to_mm(580, 2000), 100);
+        assert_eq!(echo_time_to_mm(5800, 2000), 996);
+    }
+
+    #[test]
+    fn temperature_changes_distance() {
+        // Same echo, but sound is slower in a cold garage and faster in a hot classroom.
+        let echo = 5830;
+        assert_eq!(echo_time_to_mm(echo, 0), 966);
+        assert_eq!(echo_time_to_mm(echo, 2000), 1001);
+        assert_eq!(echo_time_to_mm(echo, 3500), 1028);
+    }
+
+    #[test]
+    fn rounds_to_nearest_mm() {
+        assert_eq!(echo_time_to_mm(0, 2000), 0);
+        assert_eq!(echo_time_to_mm(3, 2000), 1);
+        assert_eq!(echo_time_to_mm(2, 2000), 0);
+    }
+
+    #[test]
+    fn max_echo_is_max_distance() {
+        for temperature in [-2000, 0, 2000, 4000] {
+            let max = max_echo_time_us(temperature);
+            assert_eq!(
+                echo_time_to_mm(max, temperature),
+                MAX_SENSOR_DISTANCE_MM as u32
+            );
+        }
+        assert!(max_echo_time_us(0) > max_echo_time_us(3000));
+    }
+
+    fn filter_with(samples: &[Option<u32>]) -> SonarFilter {
+        let mut filter = SonarFilter::new();
+        for (i, sample) in samples.iter().enumerate() {
+            filter.add(i as u64 * MIN_PING_INTERVAL_US, *sample);
+        }
+        filter
+    }
+
+    #[test]
+    fn empty_filter_is_invalid() {
+        let reading = SonarFilter::new().reading(0);
+        assert_eq!(reading.distance_mm, None);
+        assert!(!reading.valid);
+        assert_eq!(reading.age_us, None);
+    }
+
+    #[test]
+    fn rejects_spikes_and_dropouts() {
+        let filter = filter_with(&[Some(500), Some(2900), None, Some(510), Some(495)]);
+        let reading = filter.reading(5 * MIN_PING_INTERVAL_US);
+        assert_eq!(reading.distance_mm, Some(510));
+        assert!(reading.valid);
+        assert_eq!(reading.age_us, Some(MIN_PING_INTERVAL_US));
+    }
+
+    #[test]
+    fn needs_agreeing_samples() {
+        let reading = filter_with(&[Some(500), Some(1500)]).reading(0);
+        assert!(!reading.valid);
+        let reading = filter_with(&[None, None, Some(500), Some(502), None]).reading(0);
+        assert_eq!(reading.distance_mm, Some(502));
+        assert!(!reading.valid);
+    }
+
+    #[test]
+    fn agreeing_dropouts_mean_nothing_in_range() {
+        let reading = filter_with(&[None, None]).reading(2 * MIN_PING_INTERVAL_US);
+        assert!(!reading.valid);
+        let reading = filter_with(&[None, None, None]).reading(3 * MIN_PING_INTERVAL_US);
+        assert_eq!(reading.distance_mm, None);
+        assert!(reading.valid);
+        assert_eq!(reading.age_us, Some(MIN_PING_INTERVAL_US));
+    }
+
+    #[test]
+    fn old_samples_leave_the_window() {
+        let mut filter = filter_with(&[Some(800); FILTER_WINDOW]);
+        for i in 0..FILTER_WINDOW as u64 {
+            filter.add((10 + i) * MIN_PING_INTERVAL_US, Some(300));
+        }
+        let reading = filter.reading(15 * MIN_PING_INTERVAL_US);
+        assert_eq!(reading.distance_mm, Some(300));
+        assert!(reading.valid);
+    }
+
+    #[test]
+    fn ping_interval() {
+        let mut filter = SonarFilter::new();
+        assert_eq!(filter.next_ping_us(), 0);
+        filter.add(1_000, None);
+        assert_eq!(filter.next_ping_us(), 1_000 + MIN_PING_INTERVAL_US);
+        filter.record_ping(5_000);
+        assert_eq!(filter.next_ping_us(), 5_000 + MIN_PING_INTERVAL_US);
+        assert_eq!(filter.reading(5_000).distance_mm, None);
+    }
+}
@@ -0,0 +1,379 @@
+//! Guided self-test for working out how the motors are really wired.
+//! Each motor is spun in turn while the display shows which wheel should be moving,
+//! and buttons A and B answer what actually happened. The answers become a
+//! `MotorCorrection` that `RobotBase` applies on top of the board profile.
+
+use crate::board::MotorConfig;
+use crate::display::DisplayData;
+use crate::motor::{Direction, Motor};
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    /// Yes, or pick what is shown.
+    A,
+    /// No, or show the next choice.
+    B,
+}
+
+/// Which profile motor drives each wheel, and which ones run backwards.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotorCorrection {
+    /// For each wheel in `Motor::ALL` order, the profile motor that drives it.
+    pub sources: [Motor; 4],
+    /// For each wheel in `Motor::ALL` order, whether its motor runs backwards.
+    pub inverted: [bool; 4],
+}
+
+impl MotorCorrection {
+    pub const NONE: MotorCorrection = MotorCorrection {
+        sources: Motor::ALL,
+        inverted: [false; 4],
+    };
+
+    /// Rearranges profile motors, in `Motor::ALL` order, so each one drives the wheel it is
+    /// really connected to.
+    pub fn apply(&self, motors: [Option<MotorConfig>; 4]) -> [Option<MotorConfig>; 4] {
+        let mut corrected = [None; 4];
+        for ((wheel, source), inverted) in corrected.iter_mut().zip(self.sources).zip(self.inverted)
+        {
+            *wheel = motors[source as usize].map(|config| MotorConfig {
+                inverted: config.inverted != inverted,
+                ..config
+            });
+        }
+        corrected
+    }
+}
+
+impl Default for MotorCorrection {
+    fn default() -> MotorCorrection {
+        MotorCorrection::NONE
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WiringError {
+    Unfinished,
+    /// Nothing moved when this profile motor was driven.
+    NoWheel(Motor),
+    /// The motor went the same way when driven forward and reverse.
+    Inconsistent(Motor),
+    /// More than one motor drives this wheel.
+    Duplicate(Motor),
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// `motor` spins forward while `shown` is displayed.
+    /// A picks the shown wheel as the one moving, B shows the next wheel, then nothing.
+    FindWheel {
+        motor: Motor,
+        shown: Option<Motor>,
+    },
+    /// `motor` still spins forward. A if `wheel` rolls forward, B if it rolls backward.
+    CheckForward {
+        motor: Motor,
+        wheel: Motor,
+    },
+    /// `motor` spins in reverse. A if `wheel` now rolls backward, B if it does not.
+    CheckReverse {
+        motor: Motor,
+        wheel: Motor,
+    },
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Answer {
+    wheel: Motor,
+    inverted: bool,
+}
+
+pub struct WiringTest {
+    present: [bool; 4],
+    step: Step,
+    // For each profile motor, the wheel it drives. None if not tested yet.
+    answers: [Option<Answer>; 4],
+    error: Option<WiringError>,
+}
+
+impl WiringTest {
+    /// Tests the profile motors that are `present`, in `Motor::ALL` order.
+    pub fn new(present: [bool; 4]) -> WiringTest {
+        let mut test = WiringTest {
+            present,
+            step: Step::Done,
+            answers: [None; 4],
+            error: None,
+        };
+        test.step = test.first_step_from(0);
+        test
+    }
+
+    fn first_step_from(&self, index: usize) -> Step {
+        Motor::ALL[index.min(4)..]
+            .iter()
+            .find(|motor| self.present[**motor as usize])
+            .map(|motor| Step::FindWheel {
+                motor: *motor,
+                shown: Some(*motor),
+            })
+            .unwrap_or(Step::Done)
+    }
+
+    pub fn step(&self) -> Step {
+        self.step
+    }
+
+    /// The profile motor to drive for the current step and which way, None once done.
+    pub fn command(&self) -> Option<(Motor, Direction)> {
+        match self.step {
+            Step::FindWheel { motor, .. } | Step::CheckForward { motor, .. } => {
+                Some((motor, Direction::Forward))
+            }
+            Step::CheckReverse { motor, .. } => Some((motor, Direction::Reverse)),
+            Step::Done => None,
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.step = match (self.step, button) {
+            (Step::FindWheel { motor, shown }, Button::B) => Step::FindWheel {
+                motor,
+                shown: match shown {
+                    Some(wheel) => Motor::ALL.get(wheel as usize + 1).copied(),
+                    None => Some(Motor::ALL[0]),
+                },
+            },
+            (Step::FindWheel { motor, shown: None }, Button::A) => {
+                self.fail(WiringError::NoWheel(motor));
+                self.first_step_from(motor as usize + 1)
+            }
+            (
+                Step::FindWheel {
+                    motor,
+                    shown: Some(wheel),
+                },
+                Button::A,
+            ) => Step::CheckForward { motor, wheel },
+            (Step::CheckForward { motor, wheel }, button) => {
+                self.answers[motor as usize] = Some(Answer {
+                    wheel,
+                    inverted: button == Button::B,
+                });
+                Step::CheckReverse { motor, wheel }
+            }
+            (Step::CheckReverse { motor, .. }, button) => {
+                if button == Button::B {
+                    self.fail(WiringError::Inconsistent(motor));
+                }
+                self.first_step_from(motor as usize + 1)
+            }
+            (Step::Done, _) => Step::Done,
+        };
+    }
+
+    fn fail(&mut self, error: WiringError) {
+        self.error.get_or_insert(error);
+    }
+
+    /// The correction for everything answered, once every motor has been tested.
+    pub fn result(&self) -> Result<MotorCorrection, WiringError> {
+        if self.step != Step::Done {
+            return Err(WiringError::Unfinished);
+        }
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let mut sources: [Option<Motor>; 4] = [None; 4];
+        let mut inverted = [false; 4];
+        for (motor, answer) in Motor::ALL.iter().zip(self.answers) {
+            if let Some(Answer { wheel, inverted: i }) = answer {
+                if sources[wheel as usize].replace(*motor).is_some() {
+                    return Err(WiringError::Duplicate(wheel));
+                }
+                inverted[wheel as usize] = i;
+            }
+        }
+        // Untested motors go to the wheels nobody claimed, so every motor is still used once.
+        let mut untested = Motor::ALL
+            .iter()
+            .filter(|motor| self.answers[**motor as usize].is_none());
+        let sources = sources.map(|source| source.or_else(|| untested.next().copied()));
+        Ok(MotorCorrection {
+            sources: sources.map(|source| source.unwrap_or(Motor::FrontLeft)),
+            inverted,
+        })
+    }
+
+    /// Lights the corner of the wheel being asked about, with a bar in the middle
+    /// pointing the way it should roll. A blank display asks whether nothing moved.
+    pub fn display(&self) -> DisplayData {
+        let (wheel, dir) = match self.step {
+            Step::FindWheel { shown, .. } => (shown, None),
+            Step::CheckForward { wheel, .. } => (Some(wheel), Some(Direction::Forward)),
+            Step::CheckReverse { wheel, .. } => (Some(wheel), Some(Direction::Reverse)),
+            Step::Done => (None, None),
+        };
+        let mut bytes = [[0; 5]; 5];
+        if let Some(wheel) = wheel {
+            let rows = match wheel {
+                Motor::FrontLeft | Motor::FrontRight => 0..2,
+                Motor::BackLeft | Motor::BackRight => 3..5,
+            };
+            let cols = match wheel {
+                Motor::FrontLeft | Motor::BackLeft => 0..2,
+                Motor::FrontRight | Motor::BackRight => 3..5,
+            };
+            for row in &mut bytes[rows] {
+                row[cols.clone()].fill(1);
+            }
+        }
+        let bar = match dir {
+            Some(Direction::Forward) => 0..3,
+            Some(Direction::Reverse) => 2..5,
+            None => 0..0,
+        };
+        for row in &mut bytes[bar] {
+            row[2] = 1;
+        }
+        DisplayData::from_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::{MotorWiring, KEYESTUDIO_MECANUM};
+
+    fn answer_all(test: &mut WiringTest, presses: &[Button]) {
+        for press in presses {
+            test.press(*press);
+        }
+    }
+
+    #[test]
+    fn correct_wiring_needs_no_correction() {
+        let mut test = WiringTest::new([true; 4]);
+        assert_eq!(test.command(), Some((Motor::FrontLeft, Direction::Forward)));
+        // Pick the shown wheel, it rolls forward, then backward in reverse.
+        answer_all(&mut test, &[Button::A; 12]);
+        assert_eq!(test.step(), Step::Done);
+        assert_eq!(test.command(), None);
+        assert_eq!(test.result(), Ok(MotorCorrection::NONE));
+    }
+
+    #[test]
+    fn swapped_and_inverted_motors() {
+        let mut test = WiringTest::new([true; 4]);
+        use Button::*;
+        // Front left drives the back left wheel backwards.
+        answer_all(&mut test, &[B, A, B, A]);
+        // Back left drives the front left wheel.
+        assert_eq!(
+            test.step(),
+            Step::FindWheel {
+                motor: Motor::BackLeft,
+                shown: Some(Motor::BackLeft)
+            }
+        );
+        answer_all(&mut test, &[B, B, B, B, A, A, A]);
+        // The right side is fine.
+        answer_all(&mut test, &[A; 6]);
+        let correction = test.result().unwrap();
+        assert_eq!(
+            correction,
+            MotorCorrection {
+                sources: [
+                    Motor::BackLeft,
+                    Motor::FrontLeft,
+                    Motor::FrontRight,
+                    Motor::BackRight
+                ],
+                inverted: [false, true, false, false],
+            }
+        );
+
+        let motors = correction.apply(KEYESTUDIO_MECANUM.motors);
+        let back_left = motors[Motor::BackLeft as usize].unwrap();
+        assert_eq!(
+            back_left.wiring,
+            MotorWiring::ThreeChannel(Motor::FrontLeft.channels())
+        );
+        assert!(back_left.inverted);
+    }
+
+    #[test]
+    fn errors() {
+        use Button::*;
+        let mut test = WiringTest::new([true; 4]);
+        assert_eq!(test.result(), Err(WiringError::Unfinished));
+        // Cycle past every wheel to "nothing moved".
+        answer_all(&mut test, &[B, B, B, B, A]);
+        answer_all(&mut test, &[A; 9]);
+        assert_eq!(test.result(), Err(WiringError::NoWheel(Motor::FrontLeft)));
+
+        let mut test = WiringTest::new([true; 4]);
+        answer_all(&mut test, &[A, A, B]);
+        answer_all(&mut test, &[A; 9]);
+        assert_eq!(
+            test.result(),
+            Err(WiringError::Inconsistent(Motor::FrontLeft))
+        );
+
+        let mut test = WiringTest::new([true; 4]);
+        // Front left and back left both claim the front left wheel.
+        answer_all(&mut test, &[A, A, A, B, B, B, B, A, A, A]);
+        answer_all(&mut test, &[A; 6]);
+        assert_eq!(test.result(), Err(WiringError::Duplicate(Motor::FrontLeft)));
+    }
+
+    #[test]
+    fn missing_motors_are_skipped() {
+        let mut test = WiringTest::new([true, false, true, false]);
+        answer_all(&mut test, &[Button::A; 3]);
+        assert_eq!(
+            test.step(),
+            Step::FindWheel {
+                motor: Motor::FrontRight,
+                shown: Some(Motor::FrontRight)
+            }
+        );
+        answer_all(&mut test, &[Button::A; 3]);
+        assert_eq!(test.result(), Ok(MotorCorrection::NONE));
+    }
+
+    #[test]
+    fn display_shows_wheel_and_direction() {
+        let mut test = WiringTest::new([true; 4]);
+        assert_eq!(
+            test.display().to_bytes(),
+            [
+                [1, 1, 0, 0, 0],
+                [1, 1, 0, 0, 0],
+                [0, 0, 0, 0, 0],
+                [0, 0, 0, 0, 0],
+                [0, 0, 0, 0, 0],
+            ]
+        );
+        test.press(Button::B);
+        test.press(Button::B);
+        test.press(Button::A);
+        test.press(Button::A);
+        // Asking whether the front right wheel rolls backward.
+        assert_eq!(
+            test.display().to_bytes(),
+            [
+                [0, 0, 0, 1, 1],
+                [0, 0, 0, 1, 1],
+                [0, 0, 1, 0, 0],
+                [0, 0, 1, 0, 0],
+                [0, 0, 1, 0, 0],
+            ]
+        );
+    }
+}
@@ -1,5 +1,5 @@
 interface IO
-    exposes [ Input, Output, Display, Row, displayNum ]
+    exposes [ Input, Output, Display, Row, MapPoint, ScanMap, displayNum ]
     imports []
 
 Row : [
@@ -15,18 +15,123 @@ LightLevel : [
         Dark,
     ]
 
+# Whether the collision guard held back the last motor command.
+# Until the sonar pings agree it drives as if something were 20cm ahead.
+GuardStatus : [
+        Clear,
+        Slowed,
+        Blocked,
+    ]
+
+# What the IR remote did since the last call.
+IrEvent : [
+        Idle,
+        Pressed,
+        Repeat,
+    ]
+
+# The latest thing done to the robot since the last call, from the robot's point of view.
+# The motors stop by themselves when it is lifted, dropped or tipped over, and start again
+# once it has sat still on its wheels for a couple of seconds.
+Gesture : [
+        None,
+        Shake,
+        Lifted,
+        FreeFall,
+        FaceUp,
+        FaceDown,
+        # Nose down.
+        TiltForward,
+        # Nose up.
+        TiltBackward,
+        TiltLeft,
+        TiltRight,
+    ]
+
+# What the headlights do until the next call, the robot keeps the patterns going.
+# TurnSignals keeps them on, blinking the one on the side the robot turns towards.
+HeadlightMode : [
+        Off,
+        On,
+        Blink,
+        Breathe,
+        TurnSignals,
+    ]
+
+# Whether a device on the I2C bus is answering.
+# The robot keeps running without an unavailable device, and keeps trying to get it back.
+DeviceStatus : [
+        Available,
+        Unavailable,
+    ]
+
+# One point of a sonar scan: the distance in mm, 0 where nothing was in range,
+# and the servo angle it was pinged at in degrees, 90 being straight ahead.
+MapPoint : [
+        MapPoint U32 U8,
+    ]
+
+# The points of the last sonar scan, spread across the scan arc, 0 to 180 degrees by default.
+ScanMap : [
+        ScanMap MapPoint MapPoint MapPoint MapPoint MapPoint MapPoint MapPoint MapPoint MapPoint,
+    ]
+
+# Where the robot is, dead reckoned from the wheel speeds and compass since the last resetPose.
+# x is forwards and y is to the left at the reset, and the heading turns counterclockwise.
+# The wheel speed model is not calibrated, so treat distances as a rough guess.
+Pose : {
+        xMM : I32,
+        yMM : I32,
+        # Hundredths of a degree, from -18000 to 18000.
+        headingCentiDeg : I16,
+    }
+
 State : U64
 
 Input : {
         state: State,
+        # Filtered sonar distance, 0 if nothing is in range.
+        distanceMM : U32,
+        # Whether enough recent pings agree on distanceMM to trust it, 0 included.
+        distanceValid : Bool,
+        # Time since a ping last agreed with distanceMM.
+        distanceAgeMS : U32,
+        scan : ScanMap,
+        pose : Pose,
+        guard : GuardStatus,
+        # Address and command of the last IR remote key, kept until another key arrives.
+        irAddress : U16,
+        irCommand : U8,
+        irEvent : IrEvent,
         lightLeft : LightLevel,
         lightRight : LightLevel,
+        gesture : Gesture,
+        # Largest acceleration since the last call in milli-g, about 1000 when nothing happened.
+        # Hitting a wall shows up as a spike.
+        accelPeakMG : U16,
+        # How much the acceleration shook about its average since the last call, in milli-g.
+        # Near 0 on a smooth floor, higher on rough ground.
+        accelRmsMG : U16,
+        # Debounced line sensor changes since the last call, so narrow lines are not missed.
+        # Crossing a strip of tape counts as two.
+        transitionsLeft : U8,
+        transitionsRight : U8,
+        # The accelerometer and magnetometer.
+        imu : DeviceStatus,
+        # The motor driver, which also runs the headlights.
+        # Wheel speeds are ignored while it is unavailable.
+        motors : DeviceStatus,
     }
 
 Output : {
         delayMS: U64,
         state: State,
         display : Display,
+        headlights : HeadlightMode,
+        # Make the current position the origin of pose before the next call.
+        resetPose : Bool,
+        # Sweep the sonar across the scan arc before the next call.
+        scan : Bool,
         speedLeft: I8,
         speedRight: I8,
     }
@@ -1,6 +1,7 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x00000000, LENGTH = 256K
+  /* The last 4K page is left out for settings, see src/settings.rs */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 252K
   RAM : ORIGIN = 0x20000000, LENGTH = 16K
 }
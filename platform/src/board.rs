@@ -0,0 +1,26 @@
+use embassy_nrf::gpio::AnyPin;
+use embassy_nrf::Peri;
+use roc_microbit_core::board::{self, BoardProfile};
+
+/// The robot base this firmware is built for, picked with a cargo feature.
+/// `RobotBase::new` takes any profile, so it could also be picked at runtime.
+#[cfg(not(feature = "board-two-wheel"))]
+pub const PROFILE: BoardProfile = board::KEYESTUDIO_MECANUM;
+#[cfg(feature = "board-two-wheel")]
+pub const PROFILE: BoardProfile = board::TWO_WHEEL;
+
+// `edge_pin` steals the profile's pins, so they must not be ones `main` takes from `p`.
+const _: () = assert!(
+    PROFILE.pins.are_usable(),
+    "The board profile's pins clash with the display, buttons or I2C."
+);
+
+/// Takes the pin on micro:bit edge connector `pin`, as named in a `BoardPins`.
+///
+/// # Safety
+/// Nothing else may be using the pin. `main` leaves the profile's pins alone, and they are
+/// checked against the pins it does use when building.
+pub unsafe fn edge_pin(pin: u8) -> Peri<'static, AnyPin> {
+    let pin_port = board::edge_connector_gpio(pin).expect("Not an edge connector pin.");
+    AnyPin::steal(pin_port)
+}
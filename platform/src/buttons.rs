@@ -0,0 +1,52 @@
+use embassy_nrf::gpio::{Input, Pull};
+use embassy_nrf::{peripherals, Peri};
+use futures::future::{select, Either};
+use futures::pin_mut;
+use roc_microbit_core::wiring::Button;
+
+/// Buttons A and B on the front of the micro:bit. Both read low while pressed.
+pub struct Buttons<'d> {
+    a: Input<'d>,
+    b: Input<'d>,
+}
+
+impl<'d> Buttons<'d> {
+    pub fn new(a: Peri<'d, peripherals::P0_14>, b: Peri<'d, peripherals::P0_23>) -> Buttons<'d> {
+        // The board has its own pull ups on the buttons.
+        Buttons {
+            a: Input::new(a, Pull::None),
+            b: Input::new(b, Pull::None),
+        }
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        match button {
+            Button::A => self.a.is_low(),
+            Button::B => self.b.is_low(),
+        }
+    }
+
+    pub async fn wait_for_release(&mut self, button: Button) {
+        match button {
+            Button::A => self.a.wait_for_high().await,
+            Button::B => self.b.wait_for_high().await,
+        }
+    }
+
+    /// Waits for either button to be pressed and released, and returns which one it was.
+    pub async fn wait_for_press(&mut self) -> Button {
+        let button = {
+            let a = self.a.wait_for_low();
+            let b = self.b.wait_for_low();
+            pin_mut!(a);
+            pin_mut!(b);
+            match select(a, b).await {
+                Either::Left(_) => Button::A,
+                Either::Right(_) => Button::B,
+            }
+        };
+        // Waiting for the release also rides out any contact bounce.
+        self.wait_for_release(button).await;
+        button
+    }
+}
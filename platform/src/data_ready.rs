@@ -0,0 +1,62 @@
+use embassy_nrf::gpio::{AnyPin, Input, Level, Pull};
+use embassy_nrf::Peri;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+/// Waits for a sensor to have a new sample, instead of spinning on its status register.
+/// Sleeps on the sensor's interrupt line when it is wired to the nRF, and otherwise until
+/// the next sample is due at the sensor's output data rate.
+pub struct DataReady<'d> {
+    pin: Option<Input<'d>>,
+    // Level of the line while a sample is waiting.
+    active: Level,
+    period: Duration,
+    next: Instant,
+    // Set when a wait found no sample, like when another chip shares the line and holds it.
+    // The next wait is then timed, so a stuck line can not turn into a busy loop.
+    missed: bool,
+}
+
+impl<'d> DataReady<'d> {
+    pub fn new(pin: Option<Peri<'d, AnyPin>>, active: Level, period_us: u32) -> DataReady<'d> {
+        DataReady {
+            pin: pin.map(|pin| Input::new(pin, Pull::Up)),
+            active,
+            period: Duration::from_micros(period_us as u64),
+            next: Instant::now(),
+            missed: false,
+        }
+    }
+
+    /// Makes the next timed wait a whole period from now, for when a measurement has
+    /// just been started.
+    pub fn restart(&mut self) {
+        self.next = Instant::now() + self.period;
+    }
+
+    pub async fn wait(&mut self) {
+        match self.pin.as_mut() {
+            Some(pin) if !self.missed => {
+                let edge = async {
+                    match self.active {
+                        Level::High => pin.wait_for_high().await,
+                        Level::Low => pin.wait_for_low().await,
+                    }
+                };
+                // In case the interrupt never comes, like while the chip is being reset.
+                let _ = with_timeout(self.period * 2, edge).await;
+                self.next = Instant::now() + self.period;
+            }
+            _ => {
+                Timer::at(self.next).await;
+                // Falling behind skips samples rather than rushing to catch up.
+                self.next = (self.next + self.period).max(Instant::now());
+            }
+        }
+        self.missed = false;
+    }
+
+    /// Tells it the last wait did not find a new sample.
+    pub fn missed(&mut self) {
+        self.missed = true;
+    }
+}
@@ -0,0 +1,112 @@
+use embassy_nrf::gpio::{AnyPin, Flex, OutputDrive, Pull};
+use embassy_nrf::interrupt::typelevel::Binding;
+use embassy_nrf::twim::{self, Twim};
+use embassy_nrf::{bind_interrupts, peripherals, Peri};
+use embassy_time::Delay;
+use embedded_hal_async::i2c::{ErrorType, I2c, Operation, SevenBitAddress};
+use roc_microbit_core::bus;
+
+bind_interrupts!(struct Irqs {
+    TWISPI0 => twim::InterruptHandler<peripherals::TWISPI0>;
+    TWISPI1 => twim::InterruptHandler<peripherals::TWISPI1>;
+});
+
+// Writes of constants come from flash, which the TWIM can't read, so they are copied here
+// first. They are only ever a register and a value or two.
+const TX_BUF_LEN: usize = 16;
+
+/// A TWIM peripheral with its interrupt bound above.
+pub trait Instance: twim::Instance {
+    fn irqs() -> impl Binding<Self::Interrupt, twim::InterruptHandler<Self>>;
+}
+
+impl<T: twim::Instance> Instance for T
+where
+    Irqs: Binding<T::Interrupt, twim::InterruptHandler<T>>,
+{
+    fn irqs() -> impl Binding<T::Interrupt, twim::InterruptHandler<T>> {
+        Irqs
+    }
+}
+
+/// An I2C bus that can clock itself free with plain GPIO when a slave holds it stuck.
+/// The TWIM is set up for each transfer and let go of afterwards, so the pins are always
+/// free for recovery between transfers.
+pub struct TwimBus<'d, T: Instance> {
+    twim: Peri<'d, T>,
+    sda: Peri<'d, AnyPin>,
+    scl: Peri<'d, AnyPin>,
+    tx_buf: [u8; TX_BUF_LEN],
+}
+
+impl<'d, T: Instance> TwimBus<'d, T> {
+    pub fn new(twim: Peri<'d, T>, sda: Peri<'d, AnyPin>, scl: Peri<'d, AnyPin>) -> TwimBus<'d, T> {
+        TwimBus {
+            twim,
+            sda,
+            scl,
+            tx_buf: [0; TX_BUF_LEN],
+        }
+    }
+
+    fn twim(&mut self) -> Twim<'_> {
+        Twim::new(
+            self.twim.reborrow(),
+            T::irqs(),
+            self.sda.reborrow(),
+            self.scl.reborrow(),
+            twim::Config::default(),
+            &mut self.tx_buf,
+        )
+    }
+
+    /// Frees the bus from a slave holding SDA low, see `bus::recover_bus`.
+    /// Devices on it should be set up again afterwards, since they may have been reset.
+    pub fn recover(&mut self) -> bool {
+        let mut scl = open_drain(self.scl.reborrow());
+        let mut sda = open_drain(self.sda.reborrow());
+        let released = bus::recover_bus(&mut scl, &mut sda, &mut Delay).unwrap_or(false);
+        if !released {
+            defmt::warn!("I2C bus is still held low after recovery.");
+        }
+        released
+    }
+}
+
+fn open_drain(pin: Peri<'_, AnyPin>) -> Flex<'_> {
+    let mut pin = Flex::new(pin);
+    pin.set_high();
+    pin.set_as_input_output(Pull::Up, OutputDrive::Standard0Disconnect1);
+    pin
+}
+
+impl<'d, T: Instance> ErrorType for TwimBus<'d, T> {
+    type Error = twim::Error;
+}
+
+impl<'d, T: Instance> I2c<SevenBitAddress> for TwimBus<'d, T> {
+    async fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.twim().read(address, read).await
+    }
+
+    async fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        self.twim().write(address, write).await
+    }
+
+    async fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.twim().write_read(address, write, read).await
+    }
+
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.twim().transaction(address, operations).await
+    }
+}
@@ -0,0 +1,245 @@
+use crate::data_ready::DataReady;
+use crate::i2c_bus::{Instance, TwimBus};
+use crate::lsm303agr::Lsm303agr;
+use embassy_nrf::gpio::{AnyPin, Level};
+use embassy_nrf::{twim, Peri};
+use embassy_time::Instant;
+use roc_microbit_core::accel::{AccelConfig, AccelData, AccelStats, FifoConfig, FIFO_DEPTH};
+use roc_microbit_core::bus::{DeviceHealth, Recovery, Retry};
+use roc_microbit_core::gesture::GestureDetector;
+use roc_microbit_core::mag::{MagCalibration, MagData, MagMode, Mounting};
+use roc_microbit_core::roc::{DeviceStatus, Gesture};
+
+/// The LSM303AGR, kept going through bus errors and brown outs.
+/// While it is unavailable reads return None and the rest of the robot carries on.
+/// Compass reads wait for the next sample, see `DataReady`, and empty the accelerometer's
+/// FIFO on the way.
+pub struct Imu<'d, T: Instance> {
+    lsm: Lsm303agr<Retry<TwimBus<'d, T>>>,
+    health: DeviceHealth,
+    mag_ready: DataReady<'d>,
+    mounting: Mounting,
+    // Latest gravity, for levelling the compass.
+    last_accel: Option<AccelData>,
+    // Every accelerometer sample read since the stats were last taken.
+    accel_stats: AccelStats,
+    gestures: GestureDetector,
+    // Latest gesture not yet taken.
+    gesture: Gesture,
+}
+
+impl<'d, T: Instance> Imu<'d, T> {
+    /// `mag_int` is the pin the magnetometer's data ready signal is wired to, None if it
+    /// is not, which is then polled at its output data rate instead.
+    pub async fn new(
+        i2c: TwimBus<'d, T>,
+        mounting: Mounting,
+        mag_int: Option<Peri<'d, AnyPin>>,
+    ) -> Imu<'d, T> {
+        let lsm = Lsm303agr::new(Retry::new(i2c), mag_int.is_some());
+        let mag_period = lsm.mag_config().odr.period_us();
+        let mut imu = Imu {
+            lsm,
+            health: DeviceHealth::new(),
+            mag_ready: DataReady::new(mag_int, Level::High, mag_period),
+            mounting,
+            last_accel: None,
+            accel_stats: AccelStats::default(),
+            gestures: GestureDetector::new(mounting),
+            gesture: Gesture::None,
+        };
+        let result = imu.lsm.init().await;
+        imu.check(result).await;
+        imu
+    }
+
+    pub fn mounting(&self) -> Mounting {
+        self.mounting
+    }
+
+    pub fn status(&self) -> DeviceStatus {
+        self.health.status()
+    }
+
+    /// Waits for the next magnetometer reading and its heading, None if there was not one.
+    /// Once the accelerometer has been read, the reading is levelled so the heading is
+    /// right on slopes, see `MagData::levelled`.
+    pub async fn mag_heading(&mut self) -> Option<(MagData, f32)> {
+        if !self.wait_for_mag().await {
+            return None;
+        }
+        let result = self.lsm.mag_data().await;
+        let mut data = self.check(result).await?;
+        // Pick up fresh gravity if there is some, but stale gravity is fine for levelling.
+        self.read_accel().await;
+        if let Some(accel) = &self.last_accel {
+            data = data.levelled(accel, self.mounting);
+        }
+        let heading = data.heading();
+        Some((data, heading))
+    }
+
+    /// Waits for the next magnetometer reading, without calibration or levelling.
+    pub async fn mag_raw(&mut self) -> Option<MagData> {
+        if !self.wait_for_mag().await {
+            return None;
+        }
+        let result = self.lsm.mag_raw().await;
+        self.check(result).await
+    }
+
+    /// Whether there is a new magnetometer reading once the next one is due.
+    async fn wait_for_mag(&mut self) -> bool {
+        if self.lsm.mag_config().mode == MagMode::SingleShot && self.health.is_available() {
+            // Nothing is measured until it is asked for.
+            let result = self.lsm.mag_trigger().await;
+            if self.check(result).await.is_some() {
+                self.mag_ready.restart();
+            }
+        }
+        self.mag_ready.wait().await;
+        if !self.ready().await {
+            return false;
+        }
+        let result = self.lsm.mag_ready().await;
+        let ready = self.check(result).await == Some(true);
+        if !ready {
+            self.mag_ready.missed();
+        }
+        ready
+    }
+
+    pub fn mag_calibration(&self) -> MagCalibration {
+        self.lsm.mag_calibration()
+    }
+
+    pub fn set_mag_calibration(&mut self, calibration: MagCalibration) {
+        self.lsm.set_mag_calibration(calibration);
+    }
+
+    /// The latest accelerometer reading without waiting, None if there is no new one.
+    /// With the FIFO on, every sample waiting is read in one burst, and this is the newest.
+    /// Every sample goes into the stats and to the gesture detector.
+    pub async fn read_accel(&mut self) -> Option<AccelData> {
+        if !self.ready().await {
+            return None;
+        }
+        let mut samples = [AccelData::default(); FIFO_DEPTH];
+        let count = if self.lsm.fifo().enabled() {
+            let result = self.lsm.read_fifo(&mut samples).await;
+            let status = self.check(result).await?;
+            if status.overrun {
+                defmt::debug!("Accelerometer FIFO overran, read it more often.");
+            }
+            status.samples
+        } else {
+            let result = self.lsm.accel_ready().await;
+            if !self.check(result).await? {
+                return None;
+            }
+            let result = self.lsm.accel_data().await;
+            samples[0] = self.check(result).await?;
+            1
+        };
+        let samples = &samples[..count];
+        let accel = *samples.last()?;
+        self.last_accel = Some(accel);
+        // The newest sample was taken about now, and the others a sample period apart before it.
+        let now_us = Instant::now().as_micros();
+        let period_us = self.lsm.accel_config().odr.period_us().unwrap_or(0) as u64;
+        let result = self.lsm.engine_sources().await;
+        let sources = self.check(result).await;
+        for (i, sample) in samples.iter().enumerate() {
+            self.accel_stats.add(sample);
+            if let Some(sources) = sources {
+                let age_us = (count - 1 - i) as u64 * period_us;
+                let at_ms = now_us.saturating_sub(age_us) / 1000;
+                if let Some(gesture) = self.gestures.update(sample, sources, at_ms) {
+                    defmt::info!("Gesture: {:?}", gesture);
+                    self.gesture = gesture;
+                }
+            }
+        }
+        Some(accel)
+    }
+
+    /// Peak and RMS of every accelerometer sample read since the last call.
+    pub fn take_accel_stats(&mut self) -> AccelStats {
+        core::mem::take(&mut self.accel_stats)
+    }
+
+    /// The latest gesture since the last call, `Gesture::None` if there was not one.
+    /// Gestures are only seen while the accelerometer is being read.
+    pub fn take_gesture(&mut self) -> Gesture {
+        core::mem::replace(&mut self.gesture, Gesture::None)
+    }
+
+    /// False while the robot has been picked up, dropped or tipped over, see
+    /// `GestureDetector::motors_allowed`. Without the chip there is no telling, so true.
+    pub fn motors_allowed(&self) -> bool {
+        !self.health.is_available() || self.gestures.motors_allowed()
+    }
+
+    /// Changes the accelerometer's rate, range and resolution, see `AccelConfig`.
+    pub async fn set_accel_config(&mut self, config: AccelConfig) {
+        let result = self.lsm.set_accel_config(config).await;
+        self.check(result).await;
+    }
+
+    /// Turns the accelerometer FIFO on or off, see `FifoConfig`.
+    /// Meant for sampling fast enough to catch bumps without a read for every sample.
+    pub async fn set_fifo(&mut self, fifo: FifoConfig) {
+        let result = self.lsm.set_fifo(fifo).await;
+        self.check(result).await;
+    }
+
+    /// Whether the chip can be read right now. Sets it up again first if it was unavailable
+    /// and is due another try, or if it has been reset by a brown out.
+    async fn ready(&mut self) -> bool {
+        let now = Instant::now().as_millis();
+        if !self.health.should_try(now) {
+            return false;
+        }
+        let result = if !self.health.is_available() {
+            self.lsm.bus_mut().inner_mut().recover();
+            self.lsm.init().await
+        } else if self.health.reset_check_due(now) {
+            match self.lsm.was_reset().await {
+                Ok(true) => {
+                    defmt::warn!("LSM303AGR was reset, setting it up again.");
+                    self.lsm.init().await
+                }
+                other => other.map(|_| ()),
+            }
+        } else {
+            return true;
+        };
+        self.check(result).await.is_some()
+    }
+
+    /// Updates the chip's health with the result of talking to it.
+    async fn check<R>(&mut self, result: Result<R, twim::Error>) -> Option<R> {
+        let e = match result {
+            Ok(value) => {
+                if self.health.succeeded() {
+                    defmt::info!("LSM303AGR is back.");
+                }
+                return Some(value);
+            }
+            Err(e) => e,
+        };
+        match self.health.failed(Instant::now().as_millis()) {
+            Recovery::Reinit => {
+                defmt::warn!("LSM303AGR failed: {:?}, recovering the bus.", e);
+                self.lsm.bus_mut().inner_mut().recover();
+                if self.lsm.init().await.is_err() {
+                    self.health.failed(Instant::now().as_millis());
+                }
+            }
+            Recovery::Degraded => {
+                defmt::warn!("LSM303AGR failed: {:?}, carrying on without it.", e)
+            }
+        }
+        None
+    }
+}
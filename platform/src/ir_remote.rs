@@ -0,0 +1,51 @@
+use core::cell::RefCell;
+use embassy_nrf::gpio::{AnyPin, Input, Pull};
+use embassy_nrf::Peri;
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_time::Instant;
+use roc_microbit_core::ir::{IrCode, IrDecoder};
+use roc_microbit_core::roc::IrEvent;
+
+// Shared between the receiver task and the main loop.
+static DECODER: CriticalSectionMutex<RefCell<IrDecoder>> =
+    CriticalSectionMutex::new(RefCell::new(IrDecoder::new()));
+
+fn with_decoder<R>(f: impl FnOnce(&mut IrDecoder) -> R) -> R {
+    DECODER.lock(|decoder| f(&mut decoder.borrow_mut()))
+}
+
+/// The IR receiver on the robot base.
+/// Its output idles high and goes low while it sees a 38kHz carrier.
+pub struct IrRemote<'d> {
+    receiver: Input<'d>,
+}
+
+impl<'d> IrRemote<'d> {
+    pub fn new(receiver: Peri<'d, AnyPin>) -> IrRemote<'d> {
+        IrRemote {
+            receiver: Input::new(receiver, Pull::Up),
+        }
+    }
+
+    /// Decodes every edge as it arrives. Never returns, so run it in its own task.
+    pub async fn watch(&mut self) {
+        loop {
+            self.receiver.wait_for_any_edge().await;
+            let at_us = Instant::now().as_micros();
+            let mark = self.receiver.is_low();
+            if let Some(code) = with_decoder(|decoder| decoder.edge(at_us, mark)) {
+                defmt::debug!("IR: {:?}", code);
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn ir_remote_task(mut remote: IrRemote<'static>) {
+    remote.watch().await
+}
+
+/// Whether a key was pressed or held since the last call, and the last key seen.
+pub fn take_event() -> (IrEvent, Option<IrCode>) {
+    with_decoder(|decoder| (decoder.take_event(), decoder.last()))
+}
@@ -0,0 +1,101 @@
+use core::cell::RefCell;
+use embassy_nrf::gpio::{AnyPin, Input, Pull};
+use embassy_nrf::Peri;
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_time::{Instant, Timer};
+use futures::future::{join, select, Either};
+use futures::pin_mut;
+use roc_microbit_core::line::{LineEdges, Side, DEFAULT_DEBOUNCE_US};
+use roc_microbit_core::roc::LightLevel;
+
+// Shared between the watcher task and the main loop.
+static EDGES: CriticalSectionMutex<RefCell<LineEdges>> =
+    CriticalSectionMutex::new(RefCell::new(LineEdges::new(DEFAULT_DEBOUNCE_US)));
+
+fn with_edges<R>(f: impl FnOnce(&mut LineEdges) -> R) -> R {
+    EDGES.lock(|edges| f(&mut edges.borrow_mut()))
+}
+
+/// The line sensors, watched with GPIOTE so that lines narrower than a main loop tick
+/// are still counted.
+pub struct LineSensors<'d> {
+    left: Input<'d>,
+    right: Input<'d>,
+}
+
+impl<'d> LineSensors<'d> {
+    pub fn new(left: Peri<'d, AnyPin>, right: Peri<'d, AnyPin>) -> LineSensors<'d> {
+        LineSensors {
+            left: Input::new(left, Pull::Down),
+            right: Input::new(right, Pull::Down),
+        }
+    }
+
+    /// Records debounced transitions on both sensors. Never returns, so run it in its own task.
+    pub async fn watch(&mut self) {
+        // Pick up the starting levels without counting them.
+        with_edges(|edges| {
+            let now = Instant::now().as_micros();
+            edges.settle(Side::Left, now, level(&self.left));
+            edges.settle(Side::Right, now, level(&self.right));
+            edges.take_transitions();
+            while edges.pop_event().is_some() {}
+        });
+        join(
+            watch_sensor(Side::Left, &mut self.left),
+            watch_sensor(Side::Right, &mut self.right),
+        )
+        .await;
+    }
+}
+
+#[embassy_executor::task]
+pub async fn line_sensor_task(mut sensors: LineSensors<'static>) {
+    sensors.watch().await
+}
+
+/// Debounced level of a sensor.
+pub fn light(side: Side) -> LightLevel {
+    with_edges(|edges| edges.level(side))
+}
+
+/// Left and right transition counts since the last call.
+pub fn take_transitions() -> (u8, u8) {
+    with_edges(|edges| edges.take_transitions())
+}
+
+fn level(pin: &Input<'_>) -> LightLevel {
+    if pin.is_low() {
+        LightLevel::Bright
+    } else {
+        LightLevel::Dark
+    }
+}
+
+async fn watch_sensor(side: Side, pin: &mut Input<'_>) {
+    let now = || Instant::now().as_micros();
+    loop {
+        // An edge could have come in while the level was being read, so check before waiting.
+        if level(pin) == with_edges(|edges| edges.level(side)) {
+            pin.wait_for_any_edge().await;
+        }
+        with_edges(|edges| edges.edge(side, now()));
+        // Keep taking edges until the pin has been quiet for the debounce time.
+        while let Some(settle_at) = with_edges(|edges| edges.settle_at_us(side)) {
+            let quiet = {
+                let edge = pin.wait_for_any_edge();
+                pin_mut!(edge);
+                let timeout = Timer::at(Instant::from_micros(settle_at));
+                matches!(select(timeout, edge).await, Either::Left(_))
+            };
+            if quiet {
+                let event = with_edges(|edges| edges.settle(side, now(), level(pin)));
+                if let Some(event) = event {
+                    defmt::debug!("Line: {:?}", event);
+                }
+            } else {
+                with_edges(|edges| edges.edge(side, now()));
+            }
+        }
+    }
+}
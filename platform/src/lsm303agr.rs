@@ -1,43 +1,182 @@
-use defmt::Format;
-use embassy::time::Instant;
-use embassy_nrf::twim;
+use embedded_hal_async::i2c::I2c;
+use roc_microbit_core::accel::{AccelConfig, AccelData, FifoConfig, FifoStatus, FIFO_DEPTH};
+use roc_microbit_core::gesture::{EngineRegisters, EngineSources};
+use roc_microbit_core::mag::{MagCalibration, MagConfig, MagData, MagMode};
 
-// const ACCEL_ADDR: u8 = 0b0011001;
+const ACCEL_ADDR: u8 = 0b0011001;
 const MAG_ADDR: u8 = 0b0011110;
 
+const CTRL_REG1_A: u8 = 0x20;
+const CTRL_REG3_A: u8 = 0x22;
+const CTRL_REG4_A: u8 = 0x23;
+const CTRL_REG5_A: u8 = 0x24;
+const CTRL_REG6_A: u8 = 0x25;
+const STATUS_REG_A: u8 = 0x27;
+const OUT_X_L_A: u8 = 0x28;
+const FIFO_CTRL_REG_A: u8 = 0x2E;
+const FIFO_SRC_REG_A: u8 = 0x2F;
+const INT1_CFG_A: u8 = 0x30;
+const INT1_SRC_A: u8 = 0x31;
+const INT1_THS_A: u8 = 0x32;
+const INT1_DURATION_A: u8 = 0x33;
+const INT2_CFG_A: u8 = 0x34;
+const INT2_THS_A: u8 = 0x36;
+const INT2_DURATION_A: u8 = 0x37;
+
 const CFG_REG_A_M: u8 = 0x60;
 const CFG_REG_B_M: u8 = 0x61;
-// const CFG_REG_C_M: u8 = 0x62;
+const CFG_REG_C_M: u8 = 0x62;
 const STATUS_REG_M: u8 = 0x67;
 const OUT_BASE_REG_M: u8 = 0x68;
 
-#[derive(Format)]
-pub struct MagData {
-    pub x: i32,
-    // TODO: Maybe re-add y, but it is not used for the current robot or calibrated.
-    // pub y: i32,
-    pub z: i32,
-}
+// INT1 and INT2 active low.
+const H_LACTIVE: u8 = 0b00000010;
+// Magnetometer data ready on INT_MAG/DRDY.
+const INT_MAG: u8 = 0b00000001;
 
-pub struct Lsm303agr<'d, T: twim::Instance> {
-    i2c: twim::Twim<'d, T>,
+pub struct Lsm303agr<I> {
+    i2c: I,
+    accel_config: AccelConfig,
+    fifo: FifoConfig,
+    mag_config: MagConfig,
+    mag_calibration: MagCalibration,
+    mag_data_ready: bool,
 }
-impl<'d, T: twim::Instance> Lsm303agr<'d, T> {
-    pub async fn new(mut i2c: twim::Twim<'d, T>) -> Result<Lsm303agr<'d, T>, twim::Error> {
-        // Set to continous mode with high resolution and 100Hz ODR.
-        i2c.write(MAG_ADDR, &[CFG_REG_A_M, 0b00001100]).await?;
-        // Enable low pass filter.
-        i2c.write(MAG_ADDR, &[CFG_REG_B_M, 0b00000001]).await?;
-        // Uncalibrate the magnometer. We will offset and scale it in the code here.
-        // TODO: setup accelometer.
+impl<I: I2c> Lsm303agr<I> {
+    /// `init` has to be called before reading anything.
+    /// `mag_data_ready` drives the magnetometer's data ready onto INT_MAG/DRDY, active high.
+    pub fn new(i2c: I, mag_data_ready: bool) -> Lsm303agr<I> {
+        Lsm303agr {
+            i2c,
+            mag_data_ready,
+            accel_config: AccelConfig::default(),
+            fifo: FifoConfig::default(),
+            mag_config: MagConfig::default(),
+            mag_calibration: MagCalibration::default(),
+        }
+    }
+
+    pub fn bus_mut(&mut self) -> &mut I {
+        &mut self.i2c
+    }
+
+    pub async fn init(&mut self) -> Result<(), I::Error> {
+        // The magnetometer's offset registers are left alone, it is offset and scaled
+        // by `MagCalibration` in code instead.
+        self.write_mag_config().await?;
+        // Data ready on the interrupt pin, for waiting on instead of polling.
+        let int_mag = if self.mag_data_ready { INT_MAG } else { 0 };
+        self.i2c.write(MAG_ADDR, &[CFG_REG_C_M, int_mag]).await?;
+        self.i2c
+            .write(ACCEL_ADDR, &[CTRL_REG6_A, H_LACTIVE])
+            .await?;
+        self.write_accel_config().await
+    }
 
-        Ok(Lsm303agr { i2c })
+    async fn write_mag_config(&mut self) -> Result<(), I::Error> {
+        let config = self.mag_config;
+        // Filters first, so the first measurement already uses them.
+        self.i2c
+            .write(MAG_ADDR, &[CFG_REG_B_M, config.cfg_reg_b()])
+            .await?;
+        self.i2c
+            .write(MAG_ADDR, &[CFG_REG_A_M, config.cfg_reg_a()])
+            .await
+    }
+
+    async fn write_accel_config(&mut self) -> Result<(), I::Error> {
+        let config = self.accel_config;
+        self.i2c
+            .write(ACCEL_ADDR, &[CTRL_REG4_A, config.ctrl_reg4()])
+            .await?;
+        self.i2c
+            .write(ACCEL_ADDR, &[CTRL_REG1_A, config.ctrl_reg1()])
+            .await?;
+        // The gesture engines count in steps of the range and data rate.
+        let engines = EngineRegisters::new(&config);
+        for (reg, value) in [
+            // Nothing on INT1, the FIFO is read along with the compass instead.
+            (CTRL_REG3_A, 0),
+            (CTRL_REG5_A, engines.ctrl_reg5 | self.fifo.ctrl_reg5()),
+            (INT1_THS_A, engines.int1_ths),
+            (INT1_DURATION_A, engines.int1_duration),
+            (INT1_CFG_A, engines.int1_cfg),
+            (INT2_THS_A, engines.int2_ths),
+            (INT2_DURATION_A, engines.int2_duration),
+            (INT2_CFG_A, engines.int2_cfg),
+        ] {
+            self.i2c.write(ACCEL_ADDR, &[reg, value]).await?;
+        }
+        self.restart_fifo().await
     }
 
-    pub async fn mag_ready(&mut self) -> Result<bool, twim::Error> {
+    /// Empties the FIFO and starts it filling again, by way of bypass mode.
+    async fn restart_fifo(&mut self) -> Result<(), I::Error> {
+        let bypass = FifoConfig::BYPASS.fifo_ctrl_reg();
+        self.i2c
+            .write(ACCEL_ADDR, &[FIFO_CTRL_REG_A, bypass])
+            .await?;
+        if !self.fifo.enabled() {
+            return Ok(());
+        }
+        self.i2c
+            .write(ACCEL_ADDR, &[FIFO_CTRL_REG_A, self.fifo.fifo_ctrl_reg()])
+            .await
+    }
+
+    pub fn fifo(&self) -> FifoConfig {
+        self.fifo
+    }
+
+    /// Turns the FIFO on or off. While it is on, read with `read_fifo` instead of
+    /// `accel_data`. Kept across `init`, so it survives the chip being reset.
+    pub async fn set_fifo(&mut self, fifo: FifoConfig) -> Result<(), I::Error> {
+        self.fifo = fifo;
+        self.write_accel_config().await
+    }
+
+    /// Reads every sample waiting in the FIFO into `samples`, oldest first, in one burst. Returns how full it was.
+    pub async fn read_fifo(
+        &mut self,
+        samples: &mut [AccelData; FIFO_DEPTH],
+    ) -> Result<FifoStatus, I::Error> {
+        let mut src = [0];
+        self.i2c
+            .write_read(ACCEL_ADDR, &[FIFO_SRC_REG_A], &mut src)
+            .await?;
+        let status = FifoStatus::from_src_reg(src[0]);
+        if status.samples > 0 {
+            // The address wraps back to OUT_X_L_A after each sample while the FIFO is on.
+            let mut data = [0; FIFO_DEPTH * 6];
+            let data = &mut data[..status.samples * 6];
+            self.i2c
+                .write_read(ACCEL_ADDR, &[OUT_X_L_A | 0x80], data)
+                .await?;
+            for (sample, raw) in samples.iter_mut().zip(data.chunks_exact(6)) {
+                *sample = AccelData::from_raw(raw.try_into().unwrap(), &self.accel_config);
+            }
+        }
+        if status.overrun && self.fifo.stops_when_full() {
+            self.restart_fifo().await?;
+        }
+        Ok(status)
+    }
+
+    pub fn accel_config(&self) -> AccelConfig {
+        self.accel_config
+    }
+
+    /// Changes the accelerometer's rate, range and resolution.
+    /// Kept across `init`, so it survives the chip being reset.
+    pub async fn set_accel_config(&mut self, config: AccelConfig) -> Result<(), I::Error> {
+        self.accel_config = config;
+        self.write_accel_config().await
+    }
+
+    pub async fn accel_ready(&mut self) -> Result<bool, I::Error> {
         let mut data = [0];
         self.i2c
-            .write_read(MAG_ADDR, &[STATUS_REG_M], &mut data)
+            .write_read(ACCEL_ADDR, &[STATUS_REG_A], &mut data)
             .await?;
 
         // zyx data available.
@@ -45,164 +184,91 @@ impl<'d, T: twim::Instance> Lsm303agr<'d, T> {
         Ok(data[0] & zyxda == zyxda)
     }
 
-    pub async fn mag_data(&mut self) -> Result<MagData, twim::Error> {
+    /// Latest acceleration in milli-g.
+    pub async fn accel_data(&mut self) -> Result<AccelData, I::Error> {
         let mut data = [0; 6];
         self.i2c
-            .write_read(MAG_ADDR, &[OUT_BASE_REG_M | 0x80], &mut data)
+            .write_read(ACCEL_ADDR, &[OUT_X_L_A | 0x80], &mut data)
+            .await?;
+        Ok(AccelData::from_raw(&data, &self.accel_config))
+    }
+
+    /// What the free fall and 6D engines have seen, see `gesture`.
+    /// Reading clears the latched free fall.
+    pub async fn engine_sources(&mut self) -> Result<EngineSources, I::Error> {
+        // INT1_SRC_A through INT2_SRC_A in one go.
+        let mut data = [0; 5];
+        self.i2c
+            .write_read(ACCEL_ADDR, &[INT1_SRC_A | 0x80], &mut data)
             .await?;
-        let x = (u16::from(data[0]) | (u16::from(data[1]) << 8)) as i16;
-        // let y = (u16::from(data[2]) | (u16::from(data[3]) << 8)) as i16;
-        let z = (u16::from(data[4]) | (u16::from(data[5]) << 8)) as i16;
-        // These need to be scaled by 1.5 to be converted from raw to milliGuass.
-        // We also convert them from milliGauss to nanoTesla by multiplying by 100.
-        // This leads to times 150.
-        let scaled_x = x as i32 * 150;
-        // let scaled_y = y as i32 * 150;
-        let scaled_z = z as i32 * 150;
-
-        // Finally apply hard and soft iron calibration.
-        // These were calculated with this method: https://www.appelsiini.net/2018/calibrate-magnetometer/
-        // Center: (77325, -11700.0)
-        // Scale: (0.9636118598382749, 1.0392441860465116)
-        // Note, the USB cable definitely affects the hard iron offset...so this is probably off by a few thousand.
-        // Staying in interger since the numbers are between +/-35,000
-        let calibrated_x = ((scaled_x - 77325) * 09_636) / 10_000;
-        let calibrated_z = ((scaled_z + 11700) * 11_700) / 10_000;
-        Ok(MagData {
-            x: calibrated_x,
-            // y: scaled_y,
-            z: calibrated_z,
+        Ok(EngineSources {
+            int1_src: data[0],
+            int2_src: data[4],
         })
     }
 
-    pub async fn mag_heading(&mut self) -> Result<(MagData, f32), twim::Error> {
-        let data = self.mag_data().await?;
-        let heading = libm::atan2f(data.x as f32, data.z as f32);
-        Ok((data, heading))
+    /// Whether the chip has been power cycled since `init`.
+    /// Both sensors come back powered down, which one of them will not be configured as.
+    pub async fn was_reset(&mut self) -> Result<bool, I::Error> {
+        let mut data = [0];
+        self.i2c
+            .write_read(MAG_ADDR, &[CFG_REG_A_M], &mut data)
+            .await?;
+        if !self.mag_config.matches_cfg_reg_a(data[0]) {
+            return Ok(true);
+        }
+        self.i2c
+            .write_read(ACCEL_ADDR, &[CTRL_REG1_A], &mut data)
+            .await?;
+        Ok(data[0] != self.accel_config.ctrl_reg1())
     }
-}
 
-// Where would I add the fact that magnitude = sqrt(x*x+z*z)?
-// Would this require adding x and z as state variables?
-// Actually I think it may require making magnitude a measurement?
-
-// We currently don't have any other angle sensors, so this will just estimate by itself.
-// Once we have wheel encoders, it should be possible to get a pretty noisy delta angle reading.
-// This is a Kalman filter that will go from x, z to the angle of heading.
-pub struct MagFilter {
-    // State is tracking angle, delta_angle, magnitude, x_bias, z_bias, x_scale, z_scale.
-    last_t: Instant,
-    x: na::SVector<f32, 7>,    // State Vector
-    p: na::SMatrix<f32, 7, 7>, // Estimate Uncertainty
-    q: na::SMatrix<f32, 7, 7>, // Process Noise Uncertainty
-    r: na::SMatrix<f32, 2, 2>, // Measurement Uncertainty
-}
+    pub fn mag_config(&self) -> MagConfig {
+        self.mag_config
+    }
 
-impl MagFilter {
-    pub fn new(mag_full: (MagData, f32)) -> MagFilter {
-        let (mag_data, heading) = mag_full;
-        let x = mag_data.x as f32;
-        let z = mag_data.z as f32;
-        let magnitude = libm::sqrtf(x * x + z * z);
-        MagFilter {
-            last_t: Instant::now(),
-            x: na::SVector::from([heading, 0.0, magnitude, 0.0, 0.0, 1.0, 1.0]),
-            // TODO: Actually setup/tune below values this.
-            #[rustfmt::skip]
-            p: na::SMatrix::from_row_slice(&[
-                1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
-                0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0,
-                0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0,
-                0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0,
-                0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0,
-                0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0,
-                0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0,
-            ]),
-            #[rustfmt::skip]
-            q: na::SMatrix::from_row_slice(&[
-                1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
-                0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0,
-                0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0,
-                0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0,
-                0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0,
-                0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0,
-                0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0,
-            ]),
-            #[rustfmt::skip]
-            r: na::SMatrix::from_row_slice(&[
-                1.0, 0.0,
-                0.0, 1.0,
-            ]),
-        }
+    /// Starts a measurement in single shot mode. `mag_ready` says when it is done.
+    pub async fn mag_trigger(&mut self) -> Result<(), I::Error> {
+        let config = MagConfig {
+            mode: MagMode::SingleShot,
+            ..self.mag_config
+        };
+        self.i2c
+            .write(MAG_ADDR, &[CFG_REG_A_M, config.cfg_reg_a()])
+            .await
+    }
+
+    pub fn mag_calibration(&self) -> MagCalibration {
+        self.mag_calibration
+    }
+
+    pub fn set_mag_calibration(&mut self, calibration: MagCalibration) {
+        self.mag_calibration = calibration;
+    }
+
+    pub async fn mag_ready(&mut self) -> Result<bool, I::Error> {
+        let mut data = [0];
+        self.i2c
+            .write_read(MAG_ADDR, &[STATUS_REG_M], &mut data)
+            .await?;
+
+        // zyx data available.
+        let zyxda = 0b00001000;
+        Ok(data[0] & zyxda == zyxda)
+    }
+
+    /// Latest field in nanoTesla, without the calibration, for calibrating.
+    pub async fn mag_raw(&mut self) -> Result<MagData, I::Error> {
+        let mut data = [0; 6];
+        self.i2c
+            .write_read(MAG_ADDR, &[OUT_BASE_REG_M | 0x80], &mut data)
+            .await?;
+        Ok(MagData::from_raw(&data))
     }
 
-    fn predict(&mut self) {
-        // State Update:
-        // angle = angle + delta_angle * dt
-        // Everything else is constant
-        let dt = self.last_t.elapsed();
-        self.last_t = Instant::now();
-        let mut f = na::SMatrix::<f32, 7, 7>::identity();
-        // angle, delta_angle = dt
-        f[(0, 1)] = dt.as_micros() as f32 / 1_000_000.0;
-
-        // This function is linear so it is fine to directly apply.
-        // Normally, f would be the jacobian and we would have to directly update x.
-        self.x = f * self.x;
-        self.p = f * self.p * f.transpose() + self.q;
-    }
-
-    fn update(&mut self, mag_full: &(MagData, f32)) -> na::SVector<f32, 7> {
-        let (mag_data, heading) = mag_full;
-        let angle = self.x[0];
-        // let dangle = self.x[1];
-        let magnitude = self.x[2];
-        let x_bias = self.x[3];
-        let z_bias = self.x[4];
-        let x_scale = self.x[5];
-        let z_scale = self.x[6];
-        let sin_angle = libm::sinf(angle);
-        let cos_angle = libm::cosf(angle);
-        // Measurement equations:
-        // x = (magnitude * sin(angle) * x_scale) + x_bias
-        // z = (magnitude * cos(angle) * z_scale) + z_bias
-        let hx = na::SVector::from([
-            (magnitude * sin_angle * x_scale) + x_bias,
-            (magnitude * cos_angle * z_scale) + z_bias,
-        ]);
-        // Measurement jacobian.
-        #[rustfmt::skip]
-        let h = na::SMatrix::<f32, 2, 7>::from_row_slice(&[
-            // dx/d_angle = magnitude * cos(angle) * x_scale
-            // dz/d_angle = magnitude * -1 * sin(angle) * z_scale
-            magnitude * cos_angle * x_scale, magnitude * -1.0 * sin_angle * z_scale,
-            // dx/d2_angle = 0: Should this actually be the second deravitive of the above?
-            0.0, 0.0,
-            // dx/d_magnitude = sin(angle) * x_scale
-            // dz/d_magnitude = cos(angle) * z_scale
-            sin_angle * x_scale, cos_angle * z_scale,
-            // dx/d_x_bias = 1
-            1.0, 0.0,
-            // dz/d_z_bias = 1
-            0.0, 1.0,
-            // dx/d_x_scale = magnitide * sin(angle)
-            magnitude * sin_angle, 0.0,
-            // dz/d_z_scale = magnitide * cos(angle)
-            0.0, magnitude * cos_angle,
-        ]);
-
-        // TODO: investigate inverse, maybe use pseudo inverse?
-        let k =
-            self.p * h.transpose() * (h * self.p * h.transpose() + self.r).try_inverse().unwrap();
-        let t = na::SMatrix::identity() - k * h;
-        self.p = t * self.p * t.transpose() + k * self.r * k.transpose();
-        let z = na::SVector::from([mag_data.x as f32, mag_data.z as f32]);
-        self.x = self.x + k * (z - hx);
-        self.x
-    }
-
-    pub fn predict_and_update(&mut self, mag_full: &(MagData, f32)) -> na::SVector<f32, 7> {
-        self.predict();
-        self.update(mag_full)
+    /// Latest field in nanoTesla, with the calibration applied.
+    pub async fn mag_data(&mut self) -> Result<MagData, I::Error> {
+        let data = self.mag_raw().await?;
+        Ok(self.mag_calibration.apply(&data))
     }
 }
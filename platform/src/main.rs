@@ -1,91 +1,86 @@
 #![no_main]
 #![no_std]
-#![feature(type_alias_impl_trait)]
 
-use defmt::Format;
-use embassy::executor::Spawner;
-use embassy::time::{Duration, Instant, Timer};
-use embassy_nrf::gpio::{AnyPin, Level, Output, OutputDrive, Pin};
-use embassy_nrf::{interrupt, peripherals, twim, Peripherals};
+use embassy_executor::Spawner;
+use embassy_nrf::gpio::{Level, Output, OutputDrive};
+use embassy_nrf::{bind_interrupts, peripherals, temp, Peri};
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use futures::future::join;
 
+mod board;
+mod buttons;
+mod data_ready;
 mod fmt;
+mod i2c_bus;
+mod imu;
+mod ir_remote;
+mod line_sensors;
 mod lsm303agr;
 mod memory;
 pub mod robot_base;
+mod self_test;
+mod settings;
 
-use robot_base::{Direction, LightLevel, RobotBase};
+use buttons::Buttons;
+use embassy_nrf::nvmc::Nvmc;
+use i2c_bus::TwimBus;
+use imu::Imu;
+use ir_remote::IrRemote;
+use line_sensors::LineSensors;
+use robot_base::{RobotBase, SharedRobotBase};
+use roc_microbit_core::accel::{AccelConfig, AccelOdr, FifoConfig};
+use roc_microbit_core::display::DisplayData;
+use roc_microbit_core::line::Side;
+use roc_microbit_core::mag::MagFilter;
+use roc_microbit_core::roc::{
+    DeviceStatus, Gesture, GuardStatus, IrEvent, LightLevel, Pose, RocInput, RocOutput, ScanMap,
+};
+use roc_microbit_core::scan::ScanConfig;
+use roc_microbit_core::wiring::Button;
+use static_cell::StaticCell;
 
-#[repr(C)]
-#[derive(Default)]
-struct Row {
-    a: u8,
-    b: u8,
-    c: u8,
-    d: u8,
-    e: u8,
-}
-
-#[repr(C)]
-#[derive(Default)]
-struct DisplayData {
-    a: Row,
-    b: Row,
-    c: Row,
-    d: Row,
-    e: Row,
-}
+bind_interrupts!(struct Irqs {
+    TEMP => temp::InterruptHandler;
+});
 
-impl DisplayData {
-    fn to_bytes(&self) -> [[u8; 5]; 5] {
-        [
-            [self.a.a, self.a.b, self.a.c, self.a.d, self.a.e],
-            [self.b.a, self.b.b, self.b.c, self.b.d, self.b.e],
-            [self.c.a, self.c.b, self.c.c, self.c.d, self.c.e],
-            [self.d.a, self.d.b, self.d.c, self.d.d, self.d.e],
-            [self.e.a, self.e.b, self.e.c, self.e.d, self.e.e],
-        ]
-    }
-}
+const DEFAULT_DELAY_MS: u64 = 2;
 
-impl defmt::Format for DisplayData {
-    fn format(&self, f: defmt::Formatter) {
-        defmt::write!(f, "{:?}", self.to_bytes())
-    }
-}
+static ROBOT_BASE: StaticCell<SharedRobotBase> = StaticCell::new();
 
-const DEFAULT_DELAY_MS: u64 = 2;
 struct Display<'d> {
-    cols: [Output<'d, AnyPin>; 5],
-    rows: [Output<'d, AnyPin>; 5],
+    cols: [Output<'d>; 5],
+    rows: [Output<'d>; 5],
 }
 
 impl<'d> Display<'d> {
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        p0_28: peripherals::P0_28,
-        p0_11: peripherals::P0_11,
-        p0_31: peripherals::P0_31,
-        p1_05: peripherals::P1_05,
-        p0_30: peripherals::P0_30,
-        p0_21: peripherals::P0_21,
-        p0_22: peripherals::P0_22,
-        p0_15: peripherals::P0_15,
-        p0_24: peripherals::P0_24,
-        p0_19: peripherals::P0_19,
+        p0_28: Peri<'d, peripherals::P0_28>,
+        p0_11: Peri<'d, peripherals::P0_11>,
+        p0_31: Peri<'d, peripherals::P0_31>,
+        p1_05: Peri<'d, peripherals::P1_05>,
+        p0_30: Peri<'d, peripherals::P0_30>,
+        p0_21: Peri<'d, peripherals::P0_21>,
+        p0_22: Peri<'d, peripherals::P0_22>,
+        p0_15: Peri<'d, peripherals::P0_15>,
+        p0_24: Peri<'d, peripherals::P0_24>,
+        p0_19: Peri<'d, peripherals::P0_19>,
     ) -> Display<'d> {
         Display {
             cols: [
-                Output::new(p0_28.degrade(), Level::High, OutputDrive::Standard),
-                Output::new(p0_11.degrade(), Level::High, OutputDrive::Standard),
-                Output::new(p0_31.degrade(), Level::High, OutputDrive::Standard),
-                Output::new(p1_05.degrade(), Level::High, OutputDrive::Standard),
-                Output::new(p0_30.degrade(), Level::High, OutputDrive::Standard),
+                Output::new(p0_28, Level::High, OutputDrive::Standard),
+                Output::new(p0_11, Level::High, OutputDrive::Standard),
+                Output::new(p0_31, Level::High, OutputDrive::Standard),
+                Output::new(p1_05, Level::High, OutputDrive::Standard),
+                Output::new(p0_30, Level::High, OutputDrive::Standard),
             ],
             rows: [
-                Output::new(p0_21.degrade(), Level::Low, OutputDrive::Standard),
-                Output::new(p0_22.degrade(), Level::Low, OutputDrive::Standard),
-                Output::new(p0_15.degrade(), Level::Low, OutputDrive::Standard),
-                Output::new(p0_24.degrade(), Level::Low, OutputDrive::Standard),
-                Output::new(p0_19.degrade(), Level::Low, OutputDrive::Standard),
+                Output::new(p0_21, Level::Low, OutputDrive::Standard),
+                Output::new(p0_22, Level::Low, OutputDrive::Standard),
+                Output::new(p0_15, Level::Low, OutputDrive::Standard),
+                Output::new(p0_24, Level::Low, OutputDrive::Standard),
+                Output::new(p0_19, Level::Low, OutputDrive::Standard),
             ],
         }
     }
@@ -114,92 +109,223 @@ impl<'d> Display<'d> {
     }
 }
 
-#[repr(C)]
-#[derive(Format, Default, Clone)]
-struct RocInput {
-    state: u64,
-    light_left: LightLevel,
-    light_right: LightLevel,
-}
-
-#[repr(C)]
-#[derive(Format, Default)]
-struct RocOutput {
-    delay_ms: u64,
-    state: u64,
-    display: DisplayData,
-    speed_left: i8,
-    speed_right: i8,
-}
-
 fn roc_main(input: RocInput) -> RocOutput {
     #[link(name = "app")]
     extern "C" {
         #[link_name = "roc__mainForHost_1_exposed_generic"]
-        fn call(state: u64, light_left: LightLevel, light_right: LightLevel, out: &mut RocOutput);
+        fn call(
+            state: u64,
+            distance_age_ms: u32,
+            distance_mm: u32,
+            pose: Pose,
+            scan: ScanMap,
+            accel_peak_mg: u16,
+            accel_rms_mg: u16,
+            ir_address: u16,
+            distance_valid: bool,
+            gesture: Gesture,
+            guard: GuardStatus,
+            imu: DeviceStatus,
+            ir_command: u8,
+            ir_event: IrEvent,
+            light_left: LightLevel,
+            light_right: LightLevel,
+            motors: DeviceStatus,
+            transitions_left: u8,
+            transitions_right: u8,
+            out: &mut RocOutput,
+        );
     }
     let mut out: RocOutput = Default::default();
-    unsafe { call(input.state, input.light_left, input.light_right, &mut out) };
+    unsafe {
+        call(
+            input.state,
+            input.distance_age_ms,
+            input.distance_mm,
+            input.pose,
+            input.scan,
+            input.accel_peak_mg,
+            input.accel_rms_mg,
+            input.ir_address,
+            input.distance_valid,
+            input.gesture,
+            input.guard,
+            input.imu,
+            input.ir_command,
+            input.ir_event,
+            input.light_left,
+            input.light_right,
+            input.motors,
+            input.transitions_left,
+            input.transitions_right,
+            &mut out,
+        )
+    };
     out
 }
 
 // So I think the goal is going to be multithreaded sensor reading and output.
 // This way, we can both display images continuously on the display and read sonar/lidar with decent accuracy.
 // Of course, some of that can be offloaded to sensors that just continously scan for us.
-#[embassy::main]
-async fn main(_spawner: Spawner, p: Peripherals) {
-    let irq0 = interrupt::take!(SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0);
-    let i2c0 = twim::Twim::new(p.TWISPI0, irq0, p.P0_16, p.P0_08, twim::Config::default());
-    let mut imu = lsm303agr::Lsm303agr::new(i2c0).await.unwrap();
-
-    let irq1 = interrupt::take!(SPIM1_SPIS1_TWIM1_TWIS1_SPI1_TWI1);
-    let i2c1 = twim::Twim::new(p.TWISPI1, irq1, p.P1_00, p.P0_26, twim::Config::default());
-    let mut robot_base = RobotBase::new(i2c1, p.P0_03, p.P0_04, p.P0_13, p.P1_02, p.P0_01, p.PWM0)
-        .await
-        .expect("Failed to initialize robot base.");
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_nrf::init(Default::default());
+    let i2c0 = TwimBus::new(p.TWISPI0, p.P0_16.into(), p.P0_08.into());
+    // Neither I2C device stops the firmware if it is missing, Roc is told instead.
+    // The magnetometer's data ready is not wired up, so it is polled.
+    let mut imu = Imu::new(i2c0, board::PROFILE.mounting, None).await;
+    // Bumps are over in a few milliseconds, so sample fast and read the samples in bursts.
+    imu.set_accel_config(AccelConfig {
+        odr: AccelOdr::Hz400,
+        ..AccelConfig::DEFAULT
+    })
+    .await;
+    imu.set_fifo(FifoConfig::STREAM).await;
+
+    let i2c1 = TwimBus::new(p.TWISPI1, p.P1_00.into(), p.P0_26.into());
+    // The profile's pins are never touched through `p`.
+    let pins = board::PROFILE.pins;
+    let (sonar_trig, sonar_echo, servo, line_left, line_right, ir_receiver) = unsafe {
+        (
+            board::edge_pin(pins.sonar_trig),
+            board::edge_pin(pins.sonar_echo),
+            board::edge_pin(pins.servo),
+            board::edge_pin(pins.line_left),
+            board::edge_pin(pins.line_right),
+            board::edge_pin(pins.ir_receiver),
+        )
+    };
+    let mut robot_base =
+        RobotBase::new(i2c1, board::PROFILE, sonar_trig, sonar_echo, servo, p.PWM0).await;
+    let line_sensors = LineSensors::new(line_left, line_right);
+    spawner
+        .spawn(line_sensors::line_sensor_task(line_sensors))
+        .unwrap();
+    let ir_remote = IrRemote::new(ir_receiver);
+    spawner.spawn(ir_remote::ir_remote_task(ir_remote)).unwrap();
+
+    // The die runs a little warmer than the air, but it is close enough for the speed of sound.
+    let mut temp = temp::Temp::new(p.TEMP, Irqs);
 
     let mut disp = Display::new(
         p.P0_28, p.P0_11, p.P0_31, p.P1_05, p.P0_30, p.P0_21, p.P0_22, p.P0_15, p.P0_24, p.P0_19,
     );
 
+    let mut buttons = Buttons::new(p.P0_14, p.P0_23);
+    let mut nvmc = Nvmc::new(p.NVMC);
+    let mut saved = settings::load(&mut nvmc);
+    // Holding a button while powering on runs a self-test, A for the motor wiring,
+    // B for the compass and both for the servo trim.
+    let (hold_a, hold_b) = (buttons.is_pressed(Button::A), buttons.is_pressed(Button::B));
+    if hold_a && !hold_b {
+        let correction = self_test::wiring_test(&mut robot_base, &mut disp, &mut buttons).await;
+        if let Err(e) = correction {
+            defmt::warn!("Wiring test could not drive the motors: {:?}", e);
+        }
+        if let Ok(Some(correction)) = correction {
+            saved.motors = correction;
+            if let Err(e) = settings::save(&mut nvmc, &saved) {
+                defmt::warn!("Failed to save settings: {:?}", e);
+            }
+        }
+    }
+    robot_base.set_motor_correction(saved.motors);
+    if let Some(servo) = saved.servo {
+        robot_base.set_servo_calibration(servo);
+    }
+    if hold_a && hold_b {
+        if let Some(servo) = self_test::servo_trim(&mut robot_base, &mut disp, &mut buttons).await {
+            saved.servo = Some(servo);
+            if let Err(e) = settings::save(&mut nvmc, &saved) {
+                defmt::warn!("Failed to save settings: {:?}", e);
+            }
+        }
+    }
+    imu.set_mag_calibration(saved.mag);
+    if hold_b && !hold_a {
+        buttons.wait_for_release(Button::B).await;
+        if let Some(calibration) =
+            self_test::mag_calibration(&mut robot_base, &mut imu, &mut disp).await
+        {
+            saved.mag = calibration;
+            imu.set_mag_calibration(calibration);
+            if let Err(e) = settings::save(&mut nvmc, &saved) {
+                defmt::warn!("Failed to save settings: {:?}", e);
+            }
+        }
+    }
+
+    // The self-tests are done with the base, from here on it is shared with the ramp task.
+    let robot_base = ROBOT_BASE.init(Mutex::new(robot_base));
+    spawner.spawn(robot_base::ramp_task(robot_base)).unwrap();
+    spawner.spawn(robot_base::sonar_task(robot_base)).unwrap();
+
     let mut input: RocInput = Default::default();
-    while !imu.mag_ready().await.unwrap() {}
-    let data = imu.mag_heading().await.unwrap();
-    let mut filter = lsm303agr::MagFilter::new(data);
+    // Started from the first reading, which may be a while if the imu is unavailable.
+    let mut filter: Option<MagFilter> = None;
+    let mut last_t = Instant::now();
     defmt::info!("Starting Main Loop");
     loop {
-        if imu.mag_ready().await.unwrap() {
-            let data = imu.mag_heading().await.unwrap();
-            let states = filter.predict_and_update(&data);
-            defmt::info!("Raw: {:?}, Filtered: {:?}", data, states.as_slice());
+        let output = roc_main(input.clone());
+        input.state = output.state;
+        {
+            let mut robot_base = robot_base.lock().await;
+            let left = output.speed_left as i16 * 40;
+            let right = output.speed_right as i16 * 40;
+            robot_base.set_wheel_speeds([left, left, right, right]);
+            robot_base.set_headlights(output.headlights.into());
+            if output.reset_pose {
+                robot_base.reset_odometry();
+            }
+            if output.scan {
+                let scan = robot_base.scan(&ScanConfig::default()).await;
+                input.set_scan(&scan);
+            }
         }
+
+        // The compass keeps the heading fresh while the frame is shown, and reading it
+        // empties the accelerometer FIFO as well.
+        let deadline = Instant::now() + Duration::from_millis(output.delay_ms);
+        let read_imu = async {
+            while Instant::now() < deadline {
+                if let Some(data) = imu.mag_heading().await {
+                    let dt = last_t.elapsed().as_micros() as f32 / 1_000_000.0;
+                    last_t = Instant::now();
+                    match filter.as_mut() {
+                        Some(filter) => {
+                            let states = filter.predict_and_update(&data, dt);
+                            defmt::debug!("Raw: {:?}, Filtered: {:?}", data, states.as_slice());
+                        }
+                        None => filter = Some(MagFilter::new(data)),
+                    }
+                }
+                let heading = match imu.status() {
+                    DeviceStatus::Available => filter.as_ref().map(MagFilter::heading),
+                    DeviceStatus::Unavailable => None,
+                };
+                let mut robot_base = robot_base.lock().await;
+                robot_base.set_mag_heading(heading);
+                robot_base.set_motor_cutoff(!imu.motors_allowed());
+            }
+        };
+        join(disp.show(&output.display, output.delay_ms), read_imu).await;
+
+        let mut robot_base = robot_base.lock().await;
+        // Temperature is in quarter degrees.
+        robot_base.set_temperature(temp.read().await.to_bits() * 25);
+        let sonar = robot_base.sonar_reading();
+        defmt::debug!("Sonar: {:?}", sonar);
+        input.set_sonar(&sonar);
+        let (ir_event, ir_code) = ir_remote::take_event();
+        input.set_ir(ir_event, ir_code);
+        input.guard = robot_base.guard_status();
+        input.pose = robot_base.pose().to_roc();
+        input.motors = robot_base.motors_status();
+        input.imu = imu.status();
+        input.set_accel_stats(&imu.take_accel_stats());
+        input.gesture = imu.take_gesture();
+        input.light_left = line_sensors::light(Side::Left);
+        input.light_right = line_sensors::light(Side::Right);
+        (input.transitions_left, input.transitions_right) = line_sensors::take_transitions();
     }
-    // loop {
-    //     defmt::debug!("Sonar Distance: {:?}", robot_base.sonar_distance());
-    //     // defmt::debug!("Input: {}", input);
-    //     let output = roc_main(input.clone());
-    //     // defmt::debug!("Output: {}", output);
-    //     disp.show(&output.display, output.delay_ms).await;
-
-    //     robot_base
-    //         .front_left_motor(Direction::Forward, output.speed_left as u16 * 40)
-    //         .await
-    //         .unwrap();
-    //     robot_base
-    //         .back_left_motor(Direction::Forward, output.speed_left as u16 * 40)
-    //         .await
-    //         .unwrap();
-    //     robot_base
-    //         .front_right_motor(Direction::Forward, output.speed_right as u16 * 40)
-    //         .await
-    //         .unwrap();
-    //     robot_base
-    //         .back_right_motor(Direction::Forward, output.speed_right as u16 * 40)
-    //         .await
-    //         .unwrap();
-
-    //     input.state = output.state;
-    //     input.light_left = robot_base.light_left();
-    //     input.light_right = robot_base.light_right();
-    // }
 }
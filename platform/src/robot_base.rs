@@ -1,15 +1,23 @@
+use crate::i2c_bus::{Instance, TwimBus};
 use defmt::Format;
-use embassy::time::{self, Duration, Instant, Timer};
-use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
-use embassy_nrf::{peripherals, pwm, twim};
-
-#[repr(u8)]
-#[derive(Format, Default, Clone)]
-pub enum LightLevel {
-    #[default]
-    Bright = 0,
-    Dark = 1,
-}
+use embassy_nrf::gpio::{AnyPin, Input, Level, Output, OutputDrive, Pull};
+use embassy_nrf::{peripherals, pwm, twim, Peri};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{with_timeout, Delay, Duration, Instant, Timer};
+use roc_microbit_core::board::{BoardProfile, MotorConfig};
+use roc_microbit_core::bus::{DeviceHealth, Recovery, Retry};
+use roc_microbit_core::guard::CollisionGuard;
+use roc_microbit_core::headlight::{Headlight, Headlights};
+use roc_microbit_core::motor::{self, Direction, Motor};
+use roc_microbit_core::odometry::{Odometry, Pose};
+use roc_microbit_core::pca9685::{self, Pca9685};
+use roc_microbit_core::ramp::SpeedRamp;
+use roc_microbit_core::roc::{DeviceStatus, GuardStatus};
+use roc_microbit_core::scan::{self, Scan, ScanConfig, ScanPoint};
+use roc_microbit_core::servo::{self, ServoCalibration, ServoController};
+use roc_microbit_core::sonar::{self, SonarFilter, SonarReading};
+use roc_microbit_core::wiring::MotorCorrection;
 
 #[repr(u8)]
 #[derive(Format, Default, Clone)]
@@ -19,96 +27,160 @@ pub enum LightState {
     On = 1,
 }
 
-#[repr(u8)]
-#[derive(Format, Default, Clone)]
-pub enum Direction {
-    #[default]
-    Forward = 0,
-    Reverse = 1,
+impl From<LightState> for Headlight {
+    fn from(state: LightState) -> Headlight {
+        match state {
+            LightState::On => Headlight::ON,
+            LightState::Off => Headlight::OFF,
+        }
+    }
 }
 
-// Robot Base is now KeyeStudio Microbit 4WD Mecanum Robot Kit.
-// TODO: Add Magnometer with some form of calibration (can maybe use lsm303agr crate)
-// TODO: Add Accelerometer (can maybe use lsm303agr crate)
+// Robot Base is now KeyeStudio Microbit 4WD Mecanum Robot Kit by default.
+// Other chassis are described by a `BoardProfile`.
 // TODO: Add ability to turn on leds for line sensors?
-// TODO: Add ability to read ir sensor?
 // TODO: Add serial, ble, or radio for communication to computer?
-const BASE_ADDR: u8 = 0x47;
-pub struct RobotBase<'d, T: twim::Instance, P: pwm::Instance> {
-    i2c: twim::Twim<'d, T>,
-    left_light_sensor: Input<'d, peripherals::P0_03>,
-    right_light_sensor: Input<'d, peripherals::P0_04>,
-    sonar_trig: Output<'d, peripherals::P0_13>,
-    sonar_echo: Input<'d, peripherals::P1_02>,
-    servo: pwm::SimplePwm<'d, P>,
+// How often wheel speeds are stepped towards their targets.
+const RAMP_PERIOD_MS: u64 = 10;
+// How often the servo is stepped during a smooth move.
+const SERVO_PERIOD_MS: u64 = 20;
+pub struct RobotBase<'d, T: Instance> {
+    // The profile's motors are rearranged by the saved `MotorCorrection`.
+    profile: BoardProfile,
+    // Motors as the profile describes them, before any correction.
+    profile_motors: [Option<MotorConfig>; 4],
+    pca9685: Pca9685<Retry<TwimBus<'d, T>>>,
+    pca9685_health: DeviceHealth,
+    sonar_trig: Output<'d>,
+    sonar_echo: Input<'d>,
+    servo: pwm::SimplePwm<'d>,
+    ramp: SpeedRamp,
+    guard: CollisionGuard,
+    guard_status: GuardStatus,
+    // Wheel speeds asked for before the collision guard limits them.
+    requested_speeds: [i16; 4],
+    // Stops the wheels whatever they are asked to do, like while the robot is picked up.
+    motor_cutoff: bool,
+    // Used to correct the speed of sound.
+    temperature_centi_c: i32,
+    sonar_filter: SonarFilter,
+    servo_control: ServoController,
+    headlights: Headlights,
+    odometry: Odometry,
+    // Latest filtered compass heading, None while the imu is unavailable.
+    mag_heading: Option<f32>,
+    // When the wheels were last stepped, so a step after the base was held elsewhere does
+    // not make up for the lost time.
+    ramp_clock: Instant,
 }
-impl<'d, T: twim::Instance, P: pwm::Instance> RobotBase<'d, T, P> {
+impl<'d, T: Instance> RobotBase<'d, T> {
+    /// The pins have to be the ones in `profile.pins`.
+    /// If the PCA9685 does not answer the base still starts, with `motors_status` unavailable.
     pub async fn new(
-        i2c: twim::Twim<'d, T>,
-        ll: peripherals::P0_03,
-        rl: peripherals::P0_04,
-        st: peripherals::P0_13,
-        se: peripherals::P1_02,
-        servo: peripherals::P0_01,
-        pwm: P,
-    ) -> Result<RobotBase<'d, T, P>, twim::Error> {
+        i2c: TwimBus<'d, T>,
+        profile: BoardProfile,
+        st: Peri<'d, AnyPin>,
+        se: Peri<'d, AnyPin>,
+        servo: Peri<'d, AnyPin>,
+        pwm: Peri<'d, impl pwm::Instance>,
+    ) -> RobotBase<'d, T> {
+        defmt::info!("Robot base: {}", profile.name);
         let mut rb = RobotBase {
-            i2c,
-            left_light_sensor: Input::new(ll, Pull::Down),
-            right_light_sensor: Input::new(rl, Pull::Down),
+            profile,
+            profile_motors: profile.motors,
+            pca9685: Pca9685::new(Retry::new(i2c), profile.pca9685_addr),
+            pca9685_health: DeviceHealth::new(),
             sonar_trig: Output::new(st, Level::Low, OutputDrive::Standard),
             sonar_echo: Input::new(se, Pull::Down),
             servo: pwm::SimplePwm::new_1ch(pwm, servo),
+            ramp: SpeedRamp::default(),
+            guard: CollisionGuard::default(),
+            guard_status: GuardStatus::Clear,
+            requested_speeds: [0; 4],
+            motor_cutoff: false,
+            temperature_centi_c: sonar::DEFAULT_TEMPERATURE_CENTI_C,
+            sonar_filter: SonarFilter::new(),
+            servo_control: ServoController::new(profile.servo),
+            headlights: Headlights::default(),
+            odometry: Odometry::default(),
+            mag_heading: None,
+            ramp_clock: Instant::now(),
         };
-        // microservo requires 50hz or 20ms period
-        // set_period can only set down to 125khz so we cant use it directly
-        // Div128 is 125khz or 0.000008s or 0.008ms, 20/0.008 is 2500 is top
+        // See `servo::MAX_DUTY` for how the prescaler was picked.
         rb.servo.set_prescaler(pwm::Prescaler::Div128);
-        rb.servo.set_max_duty(2500);
+        rb.servo.set_max_duty(servo::MAX_DUTY);
         rb.servo.disable();
 
-        rb.i2c.write(BASE_ADDR, &[0x00, 0x00]).await?;
-        rb.set_all_pwm(0, 0).await?;
-        rb.i2c.write(BASE_ADDR, &[0x01, 0x04]).await?;
-        rb.i2c.write(BASE_ADDR, &[0x00, 0x01]).await?;
-
-        Timer::after(Duration::from_millis(5)).await;
-
-        rb.i2c.write(BASE_ADDR, &[0x00]).await?;
-        let mut models = [0];
-        rb.i2c.read(BASE_ADDR, &mut models).await?;
-        let model = models[0] & !0x10;
-        rb.i2c.write(BASE_ADDR, &[0x00, model]).await?;
-        Timer::after(Duration::from_millis(5)).await;
-        Ok(rb)
-    }
-
-    async fn set_pwm(&mut self, channel: u8, on: u16, off: u16) -> Result<(), twim::Error> {
-        self.i2c
-            .write(BASE_ADDR, &[0x06 + 4 * channel, (on & 0xFF) as u8])
-            .await?;
-        self.i2c
-            .write(BASE_ADDR, &[0x07 + 4 * channel, (on >> 8) as u8])
-            .await?;
-        self.i2c
-            .write(BASE_ADDR, &[0x08 + 4 * channel, (off & 0xFF) as u8])
-            .await?;
-        self.i2c
-            .write(BASE_ADDR, &[0x09 + 4 * channel, (off >> 8) as u8])
-            .await?;
-        Ok(())
-    }
-
-    async fn set_all_pwm(&mut self, on: u16, off: u16) -> Result<(), twim::Error> {
-        self.i2c
-            .write(BASE_ADDR, &[0xFA, (on & 0xFF) as u8])
-            .await?;
-        self.i2c.write(BASE_ADDR, &[0xFB, (on >> 8) as u8]).await?;
-        self.i2c
-            .write(BASE_ADDR, &[0xFC, (off & 0xFF) as u8])
-            .await?;
-        self.i2c.write(BASE_ADDR, &[0xFD, (off >> 8) as u8]).await?;
-        Ok(())
+        let result = rb.init_pca9685().await;
+        // Don't bother recovering at boot, `pca9685_ready` retries it soon enough.
+        let _ = rb.pca9685_result(result).await;
+        rb
+    }
+
+    /// Whether the PCA9685 is answering. While it is unavailable, motor and light updates
+    /// are skipped.
+    pub fn motors_status(&self) -> DeviceStatus {
+        self.pca9685_health.status()
+    }
+
+    /// Sets the PCA9685 up from scratch, for boot and after it may have lost power.
+    async fn init_pca9685(&mut self) -> Result<(), twim::Error> {
+        self.pca9685.forget_outputs();
+        self.pca9685.init(&mut Delay).await
+    }
+
+    /// Whether the PCA9685 can be written to right now. Sets it up again first if it was
+    /// unavailable and is due another try, or if it has been reset by a brown out.
+    async fn pca9685_ready(&mut self) -> bool {
+        let now = Instant::now().as_millis();
+        if !self.pca9685_health.should_try(now) {
+            return false;
+        }
+        let result = if !self.pca9685_health.is_available() {
+            self.pca9685.bus_mut().inner_mut().recover();
+            self.init_pca9685().await
+        } else if self.pca9685_health.reset_check_due(now) {
+            match self.pca9685.mode1().await {
+                Ok(mode) if pca9685::was_reset(mode) => {
+                    defmt::warn!("PCA9685 was reset, setting it up again.");
+                    self.init_pca9685().await
+                }
+                other => other.map(|_| ()),
+            }
+        } else {
+            return true;
+        };
+        self.pca9685_result(result).await.is_ok()
+    }
+
+    /// Updates the PCA9685's health with the result of talking to it, recovering the bus
+    /// if it failed. The result is passed back for the caller to report.
+    async fn pca9685_result<R>(
+        &mut self,
+        result: Result<R, twim::Error>,
+    ) -> Result<R, twim::Error> {
+        let e = match &result {
+            Ok(_) => {
+                if self.pca9685_health.succeeded() {
+                    defmt::info!("PCA9685 is back.");
+                }
+                return result;
+            }
+            Err(e) => *e,
+        };
+        match self.pca9685_health.failed(Instant::now().as_millis()) {
+            Recovery::Reinit => {
+                defmt::warn!("PCA9685 failed: {:?}, recovering the bus.", e);
+                self.pca9685.bus_mut().inner_mut().recover();
+                if self.init_pca9685().await.is_err() {
+                    self.pca9685_health.failed(Instant::now().as_millis());
+                }
+            }
+            Recovery::Degraded => {
+                defmt::warn!("PCA9685 failed: {:?}, carrying on without it.", e)
+            }
+        }
+        result
     }
 
     pub fn disable_servo(&mut self) {
@@ -119,69 +191,132 @@ impl<'d, T: twim::Instance, P: pwm::Instance> RobotBase<'d, T, P> {
         self.servo.enable()
     }
 
+    pub fn servo_calibration(&self) -> ServoCalibration {
+        self.servo_control.calibration()
+    }
+
+    /// Takes effect on the next servo move.
+    pub fn set_servo_calibration(&mut self, calibration: ServoCalibration) {
+        self.servo_control.set_calibration(calibration);
+    }
+
+    /// Sends the servo straight to `angle`, clamped to 0 to 180.
     pub fn servo(&mut self, angle: u8) {
-        if angle > 180 {
+        if angle > servo::MAX_ANGLE {
             defmt::warn!(
                 "Angle should be between 0 and 180 inclusive. Got: {}",
                 angle
             );
         }
-        // Servo seems to be slightly off center. Adjusting here.
-        let angle = angle + 5;
-        // 1ms 45deg (1/.008=125), 1.5ms 90deg (1.5/.008=187.5), 2ms 135deg (2/.008=250),
-        // Angle range: about 180°(in 500→2500μsec)
-        // Map value to 500 to 2500 us.
-        let us = angle as u32 * 2000 / 180 + 500;
-        // Divide by 0.008 (multiply by 125) and divide by 1000 to get final value in ms.
-        let off_duty = us * 125 / 1000;
-        let duty = 2500 - off_duty;
+        self.servo_control.jump_to(angle);
+        self.update_servo(0.0);
+    }
+
+    /// Moves the servo to `angle` at `servo::DEFAULT_DEGREES_PER_S`.
+    pub async fn move_servo(&mut self, angle: u8) {
+        self.servo_control.move_to(angle);
+        let mut last_t = Instant::now();
+        loop {
+            let dt = last_t.elapsed().as_micros() as f32 / 1_000_000.0;
+            last_t = Instant::now();
+            self.update_servo(dt);
+            if self.servo_control.is_finished() {
+                return;
+            }
+            Timer::after(Duration::from_millis(SERVO_PERIOD_MS)).await;
+        }
+    }
+
+    fn update_servo(&mut self, dt: f32) {
+        let duty = self.servo_control.step(dt);
         defmt::debug!("Setting servo to: {}", duty);
-        self.servo.set_duty(0, duty as u16)
+        self.servo.set_duty(0, duty);
+    }
+
+    /// Sweeps the sonar across the arc in `config`, pinging once at each angle.
+    /// The servo is left pointing straight ahead afterwards.
+    /// Takes around a second, and nothing ramps the wheels meanwhile, so they are stopped
+    /// first and ramp back up to their requested speeds afterwards.
+    pub async fn scan(&mut self, config: &ScanConfig) -> Scan {
+        self.ramp.stop_now();
+        if let Err(e) = self.drive_motors([(Direction::Forward, 0); 4]).await {
+            defmt::warn!("Failed to stop the motors for a scan: {:?}", e);
+        }
+        let mut scan = Scan::default();
+        self.enable_servo();
+        for (point, angle) in scan.points.iter_mut().zip(config.angles()) {
+            // Assume the worst case move if we don't know where the servo is.
+            let from = self
+                .servo_control
+                .angle()
+                .unwrap_or(if angle > 90 { 0 } else { 180 });
+            self.servo(angle);
+            Timer::after(Duration::from_millis(scan::settle_ms(from, angle) as u64)).await;
+            let next_ping = Instant::from_micros(self.sonar_filter.next_ping_us());
+            if Instant::now() < next_ping {
+                Timer::at(next_ping).await;
+            }
+            // Pings count towards the filter's ping interval but stay out of its window,
+            // since they are all at different angles.
+            self.sonar_filter.record_ping(Instant::now().as_micros());
+            let distance = self.sonar_distance().await;
+            *point = ScanPoint {
+                angle,
+                distance_mm: distance,
+            };
+        }
+        defmt::info!("Scan: {:?}", scan);
+        self.move_servo(90).await;
+        self.ramp_clock = Instant::now();
+        self.apply_guard();
+        scan
     }
 
     pub async fn left_led(&mut self, state: LightState) -> Result<(), twim::Error> {
-        match state {
-            LightState::On => self.set_pwm(12, 0, 4095).await?,
-            LightState::Off => self.set_pwm(12, 0, 0).await?,
-        };
-        Ok(())
+        self.headlights.left = state.into();
+        self.update_lights().await
     }
 
     pub async fn right_led(&mut self, state: LightState) -> Result<(), twim::Error> {
-        match state {
-            LightState::On => self.set_pwm(13, 0, 4095).await?,
-            LightState::Off => self.set_pwm(13, 0, 0).await?,
-        };
-        Ok(())
+        self.headlights.right = state.into();
+        self.update_lights().await
     }
 
-    pub fn light_left(&self) -> LightLevel {
-        if self.left_light_sensor.is_low() {
-            LightLevel::Bright
-        } else {
-            LightLevel::Dark
-        }
+    /// Patterns keep running from `ramp_step`, or `update_lights` can be called directly.
+    pub fn set_headlights(&mut self, headlights: Headlights) {
+        self.headlights = headlights;
     }
 
-    pub fn light_right(&self) -> LightLevel {
-        if self.right_light_sensor.is_low() {
-            LightLevel::Bright
-        } else {
-            LightLevel::Dark
+    /// Writes the current step of the headlight patterns. Unchanged lights are skipped.
+    pub async fn update_lights(&mut self) -> Result<(), twim::Error> {
+        let channels = match self.profile.headlights {
+            Some(channels) => channels,
+            None => return Ok(()),
+        };
+        let pwm = self
+            .headlights
+            .pwm(channels, Instant::now().as_millis(), self.ramp.current());
+        if !self.pca9685_ready().await {
+            return Ok(());
         }
+        let result = self.pca9685.set_pwms(&pwm).await;
+        self.pca9685_result(result).await
+    }
+
+    /// Sets the air temperature used to correct sonar distances, in hundredths of a degree C.
+    pub fn set_temperature(&mut self, temperature_centi_c: i32) {
+        self.temperature_centi_c = temperature_centi_c;
     }
 
-    pub fn sonar_distance(&mut self) -> Option<u32> {
-        const MAX_SENSOR_DELAY: u64 = 35000;
-        const MAX_SENSOR_DISTANCE_CM: u64 = 300;
-        // Note: 58 assumes room temperature.
-        // At 0 C, it would be 60.
-        const US_ROUNDTRIP_CM: u64 = 58;
-        const MAX_ECHO_TIME: u64 = MAX_SENSOR_DISTANCE_CM * US_ROUNDTRIP_CM + (US_ROUNDTRIP_CM / 2);
+    /// Pings the sonar and waits for the echo without blocking the executor.
+    /// Other tasks, like the display, keep running while the echo is in flight.
+    /// Returns the distance in mm, corrected for the last temperature set.
+    pub async fn sonar_distance(&mut self) -> Option<u32> {
+        // The trigger pulse is far shorter than a timer tick, so it is still busy waited.
         self.sonar_trig.set_low();
-        time::block_for(Duration::from_micros(4));
+        embassy_time::block_for(Duration::from_micros(4));
         self.sonar_trig.set_high();
-        time::block_for(Duration::from_micros(10));
+        embassy_time::block_for(Duration::from_micros(10));
         self.sonar_trig.set_low();
 
         if self.sonar_echo.is_high() {
@@ -189,59 +324,211 @@ impl<'d, T: twim::Instance, P: pwm::Instance> RobotBase<'d, T, P> {
             return None;
         }
         let start = Instant::now();
-        let timeout = start + Duration::from_micros(MAX_SENSOR_DELAY);
-        while !self.sonar_echo.is_high() {
-            if Instant::now() > timeout {
-                defmt::warn!(
-                    "Timed out while waiting to measure sonar distances: {}us",
-                    start.elapsed().as_micros()
-                );
-                return None;
-            }
+        let timeout = Duration::from_micros(sonar::MAX_SENSOR_DELAY_US);
+        if with_timeout(timeout, self.sonar_echo.wait_for_high())
+            .await
+            .is_err()
+        {
+            defmt::warn!(
+                "Timed out while waiting to measure sonar distances: {}us",
+                start.elapsed().as_micros()
+            );
+            return None;
         }
+        // Each edge is timestamped when this task next runs after its own GPIOTE interrupt,
+        // so the pulse length is off by the difference in wake up latency, more so while
+        // other tasks hold the executor. Time also only counts in 32.768kHz ticks, around
+        // 30us or 5mm each.
         let start = Instant::now();
-        let timeout = start + Duration::from_micros(MAX_ECHO_TIME);
-        while self.sonar_echo.is_high() {
-            if Instant::now() > timeout {
-                defmt::warn!(
-                    "Timed out while measuring sonar distances: {}us",
-                    start.elapsed().as_micros()
-                );
-                return None;
-            }
+        let timeout = Duration::from_micros(sonar::max_echo_time_us(self.temperature_centi_c));
+        if with_timeout(timeout, self.sonar_echo.wait_for_low())
+            .await
+            .is_err()
+        {
+            defmt::warn!(
+                "Timed out while measuring sonar distances: {}us",
+                start.elapsed().as_micros()
+            );
+            return None;
         }
         let echo_time = start.elapsed().as_micros();
 
-        Some(((echo_time + US_ROUNDTRIP_CM / 2) / US_ROUNDTRIP_CM) as u32)
+        Some(sonar::echo_time_to_mm(echo_time, self.temperature_centi_c))
+    }
+
+    /// Pings the sonar if enough time has passed since the last ping, and passes the
+    /// filtered distance on to the collision guard. `sonar_task` keeps it going.
+    pub async fn sample_sonar(&mut self) {
+        let now = Instant::now().as_micros();
+        if now >= self.sonar_filter.next_ping_us() {
+            let distance = self.sonar_distance().await;
+            self.sonar_filter.add(now, distance);
+        }
+        let reading = self.sonar_reading();
+        self.guard.update(&reading);
+        self.apply_guard();
+    }
+
+    /// The filtered distance from the pings `sample_sonar` has sent.
+    pub fn sonar_reading(&self) -> SonarReading {
+        self.sonar_filter.reading(Instant::now().as_micros())
+    }
+
+    /// Swaps and inverts motors to match how they are really wired, as found by the
+    /// wiring self-test.
+    pub fn set_motor_correction(&mut self, correction: MotorCorrection) {
+        self.profile.motors = correction.apply(self.profile_motors);
+    }
+
+    /// Which motors the profile has, in `Motor::ALL` order.
+    pub fn motors_present(&self) -> [bool; 4] {
+        self.profile_motors.map(|motor| motor.is_some())
     }
 
     async fn drive_motor(
         &mut self,
-        pwm0: u8,
-        pwm1: u8,
-        pwm2: u8,
+        motor: Motor,
         dir: Direction,
         speed: u16,
     ) -> Result<(), twim::Error> {
-        if speed > 4095 {
+        if speed > motor::MAX_SPEED {
             defmt::warn!(
                 "Speed should be between 0 and 4095 inclusive. Got speed: {}",
                 speed,
             );
         }
-        match dir {
-            Direction::Forward => {
-                self.set_pwm(pwm0, 0, 0).await?;
-                self.set_pwm(pwm1, 4096, 0).await?;
-                self.set_pwm(pwm2, 0, speed).await?;
+        if !self.pca9685_ready().await {
+            return Ok(());
+        }
+        let result = self
+            .pca9685
+            .set_pwms(self.profile.motor_pwm(motor, dir, speed).as_slice())
+            .await;
+        self.pca9685_result(result).await
+    }
+
+    /// Updates every motor in one batch. `commands` is in `Motor::ALL` order:
+    /// front left, back left, front right, back right.
+    pub async fn drive_motors(
+        &mut self,
+        commands: [(Direction, u16); 4],
+    ) -> Result<(), twim::Error> {
+        for (_, speed) in commands {
+            if speed > motor::MAX_SPEED {
+                defmt::warn!(
+                    "Speed should be between 0 and 4095 inclusive. Got speed: {}",
+                    speed,
+                );
             }
-            Direction::Reverse => {
-                self.set_pwm(pwm0, 4096, 0).await?;
-                self.set_pwm(pwm1, 0, 0).await?;
-                self.set_pwm(pwm2, 0, speed).await?;
+        }
+        if !self.pca9685_ready().await {
+            return Ok(());
+        }
+        let result = self
+            .pca9685
+            .set_pwms(self.profile.motors_pwm(&commands).as_slice())
+            .await;
+        self.pca9685_result(result).await
+    }
+
+    /// Sets the signed speed each wheel ramps towards, in `Motor::ALL` order.
+    /// Negative speeds are reverse. Nothing moves until `ramp_step` runs.
+    /// Forward motion is limited by the collision guard.
+    pub fn set_wheel_speeds(&mut self, speeds: [i16; 4]) {
+        self.requested_speeds = speeds;
+        self.apply_guard();
+    }
+
+    /// Whether the collision guard is currently limiting the wheel speeds.
+    pub fn guard_status(&self) -> GuardStatus {
+        self.guard_status
+    }
+
+    /// Stops every wheel while `cutoff` is set, like when the robot has been picked up or
+    /// tipped over, see `Imu::motors_allowed`. Wheel speeds are kept for when it clears.
+    pub fn set_motor_cutoff(&mut self, cutoff: bool) {
+        if cutoff == self.motor_cutoff {
+            return;
+        }
+        if cutoff {
+            defmt::info!("Cutting the motors.");
+            self.ramp.stop_now();
+        } else {
+            defmt::info!("Motors back on.");
+        }
+        self.motor_cutoff = cutoff;
+        self.apply_guard();
+    }
+
+    fn apply_guard(&mut self) {
+        if self.motor_cutoff {
+            self.ramp.set_target([0; 4]);
+            return;
+        }
+        let (speeds, status) = self.guard.apply(self.requested_speeds);
+        if status == GuardStatus::Blocked && self.guard_status != GuardStatus::Blocked {
+            defmt::info!(
+                "Obstacle at {:?}mm, blocking forward motion.",
+                self.guard.distance_mm()
+            );
+            // Brake right away instead of ramping into the obstacle.
+            self.ramp.stop_now();
+        }
+        self.guard_status = status;
+        self.ramp.set_target(speeds);
+    }
+
+    /// Passes on the filtered compass heading for odometry, see `MagFilter::heading`.
+    pub fn set_mag_heading(&mut self, mag_heading: Option<f32>) {
+        self.mag_heading = mag_heading;
+    }
+
+    /// Where the robot has driven since the last `reset_odometry`, worked out from the
+    /// wheel speeds sent by `ramp_step`.
+    pub fn pose(&self) -> Pose {
+        self.odometry.pose()
+    }
+
+    /// Makes the current position the origin, facing along x.
+    pub fn reset_odometry(&mut self) {
+        self.odometry.reset();
+    }
+
+    /// Steps the wheels towards their target speeds by `dt` seconds, and moves the headlight
+    /// patterns on. See `ramp_task` for keeping it going.
+    pub async fn ramp_step(&mut self, dt: f32) -> Result<(), twim::Error> {
+        let speeds = self.ramp.step(dt);
+        // Wheels don't turn while the motor driver is missing.
+        let moving = if self.pca9685_health.is_available() {
+            speeds
+        } else {
+            [0; 4]
+        };
+        self.odometry.step(moving, self.mag_heading, dt);
+        self.drive_motors(speeds.map(motor::split_speed)).await?;
+        self.update_lights().await
+    }
+
+    /// Seconds since the wheels were last stepped, starting the count again.
+    fn ramp_elapsed(&mut self) -> f32 {
+        let dt = self.ramp_clock.elapsed().as_micros() as f32 / 1_000_000.0;
+        self.ramp_clock = Instant::now();
+        dt
+    }
+
+    /// Keeps ramping for `duration`, for when nothing else needs the base, like the
+    /// self-tests before `ramp_task` starts.
+    pub async fn ramp_motors(&mut self, duration: Duration) -> Result<(), twim::Error> {
+        let end = Instant::now() + duration;
+        self.ramp_clock = Instant::now();
+        loop {
+            let dt = self.ramp_elapsed();
+            self.ramp_step(dt).await?;
+            if Instant::now() >= end {
+                return Ok(());
             }
+            Timer::after(Duration::from_millis(RAMP_PERIOD_MS)).await;
         }
-        Ok(())
     }
 
     pub async fn front_left_motor(
@@ -249,26 +536,25 @@ impl<'d, T: twim::Instance, P: pwm::Instance> RobotBase<'d, T, P> {
         dir: Direction,
         speed: u16,
     ) -> Result<(), twim::Error> {
-        self.drive_motor(4, 3, 5, dir, speed).await
+        self.drive_motor(Motor::FrontLeft, dir, speed).await
     }
     pub async fn back_left_motor(&mut self, dir: Direction, speed: u16) -> Result<(), twim::Error> {
-        self.drive_motor(10, 9, 11, dir, speed).await
+        self.drive_motor(Motor::BackLeft, dir, speed).await
     }
     pub async fn front_right_motor(
         &mut self,
         dir: Direction,
         speed: u16,
     ) -> Result<(), twim::Error> {
-        self.drive_motor(2, 1, 0, dir, speed).await
+        self.drive_motor(Motor::FrontRight, dir, speed).await
     }
     pub async fn back_right_motor(
         &mut self,
         dir: Direction,
         speed: u16,
     ) -> Result<(), twim::Error> {
-        self.drive_motor(8, 7, 6, dir, speed).await
+        self.drive_motor(Motor::BackRight, dir, speed).await
     }
-
     pub async fn stop_front_left_motor(&mut self) -> Result<(), twim::Error> {
         self.front_left_motor(Direction::Forward, 0).await
     }
@@ -282,3 +568,38 @@ impl<'d, T: twim::Instance, P: pwm::Instance> RobotBase<'d, T, P> {
         self.back_right_motor(Direction::Forward, 0).await
     }
 }
+
+/// The robot base on the micro:bit's external I2C bus, shared between the main loop,
+/// `ramp_task` and `sonar_task`.
+pub type SharedRobotBase = Mutex<ThreadModeRawMutex, RobotBase<'static, peripherals::TWISPI1>>;
+
+/// Steps the wheels towards the speeds set from the main loop, so ramps stay smooth no matter
+/// how often the app updates them. Never returns.
+/// Ramping pauses while the main loop holds the base, like during a scan.
+#[embassy_executor::task]
+pub async fn ramp_task(robot_base: &'static SharedRobotBase) {
+    // Nothing ramped the wheels since the self-tests.
+    robot_base.lock().await.ramp_clock = Instant::now();
+    loop {
+        Timer::after(Duration::from_millis(RAMP_PERIOD_MS)).await;
+        let mut robot_base = robot_base.lock().await;
+        let dt = robot_base.ramp_elapsed();
+        if let Err(e) = robot_base.ramp_step(dt).await {
+            defmt::warn!("Failed to update motors: {:?}", e);
+        }
+    }
+}
+
+/// Pings the sonar as often as it allows, so the collision guard keeps up however slowly
+/// the Roc app ticks.
+#[embassy_executor::task]
+pub async fn sonar_task(robot_base: &'static SharedRobotBase) {
+    loop {
+        let next_ping = {
+            let mut robot_base = robot_base.lock().await;
+            robot_base.sample_sonar().await;
+            robot_base.sonar_filter.next_ping_us()
+        };
+        Timer::at(Instant::from_micros(next_ping)).await;
+    }
+}
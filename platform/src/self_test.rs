@@ -0,0 +1,174 @@
+use crate::buttons::Buttons;
+use crate::i2c_bus::Instance;
+use crate::imu::Imu;
+use crate::robot_base::RobotBase;
+use crate::Display;
+use embassy_nrf::twim;
+use embassy_time::{with_timeout, Duration, Instant};
+use futures::future::{join, select, Either};
+use futures::pin_mut;
+use roc_microbit_core::display::DisplayData;
+use roc_microbit_core::mag::{MagCalibration, MagCalibrator};
+use roc_microbit_core::motor::{Direction, Motor};
+use roc_microbit_core::servo::ServoCalibration;
+use roc_microbit_core::wiring::{Button, MotorCorrection, WiringTest};
+
+// Fast enough to see which way a wheel turns, slow enough to hold the robot.
+const TEST_SPEED: u16 = 1500;
+// Slow enough that the magnetometer sees every heading, around 4 seconds a turn.
+const SPIN_SPEED: i16 = 1800;
+// Gives up if the turns never add up, like when the robot is stuck against something.
+const CALIBRATION_TIMEOUT_S: u64 = 30;
+const CALIBRATION_TICK_MS: u64 = 20;
+// Long enough to look at the sonar after a nudge.
+const TRIM_DONE_S: u64 = 5;
+
+/// Spins each motor in turn and asks, with buttons A and B, which wheel moved and which way.
+/// Returns the correction to save, or None if the answers did not add up.
+/// Hold the robot off the ground while this runs.
+pub async fn wiring_test<T: Instance>(
+    robot_base: &mut RobotBase<'_, T>,
+    disp: &mut Display<'_>,
+    buttons: &mut Buttons<'_>,
+) -> Result<Option<MotorCorrection>, twim::Error> {
+    defmt::info!("Starting motor wiring test.");
+    // Don't take the press that started the test as the first answer.
+    buttons.wait_for_release(Button::A).await;
+    // Answers are about the profile's own wiring.
+    robot_base.set_motor_correction(MotorCorrection::NONE);
+    let mut test = WiringTest::new(robot_base.motors_present());
+    while let Some((motor, dir)) = test.command() {
+        spin(robot_base, motor, dir).await?;
+        let button = show_until_press(disp, &test.display(), buttons).await;
+        defmt::debug!("{:?}: {:?}", test.step(), button);
+        test.press(button);
+        if test.command() != Some((motor, dir)) {
+            // Only one motor runs at a time.
+            stop_all(robot_base).await?;
+        }
+    }
+    match test.result() {
+        Ok(correction) => {
+            defmt::info!("Motor wiring: {:?}", correction);
+            Ok(Some(correction))
+        }
+        Err(e) => {
+            defmt::warn!("Motor wiring test failed: {:?}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Spins the robot slowly in place while collecting magnetometer readings, and works out a
+/// new calibration from them. The display fills in round the edge as headings are seen.
+/// Returns None if it timed out first.
+/// Put the robot on the floor away from anything steel, with the usb cable unplugged.
+pub async fn mag_calibration<T: Instance, U: Instance>(
+    robot_base: &mut RobotBase<'_, T>,
+    imu: &mut Imu<'_, U>,
+    disp: &mut Display<'_>,
+) -> Option<MagCalibration> {
+    defmt::info!("Starting magnetometer calibration.");
+    let mut calibrator = MagCalibrator::new(imu.mounting());
+    robot_base.set_wheel_speeds([-SPIN_SPEED, -SPIN_SPEED, SPIN_SPEED, SPIN_SPEED]);
+    let end = Instant::now() + Duration::from_secs(CALIBRATION_TIMEOUT_S);
+    while !calibrator.is_complete() && Instant::now() < end {
+        let tick = Duration::from_millis(CALIBRATION_TICK_MS);
+        let (_, ramped) = join(
+            disp.show(&calibrator.display(), CALIBRATION_TICK_MS),
+            robot_base.ramp_motors(tick),
+        )
+        .await;
+        if let Err(e) = ramped {
+            defmt::warn!("Failed to spin: {:?}", e);
+        }
+        if let Some(raw) = imu.mag_raw().await {
+            calibrator.add(&raw);
+        }
+    }
+    robot_base.set_wheel_speeds([0; 4]);
+    if let Err(e) = robot_base.ramp_motors(Duration::from_millis(500)).await {
+        defmt::warn!("Failed to stop: {:?}", e);
+    }
+    let calibration = calibrator.calibration(&imu.mag_calibration());
+    match calibration {
+        Some(calibration) => defmt::info!("Magnetometer calibration: {:?}", calibration),
+        None => defmt::warn!(
+            "Magnetometer calibration timed out with {} samples, {}% of headings seen.",
+            calibrator.samples(),
+            (calibrator.coverage() * 100.0) as u32
+        ),
+    }
+    calibration
+}
+
+/// Points the servo straight ahead and nudges its trim a degree at a time, A taking one off
+/// and B adding one, until the sonar faces forward. Finishes once neither button has been
+/// pressed for a few seconds.
+/// Returns the new calibration to save, or None if the trim was left alone.
+pub async fn servo_trim<T: Instance>(
+    robot_base: &mut RobotBase<'_, T>,
+    disp: &mut Display<'_>,
+    buttons: &mut Buttons<'_>,
+) -> Option<ServoCalibration> {
+    defmt::info!("Starting servo trim.");
+    // Both buttons started the test.
+    buttons.wait_for_release(Button::A).await;
+    buttons.wait_for_release(Button::B).await;
+    let start = robot_base.servo_calibration();
+    let mut calibration = start;
+    robot_base.enable_servo();
+    robot_base.servo(90);
+    loop {
+        let display = calibration.trim_display();
+        let press = show_until_press(disp, &display, buttons);
+        let Ok(button) = with_timeout(Duration::from_secs(TRIM_DONE_S), press).await else {
+            break;
+        };
+        calibration = calibration.trimmed_by(match button {
+            Button::A => -1,
+            Button::B => 1,
+        });
+        robot_base.set_servo_calibration(calibration);
+        robot_base.servo(90);
+    }
+    robot_base.disable_servo();
+    defmt::info!("Servo trim: {}", calibration.trim_deg);
+    (calibration != start).then_some(calibration)
+}
+
+async fn spin<T: Instance>(
+    robot_base: &mut RobotBase<'_, T>,
+    motor: Motor,
+    dir: Direction,
+) -> Result<(), twim::Error> {
+    match motor {
+        Motor::FrontLeft => robot_base.front_left_motor(dir, TEST_SPEED).await,
+        Motor::BackLeft => robot_base.back_left_motor(dir, TEST_SPEED).await,
+        Motor::FrontRight => robot_base.front_right_motor(dir, TEST_SPEED).await,
+        Motor::BackRight => robot_base.back_right_motor(dir, TEST_SPEED).await,
+    }
+}
+
+async fn stop_all<T: Instance>(robot_base: &mut RobotBase<'_, T>) -> Result<(), twim::Error> {
+    robot_base.stop_front_left_motor().await?;
+    robot_base.stop_back_left_motor().await?;
+    robot_base.stop_front_right_motor().await?;
+    robot_base.stop_back_right_motor().await
+}
+
+async fn show_until_press(
+    disp: &mut Display<'_>,
+    data: &DisplayData,
+    buttons: &mut Buttons<'_>,
+) -> Button {
+    let press = buttons.wait_for_press();
+    pin_mut!(press);
+    loop {
+        let show = disp.show(data, 100);
+        pin_mut!(show);
+        if let Either::Right((button, _)) = select(show, press.as_mut()).await {
+            return button;
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use embassy_nrf::nvmc::{self, Nvmc};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use roc_microbit_core::settings::{Settings, SETTINGS_LEN};
+
+// The page just past the flash region in memory.x, so the program never overlaps it.
+const SETTINGS_ADDR: u32 = 252 * 1024;
+
+// Flash is written a word at a time from word aligned buffers.
+#[repr(align(4))]
+struct Aligned([u8; SETTINGS_LEN]);
+
+/// Reads the saved settings, or the defaults if nothing valid has been saved.
+pub fn load(nvmc: &mut Nvmc<'_>) -> Settings {
+    let mut buf = Aligned([0; SETTINGS_LEN]);
+    if let Err(e) = nvmc.read(SETTINGS_ADDR, &mut buf.0) {
+        defmt::warn!("Failed to read settings: {:?}", e);
+        return Settings::default();
+    }
+    match Settings::from_bytes(&buf.0) {
+        Some(settings) => settings,
+        None => {
+            defmt::info!("No saved settings, using defaults.");
+            Settings::default()
+        }
+    }
+}
+
+pub fn save(nvmc: &mut Nvmc<'_>, settings: &Settings) -> Result<(), nvmc::Error> {
+    let buf = Aligned(settings.to_bytes());
+    nvmc.erase(SETTINGS_ADDR, SETTINGS_ADDR + Nvmc::ERASE_SIZE as u32)?;
+    nvmc.write(SETTINGS_ADDR, &buf.0)
+}